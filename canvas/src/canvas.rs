@@ -1,27 +1,50 @@
 use crate::{
-    command::CanvasCommand,
-    query::{CanvasQuery, CanvasQueryValue},
+    command::{CanvasCommand, FloatCommand, HistoryCommand, SliceCommand},
+    import::ImportedImage,
+    metadata::{self, BACKGROUND_COLOR, BRUSH_COLOR, SESSION},
+    query::{CanvasMode, CanvasQuery, CanvasQueryValue},
 };
 use orfail::OrFail;
-use pati::{Color, ImageCommand, Point, VersionedImage};
-use std::num::NonZeroU8;
+use pati::{Color, Image, ImageCommand, MetadataSchema, Point, Slice, Version, VersionedImage};
+use std::{collections::BTreeMap, num::NonZeroU8};
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone)]
 pub struct Canvas {
     image: VersionedImage,
+    metadata_schema: MetadataSchema,
     cursor: Point,
     camera: Point,
     brush_color: Color,
     background_color: Color,
     scale: Scale,
     fps: Fps,
+    selected_slice: Option<String>,
+    history: Option<History>,
+    floating: Option<BTreeMap<Point, Color>>,
+
+    /// Session recorded before the image changes made by the subsequent commands (see [`SESSION`]).
+    session: Option<String>,
     // TODO: fsm(or mode), frames, ticks
     quit: bool,
 }
 
 impl Canvas {
     pub fn new() -> Self {
-        Self::default()
+        Self {
+            image: VersionedImage::default(),
+            metadata_schema: metadata::schema(),
+            cursor: Point::default(),
+            camera: Point::default(),
+            brush_color: Color::default(),
+            background_color: Color::default(),
+            scale: Scale::default(),
+            fps: Fps::default(),
+            selected_slice: None,
+            history: None,
+            floating: None,
+            session: None,
+            quit: false,
+        }
     }
 
     pub fn image(&self) -> &VersionedImage {
@@ -52,10 +75,32 @@ impl Canvas {
         self.fps.0
     }
 
+    pub fn selected_slice(&self) -> Option<(&str, &Slice)> {
+        let name = self.selected_slice.as_ref()?;
+        let slice = self.image.slices().get(name)?;
+        Some((name, slice))
+    }
+
+    pub fn history(&self) -> Option<(Version, &Image)> {
+        self.history.as_ref().map(|h| (h.version, &h.image))
+    }
+
+    pub fn floating(&self) -> Option<&BTreeMap<Point, Color>> {
+        self.floating.as_ref()
+    }
+
     pub fn quit(&self) -> bool {
         self.quit
     }
 
+    /// Sets the session of the subsequent commands (see [`SESSION`]).
+    ///
+    /// The session is recorded just before the next image change if it differs from the current one,
+    /// so commands that don't change the image (e.g., cursor moves) don't modify the image at all.
+    pub fn set_session(&mut self, session: Option<String>) {
+        self.session = session;
+    }
+
     pub fn query(&self, query: &CanvasQuery) -> CanvasQueryValue {
         match query {
             CanvasQuery::Cursor => CanvasQueryValue::Cursor(self.cursor),
@@ -66,6 +111,38 @@ impl Canvas {
             }
             CanvasQuery::Scale => CanvasQueryValue::Scale(self.scale.0),
             CanvasQuery::Fps => CanvasQueryValue::Fps(self.fps.0),
+            CanvasQuery::Pixel(point) => CanvasQueryValue::Pixel(self.image.get_pixel(*point)),
+            CanvasQuery::Pixels { start, end } => {
+                CanvasQueryValue::Pixels(self.image.range_pixels(*start..=*end).collect())
+            }
+            CanvasQuery::Anchors => CanvasQueryValue::Anchors(self.image.anchors().clone()),
+            CanvasQuery::Metadata(key) => {
+                CanvasQueryValue::Metadata(self.image.metadata().get(key).cloned())
+            }
+            CanvasQuery::Version => CanvasQueryValue::Version(self.image.version()),
+            CanvasQuery::BoundingBox => CanvasQueryValue::BoundingBox(self.bounding_box()),
+            CanvasQuery::MarkedPoints => CanvasQueryValue::MarkedPoints(
+                self.selected_slice()
+                    .map(|(_, slice)| (slice.start, slice.end)),
+            ),
+            CanvasQuery::Mode => CanvasQueryValue::Mode(self.mode()),
+        }
+    }
+
+    /// Gets the bounding box (inclusive) of the pixels.
+    pub fn bounding_box(&self) -> Option<(Point, Point)> {
+        Point::bounding_box(self.image.pixels().keys().copied())
+    }
+
+    pub fn mode(&self) -> CanvasMode {
+        if self.history.is_some() {
+            CanvasMode::History
+        } else if self.floating.is_some() {
+            CanvasMode::Float
+        } else if self.selected_slice().is_some() {
+            CanvasMode::Slice
+        } else {
+            CanvasMode::Normal
         }
     }
 
@@ -74,11 +151,59 @@ impl Canvas {
             CanvasCommand::Move(c) => self.handle_move(*c).or_fail()?,
             CanvasCommand::Image(c) => self.handle_image_command(c).or_fail()?,
             CanvasCommand::Scale(c) => self.handle_scale(*c).or_fail()?,
+            CanvasCommand::Slice(c) => self.handle_slice(c).or_fail()?,
+            CanvasCommand::History(c) => self.handle_history(c).or_fail()?,
+            CanvasCommand::Float(c) => self.handle_float(c).or_fail()?,
             CanvasCommand::Quit => self.quit = true,
         }
         Ok(())
     }
 
+    /// Applies the given commands, rolling back the canvas if any of them fails.
+    ///
+    /// The image is rolled back by version, so the cost of a failure doesn't depend on the length of the history.
+    pub fn commands(&mut self, commands: &[CanvasCommand]) -> orfail::Result<()> {
+        let version = self.image.version();
+        let cursor = self.cursor;
+        let camera = self.camera;
+        let scale = self.scale;
+        let fps = self.fps;
+        let selected_slice = self.selected_slice.clone();
+        let history = self.history.as_ref().map(|h| h.version);
+        let floating = self.floating.clone();
+        let quit = self.quit;
+
+        for (i, command) in commands.iter().enumerate() {
+            let result = self.command(command).or_fail_with(|e| {
+                if commands.len() == 1 {
+                    e
+                } else {
+                    format!("Command {} of {} failed: {e}", i + 1, commands.len())
+                }
+            });
+            let Err(e) = result else {
+                continue;
+            };
+            self.image.rollback(version).or_fail()?;
+            self.sync_metadata().or_fail()?;
+            self.cursor = cursor;
+            self.camera = camera;
+            self.scale = scale;
+            self.fps = fps;
+            self.selected_slice = selected_slice;
+            self.history = history
+                .map(|version| {
+                    let image = self.image.restore(version).or_fail()?;
+                    Ok::<_, orfail::Failure>(History { version, image })
+                })
+                .transpose()?;
+            self.floating = floating;
+            self.quit = quit;
+            return Err(e);
+        }
+        Ok(())
+    }
+
     fn handle_move(&mut self, delta: Point) -> orfail::Result<()> {
         self.cursor = self.cursor + delta;
         Ok(())
@@ -90,15 +215,157 @@ impl Canvas {
         Ok(())
     }
 
+    fn handle_history(&mut self, command: &HistoryCommand) -> orfail::Result<()> {
+        match command {
+            HistoryCommand::Scrub(delta) => {
+                let latest = self.image.version();
+                let current = self.history.as_ref().map_or(latest, |h| h.version);
+                let version = (i64::from(current.get()) + i64::from(*delta))
+                    .clamp(0, i64::from(latest.get()));
+                let version = Version::new(version as u32);
+                let image = self.image.restore(version).or_fail()?;
+                self.history = Some(History { version, image });
+            }
+            HistoryCommand::Restore => {
+                if let Some(history) = self.history.take() {
+                    let diff = self.image.diff(history.version).or_fail()?;
+                    self.handle_image_command(&ImageCommand::Patch(diff))
+                        .or_fail()?;
+                }
+            }
+            HistoryCommand::Exit => {
+                self.history = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_float(&mut self, command: &FloatCommand) -> orfail::Result<()> {
+        match command {
+            FloatCommand::Import { path, downsample } => {
+                let mut image = ImportedImage::load(path).or_fail()?;
+                if *downsample {
+                    image = image.downsample(image.pixel_scale());
+                }
+                self.floating = Some(image.pixels(self.cursor).or_fail()?.collect());
+            }
+            FloatCommand::Move(delta) => {
+                if let Some(pixels) = self.floating.take() {
+                    let pixels = pixels.into_iter().map(|(p, c)| (p + *delta, c)).collect();
+                    self.floating = Some(pixels);
+                }
+            }
+            FloatCommand::Commit => {
+                if let Some(pixels) = self.floating.take() {
+                    self.handle_image_command(&ImageCommand::draw_pixels(pixels.into_iter()))
+                        .or_fail()?;
+                }
+            }
+            FloatCommand::Cancel => {
+                self.floating = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_slice(&mut self, command: &SliceCommand) -> orfail::Result<()> {
+        match command {
+            SliceCommand::New(name) => {
+                let slice = Slice::new(self.cursor, self.cursor);
+                self.handle_image_command(&ImageCommand::slice(name, Some(slice)))
+                    .or_fail()?;
+                self.selected_slice = Some(name.clone());
+            }
+            SliceCommand::Select(name) => {
+                self.image
+                    .slices()
+                    .contains_key(name)
+                    .or_fail_with(|()| format!("No such slice: {name}"))?;
+                self.selected_slice = Some(name.clone());
+            }
+            SliceCommand::Deselect => {
+                self.selected_slice = None;
+            }
+            SliceCommand::Move(delta) => {
+                self.update_selected_slice(|slice| slice.translate(*delta))
+                    .or_fail()?;
+            }
+            SliceCommand::Resize(delta) => {
+                self.update_selected_slice(|slice| slice.resize(*delta))
+                    .or_fail()?;
+            }
+            SliceCommand::Remove => {
+                if let Some(name) = self.selected_slice.take() {
+                    self.handle_image_command(&ImageCommand::slice(name, None))
+                        .or_fail()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn update_selected_slice<F>(&mut self, f: F) -> orfail::Result<()>
+    where
+        F: FnOnce(&mut Slice),
+    {
+        let Some((name, slice)) = self.selected_slice() else {
+            return Ok(());
+        };
+        let name = name.to_owned();
+        let mut slice = slice.clone();
+        f(&mut slice);
+        self.handle_image_command(&ImageCommand::slice(name, Some(slice)))
+            .or_fail()?;
+        Ok(())
+    }
+
     fn handle_image_command(&mut self, command: &ImageCommand) -> orfail::Result<()> {
-        self.image.apply(command);
-        if let ImageCommand::Put { .. } = command {
-            // TODO
+        self.metadata_schema
+            .validate_command(self.image.metadata(), command)
+            .or_fail_with(|e| format!("Invalid metadata value: {e}"))?;
+        let version = self.image.version();
+        let stamped = self.stamp_session().or_fail()?;
+        if !self.image.apply(command) && stamped {
+            // Sessions are only recorded along with image changes.
+            self.image.rollback(version).or_fail()?;
+        }
+        if let ImageCommand::Put { .. } | ImageCommand::Merge { .. } = command {
+            self.sync_metadata().or_fail()?;
+        }
+        Ok(())
+    }
+
+    /// Records the current session if it isn't the session of the image yet.
+    fn stamp_session(&mut self) -> orfail::Result<bool> {
+        let Some(session) = &self.session else {
+            return Ok(false);
+        };
+        if SESSION.get(self.image.metadata()).or_fail()?.as_ref() == Some(session) {
+            return Ok(false);
         }
+        let command = SESSION.put(&Some(session.clone())).or_fail()?;
+        Ok(self.image.apply(&command))
+    }
+
+    fn sync_metadata(&mut self) -> orfail::Result<()> {
+        self.background_color = BACKGROUND_COLOR.get(self.image.metadata()).or_fail()?;
+        self.brush_color = BRUSH_COLOR.get(self.image.metadata()).or_fail()?;
         Ok(())
     }
 }
 
+impl Default for Canvas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct History {
+    version: Version,
+    image: Image,
+}
+
 #[derive(Debug, Clone, Copy)]
 struct Scale(NonZeroU8);
 
@@ -1,177 +1,1645 @@
+use crate::transport::{Listener, Stream};
 use crate::{
     command::CanvasCommand,
-    query::{CanvasQuery, CanvasQueryValue},
+    event::CanvasEvent,
+    query::{CanvasMode, CanvasQuery, CanvasQueryValue},
+    Canvas, CanvasFile,
 };
 use orfail::OrFail;
+use pati::{Point, Version};
 use serde::{Deserialize, Serialize};
+use std::io::Write;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, VecDeque},
     io::{BufRead, BufReader},
-    net::{SocketAddr, TcpListener, TcpStream},
-};
-use std::{
-    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
     time::Duration,
 };
 
+#[cfg(unix)]
+use std::os::unix::{
+    fs::{DirBuilderExt, MetadataExt},
+    net::{UnixListener, UnixStream},
+};
+
+const MAGIC_NUMBER: &str = "PATICA";
+
+/// Version of the agent protocol implemented by this crate.
+pub const AGENT_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features supported by this crate.
+pub const AGENT_FEATURES: &[&str] = &["batch", "pipelining", "subscribe"];
+
+/// Maximum size of the unsent messages of a client.
+///
+/// If a subscribed client exceeds this, its pending events are dropped (see [`CanvasEvent::Lagged`]).
+const MAX_PENDING_BYTES: usize = 1024 * 1024;
+
+/// Maximum size of the unsent responses of a client.
+///
+/// Clients that keep sending requests without reading the responses are disconnected if they exceed this.
+const MAX_PENDING_RESPONSE_BYTES: usize = 64 * MAX_PENDING_BYTES;
+
+/// Interval to retry accepting connections after a failure (e.g., too many open files).
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Address of a [`CanvasAgentServer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CanvasAgentAddr {
+    /// TCP port on localhost.
+    Port(u16),
+
+    /// Path of a Unix domain socket.
+    #[cfg(unix)]
+    Socket(PathBuf),
+}
+
+impl std::fmt::Display for CanvasAgentAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Port(port) => write!(f, "port {port}"),
+            #[cfg(unix)]
+            Self::Socket(path) => write!(f, "socket {}", path.display()),
+        }
+    }
+}
+
+/// Identifier of a client connected to a [`CanvasAgentServer`] (assigned in the order of connection).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CanvasAgentClientId(u64);
+
 #[derive(Debug)]
 pub struct CanvasAgentServer {
-    listener: TcpListener,
-    port: u16,
-    clients: HashMap<SocketAddr, ClientState>,
+    addr: CanvasAgentAddr,
+    clients: BTreeMap<CanvasAgentClientId, ClientState>,
+
+    /// Connections and lines received by the I/O threads.
+    incoming: mpsc::Receiver<Incoming>,
+
+    /// Set when this server is dropped to stop the thread accepting connections.
+    stopped: Arc<AtomicBool>,
+    registry_path: Option<PathBuf>,
+    socket_path: Option<PathBuf>,
+    file_path: Option<PathBuf>,
+    published: Option<Snapshot>,
 }
 
 impl CanvasAgentServer {
     pub fn start() -> orfail::Result<Self> {
-        let listener = TcpListener::bind("127.0.0.1:0").or_fail()?;
-        listener.set_nonblocking(true).or_fail()?;
+        Self::start_on(0).or_fail()
+    }
+
+    /// Like [`CanvasAgentServer::start()`] but listens on the given port (`0` means an ephemeral port).
+    pub fn start_on(port: u16) -> orfail::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .or_fail_with(|e| format!("Failed to listen on port {port}: {e}"))?;
         let port = listener.local_addr().or_fail()?.port();
-        Ok(Self {
-            listener,
-            port,
-            clients: HashMap::new(),
-        })
+        Ok(Self::new(
+            Listener::Tcp(listener),
+            CanvasAgentAddr::Port(port),
+        ))
+    }
+
+    /// Starts a server listening on the Unix domain socket of the given file,
+    /// so that [`CanvasAgent::discover()`] can find it (no need to call [`CanvasAgentServer::register()`]).
+    ///
+    /// The socket is placed in a directory only accessible by the owner (see [`CanvasAgent::discover()`])
+    /// and is removed when this server is dropped.
+    #[cfg(unix)]
+    pub fn start_unix<P: AsRef<Path>>(file_path: P) -> orfail::Result<Self> {
+        let file_path = canonicalize(file_path.as_ref()).or_fail()?;
+        let socket_path = socket_path(&file_path).or_fail()?;
+        UnixStream::connect(&socket_path)
+            .is_err()
+            .or_fail_with(|()| format!("Another editor has opened {}", file_path.display()))?;
+
+        // Other users can't reach the socket because its directory is private.
+        let _ = std::fs::remove_file(&socket_path);
+        let listener = UnixListener::bind(&socket_path)
+            .or_fail_with(|e| format!("Failed to listen on {}: {e}", socket_path.display()))?;
+
+        let mut this = Self::new(
+            Listener::Unix(listener),
+            CanvasAgentAddr::Socket(socket_path.clone()),
+        );
+        this.socket_path = Some(socket_path);
+        this.file_path = Some(file_path);
+        Ok(this)
+    }
+
+    fn new(listener: Listener, addr: CanvasAgentAddr) -> Self {
+        let (tx, incoming) = mpsc::channel();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let accept_stopped = Arc::clone(&stopped);
+        std::thread::spawn(move || accept_clients(listener, tx, accept_stopped));
+        Self {
+            addr,
+            clients: BTreeMap::new(),
+            incoming,
+            stopped,
+            registry_path: None,
+            socket_path: None,
+            file_path: None,
+            published: None,
+        }
+    }
+
+    /// Registers the port of a TCP server as the agent of the given file,
+    /// so that [`CanvasAgent::discover()`] can find it.
+    ///
+    /// The registry entry is removed when this server is dropped.
+    /// The path is also reported to clients in the handshake (see [`CanvasAgentServerInfo::file`]).
+    pub fn register<P: AsRef<Path>>(&mut self, file_path: P) -> orfail::Result<()> {
+        let file_path = canonicalize(file_path.as_ref()).or_fail()?;
+        let registry_path = registry_path(&file_path).or_fail()?;
+        let CanvasAgentAddr::Port(port) = self.addr else {
+            return Err(orfail::Failure::new(
+                "Only TCP servers need to be registered",
+            ));
+        };
+        std::fs::write(&registry_path, port.to_string())
+            .or_fail_with(|e| format!("Failed to write file {}: {e}", registry_path.display()))?;
+        self.registry_path = Some(registry_path);
+        self.file_path = Some(file_path);
+        Ok(())
+    }
+
+    /// Gets the information sent to clients in the handshake.
+    pub fn info(&self) -> CanvasAgentServerInfo {
+        CanvasAgentServerInfo {
+            protocol_version: AGENT_PROTOCOL_VERSION,
+            features: AGENT_FEATURES.iter().map(|f| f.to_string()).collect(),
+            file: self.file_path.clone(),
+            version: self.published.map(|s| s.version).unwrap_or_default(),
+        }
     }
 
-    pub fn poll_request(&mut self) -> orfail::Result<Option<(SocketAddr, CanvasAgentRequest)>> {
-        match self.listener.accept() {
-            Ok((stream, addr)) => {
-                stream
-                    .set_read_timeout(Some(Duration::from_secs(0)))
-                    .or_fail()?;
-                self.clients
-                    .insert(addr, ClientState::new(stream).or_fail()?);
+    /// Gets all the complete requests that have arrived since the last call, without blocking.
+    ///
+    /// Clients can send multiple requests without waiting for the responses (pipelining),
+    /// and the requests of each client are returned in the order they were sent.
+    /// Failures of a client only disconnect that client.
+    pub fn poll_requests(&mut self) -> Vec<(CanvasAgentClientId, CanvasAgentRequestFrame)> {
+        let mut requests = Vec::new();
+        while let Ok(incoming) = self.incoming.try_recv() {
+            requests.extend(self.receive(incoming));
+        }
+        requests
+    }
+
+    /// Blocks until a request arrives.
+    ///
+    /// Connections are accepted and read by background threads, so this just waits for their notifications.
+    /// Requests are returned in the order they arrived (see also [`CanvasAgentServer::poll_requests()`]).
+    pub fn wait_request(
+        &mut self,
+    ) -> orfail::Result<(CanvasAgentClientId, CanvasAgentRequestFrame)> {
+        loop {
+            let incoming = self
+                .incoming
+                .recv()
+                .or_fail_with(|_| "The listener of the agent server aborted".to_owned())?;
+            if let Some(request) = self.receive(incoming) {
+                return Ok(request);
             }
-            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-            Err(e) => return Err(e).or_fail(),
+        }
+    }
+
+    /// Handles a request against the given file and sends the response.
+    ///
+    /// Failures of the request (e.g., an invalid command) are reported to the client as [`CanvasAgentError`]s,
+    /// and I/O errors of the client disconnect it, so this never fails.
+    pub fn handle_request(
+        &mut self,
+        file: &mut CanvasFile,
+        client_id: CanvasAgentClientId,
+        frame: CanvasAgentRequestFrame,
+    ) {
+        let session = self
+            .clients
+            .get(&client_id)
+            .and_then(|client| client.session.clone());
+        let apply = |commands: &[CanvasCommand]| match session {
+            Some(session) => file.commands_in_session(Some(session), commands),
+            None => file.commands(commands),
+        };
+        let result = match frame.request {
+            CanvasAgentRequest::Command(command) => apply(std::slice::from_ref(&command))
+                .map(|()| serde_json::Value::Null)
+                .map_err(|e| CanvasAgentError::new(CanvasAgentErrorKind::CommandFailed, e)),
+            CanvasAgentRequest::Batch(commands) => apply(&commands)
+                .map(|()| serde_json::Value::Null)
+                .map_err(|e| CanvasAgentError::new(CanvasAgentErrorKind::CommandFailed, e)),
+            CanvasAgentRequest::Query(query) => file
+                .sync()
+                .and_then(|()| serde_json::to_value(file.canvas().query(&query)).or_fail())
+                .map_err(|e| CanvasAgentError::new(CanvasAgentErrorKind::Internal, e)),
+            CanvasAgentRequest::Subscribe => return self.subscribe(client_id, frame.id),
+        };
+        self.send_response(client_id, Some(frame.id), result);
+    }
+
+    /// Sends the response to a request.
+    ///
+    /// Responses to disconnected clients are discarded.
+    pub fn send_response(
+        &mut self,
+        client_id: CanvasAgentClientId,
+        id: Option<u64>,
+        result: Result<serde_json::Value, CanvasAgentError>,
+    ) {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+        if client.respond(id, result).is_err() {
+            self.disconnect(client_id);
+        }
+    }
+
+    /// Makes the given client receive [`CanvasEvent`]s (see [`CanvasAgentServer::publish()`]).
+    pub fn subscribe(&mut self, client_id: CanvasAgentClientId, id: u64) {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+        client.subscribed = true;
+        self.send_response(client_id, Some(id), Ok(serde_json::Value::Null));
+    }
+
+    /// Sends the changes of the canvas since the last call to the subscribed clients.
+    ///
+    /// Messages are written by the writer thread of each client (so this never blocks),
+    /// and clients that don't keep up receive [`CanvasEvent::Lagged`] instead of the dropped events.
+    pub fn publish(&mut self, canvas: &Canvas) -> orfail::Result<()> {
+        let current = Snapshot::new(canvas);
+        let Some(last) = self.published.replace(current) else {
+            return Ok(());
+        };
+
+        let mut events = Vec::new();
+        for (i, command) in canvas
+            .image()
+            .applied_commands(last.version)
+            .iter()
+            .enumerate()
+        {
+            events.push(CanvasEvent::Image {
+                version: last.version + (i as u32 + 1),
+                command: command.clone(),
+            });
+        }
+        if last.cursor != current.cursor {
+            events.push(CanvasEvent::Cursor(current.cursor));
+        }
+        if last.camera != current.camera {
+            events.push(CanvasEvent::Camera(current.camera));
+        }
+        if last.mode != current.mode {
+            events.push(CanvasEvent::Mode(current.mode));
+        }
+        if events.is_empty() {
+            return Ok(());
         }
 
         let mut closed = vec![];
-        for (&addr, client) in &mut self.clients {
-            match client.poll() {
-                Ok(Some(request)) => return Ok(Some((addr, request))),
-                Ok(None) => {}
-                Err(()) => closed.push(addr),
+        for (&client_id, client) in self.clients.iter_mut().filter(|(_, c)| c.subscribed) {
+            if events
+                .iter()
+                .try_for_each(|event| client.enqueue_event(event))
+                .is_err()
+            {
+                closed.push(client_id);
             }
         }
-        for addr in closed {
-            self.clients.remove(&addr);
+        for client_id in closed {
+            self.disconnect(client_id);
         }
-        Ok(None)
+        Ok(())
     }
 
-    pub fn send_response(
+    pub fn addr(&self) -> &CanvasAgentAddr {
+        &self.addr
+    }
+
+    fn receive(
         &mut self,
-        addr: SocketAddr,
-        response: impl Serialize,
-    ) -> orfail::Result<()> {
-        let client = self.clients.get_mut(&addr).or_fail()?;
-        if send(&mut client.writer, response).is_err() {
-            self.clients.remove(&addr);
+        incoming: Incoming,
+    ) -> Option<(CanvasAgentClientId, CanvasAgentRequestFrame)> {
+        match incoming {
+            Incoming::Connected(client_id, stream) => {
+                if let Ok(client) = ClientState::new(stream) {
+                    self.clients.insert(client_id, client);
+                }
+                None
+            }
+            Incoming::Line(client_id, line) => {
+                let info = self.info();
+                let client = self.clients.get_mut(&client_id)?;
+                match client.handle_line(&line, &info) {
+                    Ok(frame) => frame.map(|frame| (client_id, frame)),
+                    Err(()) => {
+                        // The remaining messages (e.g., a handshake error) are still sent before closing.
+                        self.clients.remove(&client_id);
+                        None
+                    }
+                }
+            }
+            Incoming::Closed(client_id) => {
+                self.clients.remove(&client_id);
+                None
+            }
+        }
+    }
+
+    /// Disconnects the given client immediately, discarding its unsent messages.
+    fn disconnect(&mut self, client_id: CanvasAgentClientId) {
+        if let Some(client) = self.clients.remove(&client_id) {
+            client.disconnect();
+        }
+    }
+}
+
+impl Drop for CanvasAgentServer {
+    fn drop(&mut self) {
+        // Wake up the accepting thread so that it notices the stop.
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = match &self.addr {
+            CanvasAgentAddr::Port(port) => TcpStream::connect(("127.0.0.1", *port)).map(drop),
+            #[cfg(unix)]
+            CanvasAgentAddr::Socket(path) => UnixStream::connect(path).map(drop),
+        };
+        for client in std::mem::take(&mut self.clients).into_values() {
+            client.disconnect();
+        }
+
+        for path in [&self.registry_path, &self.socket_path]
+            .into_iter()
+            .flatten()
+        {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Notification from the I/O threads of a [`CanvasAgentServer`].
+#[derive(Debug)]
+enum Incoming {
+    Connected(CanvasAgentClientId, Stream),
+
+    /// A complete line (including the trailing newline).
+    ///
+    /// Lines are decoded by the server after splitting so that multi-byte characters split across reads are not broken.
+    Line(CanvasAgentClientId, Vec<u8>),
+    Closed(CanvasAgentClientId),
+}
+
+/// Accepts connections (blocking) and starts a reader thread for each client until `stopped` is set.
+fn accept_clients(listener: Listener, tx: mpsc::Sender<Incoming>, stopped: Arc<AtomicBool>) {
+    for client_id in (0..).map(CanvasAgentClientId) {
+        let stream = loop {
+            let result = listener.accept();
+            if stopped.load(Ordering::SeqCst) {
+                return;
+            }
+            match result {
+                Ok(stream) => break stream,
+                Err(_) => std::thread::sleep(ACCEPT_RETRY_INTERVAL),
+            }
+        };
+        let Ok(reader) = stream.try_clone() else {
+            continue;
+        };
+        if tx.send(Incoming::Connected(client_id, stream)).is_err() {
+            return;
         }
+        let tx = tx.clone();
+        std::thread::spawn(move || read_lines(client_id, reader, tx));
+    }
+}
+
+/// Forwards the lines of a client to the server until the client disconnects.
+fn read_lines(client_id: CanvasAgentClientId, stream: Stream, tx: mpsc::Sender<Incoming>) {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = Vec::new();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(_) if line.ends_with(b"\n") => {
+                if tx.send(Incoming::Line(client_id, line)).is_err() {
+                    return;
+                }
+            }
+            _ => break,
+        }
+    }
+    let _ = tx.send(Incoming::Closed(client_id));
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Snapshot {
+    version: Version,
+    cursor: Point,
+    camera: Point,
+    mode: CanvasMode,
+}
+
+impl Snapshot {
+    fn new(canvas: &Canvas) -> Self {
+        Self {
+            version: canvas.image().version(),
+            cursor: canvas.cursor(),
+            camera: canvas.camera(),
+            mode: canvas.mode(),
+        }
+    }
+}
+
+/// JSON line to be sent to a client.
+#[derive(Debug)]
+struct Message {
+    bytes: Vec<u8>,
+
+    /// Number of the events represented by this message (used to count dropped events).
+    events: usize,
+}
+
+/// Unsent messages of a client.
+///
+/// The messages are written by a thread dedicated to the client, so slow clients don't block the server.
+#[derive(Debug, Default)]
+struct Outbox {
+    state: Mutex<OutboxState>,
+    changed: Condvar,
+}
+
+impl Outbox {
+    /// Waits for the next message to be written (`None` if the outbox is closed and empty).
+    fn next_message(&self) -> Option<Message> {
+        let mut state = self.state.lock().ok()?;
+        loop {
+            if let Some(message) = state.messages.pop_front() {
+                state.bytes -= message.bytes.len();
+                return Some(message);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.changed.wait(state).ok()?;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct OutboxState {
+    messages: VecDeque<Message>,
+
+    /// Number of the unsent bytes (excluding the message being written).
+    bytes: usize,
+
+    /// If `true`, the writer thread closes the stream after writing the remaining messages.
+    closed: bool,
+}
+
+impl OutboxState {
+    fn push(&mut self, value: impl Serialize, events: usize) -> Result<(), ()> {
+        let mut bytes = serde_json::to_vec(&value).map_err(|_| ())?;
+        bytes.push(b'\n');
+        self.bytes += bytes.len();
+        self.messages.push_back(Message { bytes, events });
         Ok(())
     }
+}
 
-    pub fn port(&self) -> u16 {
-        self.port
+/// Writes the messages of the given outbox until it is closed or the client disconnects.
+fn write_messages(outbox: Arc<Outbox>, mut stream: Stream) {
+    while let Some(message) = outbox.next_message() {
+        if stream.write_all(&message.bytes).is_err() {
+            break;
+        }
     }
+
+    // This also stops the reader thread of the client.
+    let _ = stream.shutdown();
 }
 
 #[derive(Debug)]
 struct ClientState {
-    reader: BufReader<TcpStream>,
-    writer: TcpStream,
-    line: String,
+    /// Stream of the client (only used to disconnect it; the I/O threads read and write the clones).
+    stream: Stream,
+    outbox: Arc<Outbox>,
+    handshaked: bool,
+    subscribed: bool,
+
+    /// Session of the client sent in the handshake.
+    session: Option<String>,
 }
 
 impl ClientState {
-    fn new(writer: TcpStream) -> orfail::Result<Self> {
+    /// Makes a new [`ClientState`] instance and starts the writer thread of the client.
+    fn new(stream: Stream) -> Result<Self, ()> {
+        let writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(_) => {
+                let _ = stream.shutdown();
+                return Err(());
+            }
+        };
+        let outbox = Arc::new(Outbox::default());
+        let writer_outbox = Arc::clone(&outbox);
+        std::thread::spawn(move || write_messages(writer_outbox, writer));
         Ok(Self {
-            reader: BufReader::new(writer.try_clone().or_fail()?),
-            writer,
-            line: String::new(),
+            stream,
+            outbox,
+            handshaked: false,
+            subscribed: false,
+            session: None,
         })
     }
 
-    fn poll(&mut self) -> Result<Option<CanvasAgentRequest>, ()> {
-        match self.reader.read_line(&mut self.line) {
-            Ok(0) => Err(()),
-            Ok(_) => {
-                let request: CanvasAgentRequest =
-                    serde_json::from_str(&self.line).map_err(|_| ())?;
-                self.line.clear();
-                Ok(Some(request))
+    fn enqueue(&mut self, value: impl Serialize) -> Result<(), ()> {
+        let mut state = self.outbox.state.lock().map_err(|_| ())?;
+        state.push(value, 0)?;
+        self.outbox.changed.notify_one();
+        Ok(())
+    }
+
+    fn respond(
+        &mut self,
+        id: Option<u64>,
+        result: Result<serde_json::Value, CanvasAgentError>,
+    ) -> Result<(), ()> {
+        let result = match result {
+            Ok(value) => CanvasAgentResult::Ok(value),
+            Err(error) => CanvasAgentResult::Error(error),
+        };
+        self.enqueue(CanvasAgentMessage::Response(CanvasAgentResponse {
+            id,
+            result,
+        }))?;
+        if self.pending_bytes() > MAX_PENDING_RESPONSE_BYTES {
+            return Err(());
+        }
+        Ok(())
+    }
+
+    fn enqueue_event(&mut self, event: &CanvasEvent) -> Result<(), ()> {
+        let mut state = self.outbox.state.lock().map_err(|_| ())?;
+        if state.bytes > MAX_PENDING_BYTES {
+            // Replace the unsent events with a `Lagged` event (responses are never dropped).
+            let mut dropped = 0;
+            state.messages.retain(|m| {
+                dropped += m.events;
+                m.events == 0
+            });
+            state.bytes = state.messages.iter().map(|m| m.bytes.len()).sum();
+            let lagged = CanvasAgentMessage::Event(CanvasEvent::Lagged { dropped });
+            state.push(lagged, dropped)?;
+        }
+        state.push(CanvasAgentMessage::Event(event.clone()), 1)?;
+        self.outbox.changed.notify_one();
+        Ok(())
+    }
+
+    fn pending_bytes(&self) -> usize {
+        self.outbox.state.lock().map_or(0, |state| state.bytes)
+    }
+
+    /// Handles a line received from the client, returning the request if the line is one.
+    fn handle_line(
+        &mut self,
+        line: &[u8],
+        info: &CanvasAgentServerInfo,
+    ) -> Result<Option<CanvasAgentRequestFrame>, ()> {
+        if !self.handshaked {
+            let result = serde_json::from_slice::<Handshake>(line)
+                .map_err(|e| {
+                    CanvasAgentError::new(
+                        CanvasAgentErrorKind::InvalidRequest,
+                        format!("Invalid handshake: {e}"),
+                    )
+                })
+                .and_then(|handshake| {
+                    let session = handshake.session.clone();
+                    handshake.negotiate(info).map(|info| (info, session))
+                });
+            let accepted = result.is_ok();
+            let result = match result {
+                Ok((info, session)) => {
+                    self.session = session;
+                    CanvasAgentResult::Ok(serde_json::to_value(info).map_err(|_| ())?)
+                }
+                Err(error) => CanvasAgentResult::Error(error),
+            };
+            self.enqueue(&result)?;
+            if !accepted {
+                // Incompatible clients are disconnected after receiving the error.
+                return Err(());
             }
-            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
-            Err(_) => Err(()),
+            self.handshaked = true;
+            return Ok(None);
+        }
+
+        match serde_json::from_slice::<CanvasAgentRequestFrame>(line) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(e) => {
+                // Reply with the ID if the frame is well-formed enough to have one.
+                let id = serde_json::from_slice::<serde_json::Value>(line)
+                    .ok()
+                    .and_then(|v| v.get("id").and_then(|id| id.as_u64()));
+                let error = CanvasAgentError::new(
+                    CanvasAgentErrorKind::InvalidRequest,
+                    format!("Invalid request: {e}"),
+                );
+                self.respond(id, Err(error))?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Closes the stream without sending the remaining messages.
+    fn disconnect(self) {
+        if let Ok(mut state) = self.outbox.state.lock() {
+            state.messages.clear();
+            state.bytes = 0;
+        }
+        let _ = self.stream.shutdown();
+    }
+}
+
+impl Drop for ClientState {
+    fn drop(&mut self) {
+        // The writer thread closes the stream after sending the remaining messages.
+        if let Ok(mut state) = self.outbox.state.lock() {
+            state.closed = true;
         }
+        self.outbox.changed.notify_one();
     }
 }
 
 #[derive(Debug)]
 pub struct CanvasAgent {
-    stream: TcpStream,
+    reader: BufReader<Stream>,
+    writer: Stream,
+    next_id: u64,
+    server_info: CanvasAgentServerInfo,
 }
 
 impl CanvasAgent {
-    pub fn connect(port: u16) -> orfail::Result<Self> {
-        let mut stream = TcpStream::connect(("127.0.0.1", port)).or_fail()?;
+    pub fn connect(addr: &CanvasAgentAddr) -> orfail::Result<Self> {
+        Self::connect_in_session(addr, None).or_fail()
+    }
+
+    /// Like [`CanvasAgent::connect()`] but makes the editor record the image changes made by this agent
+    /// as made in the given session (see [`SESSION`][crate::SESSION]).
+    pub fn connect_in_session(
+        addr: &CanvasAgentAddr,
+        session: Option<String>,
+    ) -> orfail::Result<Self> {
+        let failed = |e| format!("Failed to connect to the editor ({addr}): {e}");
+        let mut writer = match addr {
+            CanvasAgentAddr::Port(port) => {
+                let stream = TcpStream::connect(("127.0.0.1", *port)).or_fail_with(failed)?;
+                stream.set_nodelay(true).or_fail()?;
+                Stream::Tcp(stream)
+            }
+            #[cfg(unix)]
+            CanvasAgentAddr::Socket(path) => {
+                check_owner(path).or_fail()?;
+                Stream::Unix(UnixStream::connect(path).or_fail_with(failed)?)
+            }
+        };
+        let mut reader = BufReader::new(writer.try_clone().or_fail()?);
 
         // Handshake
-        send(&mut stream, Handshake::new()).or_fail()?;
-        recv::<orfail::Result<()>>(&mut stream)
-            .or_fail()?
-            .or_fail()?;
+        let handshake = Handshake {
+            session,
+            ..Handshake::new()
+        };
+        send(&mut writer, handshake).or_fail()?;
+        let server_info = match recv(&mut reader).or_fail()? {
+            CanvasAgentResult::Ok(value) => serde_json::from_value(value).or_fail()?,
+            CanvasAgentResult::Error(e) => {
+                return Err(orfail::Failure::new(format!(
+                    "Handshake with the editor failed: {e}"
+                )));
+            }
+        };
+
+        Ok(Self {
+            reader,
+            writer,
+            next_id: 0,
+            server_info,
+        })
+    }
 
-        Ok(Self { stream })
+    /// Gets the information of the server negotiated in the handshake.
+    pub fn server_info(&self) -> &CanvasAgentServerInfo {
+        &self.server_info
+    }
+
+    /// Finds the address of the editor that has opened the given file.
+    ///
+    /// The Unix domain socket (see [`CanvasAgentServer::start_unix()`]) is preferred over
+    /// the registered port (see [`CanvasAgentServer::register()`]).
+    ///
+    /// Both are placed in `$XDG_RUNTIME_DIR/patica/` (or `$TMPDIR/patica-{uid}/` if `$XDG_RUNTIME_DIR` isn't set),
+    /// which must be a directory owned by the current user and not accessible by others.
+    pub fn discover<P: AsRef<Path>>(file_path: P) -> orfail::Result<CanvasAgentAddr> {
+        let file_path = file_path.as_ref();
+
+        #[cfg(unix)]
+        {
+            let socket_path = socket_path(file_path).or_fail()?;
+            if socket_path.exists() {
+                return Ok(CanvasAgentAddr::Socket(socket_path));
+            }
+        }
+
+        let registry_path = registry_path(file_path).or_fail()?;
+        let port = std::fs::read_to_string(&registry_path)
+            .or_fail_with(|e| format!("No running editor for {}: {e}", file_path.display()))?;
+        #[cfg(unix)]
+        check_owner(&registry_path).or_fail()?;
+        port.trim()
+            .parse::<u16>()
+            .map(CanvasAgentAddr::Port)
+            .or_fail_with(|e| format!("Invalid port in {}: {e}", registry_path.display()))
     }
 
     pub fn command(&mut self, command: CanvasCommand) -> orfail::Result<()> {
-        let request = CanvasAgentRequest::Command(command);
-        send(&mut self.stream, &request).or_fail()?;
-        recv(&mut self.stream).or_fail()
+        self.request(CanvasAgentRequest::Command(command))
+            .or_fail()?
+            .or_fail()?;
+        Ok(())
+    }
+
+    /// Applies the given commands atomically (if any of them fails, none of them are applied).
+    pub fn batch(&mut self, commands: Vec<CanvasCommand>) -> orfail::Result<()> {
+        self.server_info
+            .has_feature("batch")
+            .or_fail_with(|()| "The editor doesn't support batch requests".to_owned())?;
+        self.request(CanvasAgentRequest::Batch(commands))
+            .or_fail()?
+            .or_fail()?;
+        Ok(())
     }
 
     pub fn query(&mut self, query: CanvasQuery) -> orfail::Result<CanvasQueryValue> {
-        let request = CanvasAgentRequest::Query(query);
-        send(&mut self.stream, &request).or_fail()?;
-        recv(&mut self.stream).or_fail()
+        let value = self
+            .request(CanvasAgentRequest::Query(query))
+            .or_fail()?
+            .or_fail()?;
+        serde_json::from_value(value).or_fail()
+    }
+
+    /// Sends a request and waits for its response.
+    ///
+    /// The outer result is the failure of the communication and the inner one is the error reported by the server.
+    pub fn request(
+        &mut self,
+        request: CanvasAgentRequest,
+    ) -> orfail::Result<Result<serde_json::Value, CanvasAgentError>> {
+        let mut results = self.pipeline(vec![request]).or_fail()?;
+        results.pop().or_fail()
+    }
+
+    /// Sends the given requests at once and then waits for all the responses (in the order of the requests).
+    pub fn pipeline(
+        &mut self,
+        requests: Vec<CanvasAgentRequest>,
+    ) -> orfail::Result<Vec<Result<serde_json::Value, CanvasAgentError>>> {
+        let first_id = self.next_id;
+        let mut frames = Vec::new();
+        for request in requests {
+            let frame = CanvasAgentRequestFrame {
+                id: self.next_id,
+                request,
+            };
+            self.next_id += 1;
+            frames.extend(serde_json::to_vec(&frame).or_fail()?);
+            frames.push(b'\n');
+        }
+        self.writer.write_all(&frames).or_fail()?;
+        self.writer.flush().or_fail()?;
+
+        let mut results = (first_id..self.next_id).map(|_| None).collect::<Vec<_>>();
+        while results.iter().any(|r| r.is_none()) {
+            let CanvasAgentMessage::Response(response) = recv(&mut self.reader).or_fail()? else {
+                continue;
+            };
+            let result = match response.result {
+                CanvasAgentResult::Ok(value) => Ok(value),
+                CanvasAgentResult::Error(error) => Err(error),
+            };
+            let Some(id) = response.id else {
+                // The server could not parse a request (should not happen for this client).
+                return Err(orfail::Failure::new(result.err().or_fail()?.to_string()));
+            };
+            let i = id.checked_sub(first_id).or_fail()? as usize;
+            let slot = results
+                .get_mut(i)
+                .or_fail_with(|()| format!("Unexpected response ID: {id}"))?;
+            *slot = Some(result);
+        }
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    /// Turns this connection into a stream of [`CanvasEvent`]s.
+    pub fn subscribe(mut self) -> orfail::Result<CanvasSubscription> {
+        self.request(CanvasAgentRequest::Subscribe)
+            .or_fail()?
+            .or_fail()?;
+        Ok(CanvasSubscription {
+            reader: self.reader,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct CanvasSubscription {
+    reader: BufReader<Stream>,
+}
+
+impl CanvasSubscription {
+    /// Blocks until the next event arrives.
+    pub fn recv(&mut self) -> orfail::Result<CanvasEvent> {
+        loop {
+            if let CanvasAgentMessage::Event(event) = recv(&mut self.reader).or_fail()? {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+fn canonicalize(file_path: &Path) -> orfail::Result<PathBuf> {
+    file_path
+        .canonicalize()
+        .or_fail_with(|e| format!("Failed to resolve path {}: {e}", file_path.display()))
+}
+
+/// Gets the path of the registry entry of the given file (`{runtime directory}/{hash of the canonical path}.port`).
+fn registry_path(file_path: &Path) -> orfail::Result<PathBuf> {
+    runtime_path(file_path, "port").or_fail()
+}
+
+/// Gets the path of the Unix domain socket of the given file (`{runtime directory}/{hash of the canonical path}.sock`).
+///
+/// The socket isn't placed next to the file because the length of socket paths is limited (about 100 bytes).
+#[cfg(unix)]
+fn socket_path(file_path: &Path) -> orfail::Result<PathBuf> {
+    runtime_path(file_path, "sock").or_fail()
+}
+
+fn runtime_path(file_path: &Path, extension: &str) -> orfail::Result<PathBuf> {
+    let file_path = canonicalize(file_path).or_fail()?;
+
+    // FNV-1a (stable across processes, unlike `DefaultHasher`)
+    let hash = file_path
+        .to_string_lossy()
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |h, b| {
+            (h ^ u64::from(b)).wrapping_mul(0x100000001b3)
+        });
+    Ok(runtime_dir()
+        .or_fail()?
+        .join(format!("{hash:016x}.{extension}")))
+}
+
+/// Gets the directory of the sockets and registry entries (creating it if needed).
+#[cfg(unix)]
+fn runtime_dir() -> orfail::Result<PathBuf> {
+    let uid = current_uid();
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir).join("patica"),
+        _ => std::env::temp_dir().join(format!("patica-{uid}")),
+    };
+    private_dir(dir, uid).or_fail()
+}
+
+#[cfg(not(unix))]
+fn runtime_dir() -> orfail::Result<PathBuf> {
+    let dir = std::env::temp_dir().join("patica");
+    std::fs::create_dir_all(&dir)
+        .or_fail_with(|e| format!("Failed to create directory {}: {e}", dir.display()))?;
+    Ok(dir)
+}
+
+/// Creates the given directory only accessible by the owner if it doesn't exist.
+///
+/// Fails if the directory is not owned by `uid` or is accessible by others
+/// (e.g., created in advance by another user to intercept the sockets).
+#[cfg(unix)]
+fn private_dir(dir: PathBuf, uid: u32) -> orfail::Result<PathBuf> {
+    match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => {
+            return Err(orfail::Failure::new(format!(
+                "Failed to create directory {}: {e}",
+                dir.display()
+            )));
+        }
     }
+    let metadata = std::fs::symlink_metadata(&dir)
+        .or_fail_with(|e| format!("Failed to access directory {}: {e}", dir.display()))?;
+    (metadata.is_dir() && metadata.uid() == uid && metadata.mode() & 0o077 == 0).or_fail_with(
+        |()| {
+            format!(
+                "Refusing to use {}: it must be a directory owned by the current user and not accessible by others",
+                dir.display()
+            )
+        },
+    )?;
+    Ok(dir)
+}
+
+/// Fails if the given file (a socket or a registry entry) is not owned by the current user.
+#[cfg(unix)]
+fn check_owner(path: &Path) -> orfail::Result<()> {
+    let metadata = std::fs::metadata(path)
+        .or_fail_with(|e| format!("Failed to access {}: {e}", path.display()))?;
+    (metadata.uid() == current_uid()).or_fail_with(|()| {
+        format!(
+            "Refusing to connect via {}: it is owned by another user",
+            path.display()
+        )
+    })
+}
+
+/// Gets the real user ID of this process.
+#[cfg(unix)]
+fn current_uid() -> u32 {
+    // SAFETY: `getuid()` has no preconditions and always succeeds.
+    unsafe { libc::getuid() }
 }
 
 fn send(mut writer: impl Write, value: impl Serialize) -> orfail::Result<()> {
-    serde_json::to_writer(&mut writer, &value).or_fail()?;
-    writeln!(&mut writer).or_fail()?;
+    // Write a message at once to avoid the delay of Nagle's algorithm.
+    let mut message = serde_json::to_vec(&value).or_fail()?;
+    message.push(b'\n');
+    writer.write_all(&message).or_fail()?;
     writer.flush().or_fail()?;
     Ok(())
 }
 
-fn recv<T: for<'a> Deserialize<'a>>(mut reader: impl Read) -> orfail::Result<T> {
-    let value: T = serde_json::from_reader(&mut reader).or_fail()?;
+fn recv<T: for<'a> Deserialize<'a>>(mut reader: impl BufRead) -> orfail::Result<T> {
+    let mut line = String::new();
+    let size = reader.read_line(&mut line).or_fail()?;
+    (size > 0).or_fail_with(|()| "Connection closed by the editor".to_owned())?;
+    let value: T = serde_json::from_str(&line).or_fail()?;
     Ok(value)
 }
 
+/// Request line sent by an agent (`{"id":0,"request":...}`).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CanvasAgentRequestFrame {
+    /// ID chosen by the agent to associate the response with this request.
+    pub id: u64,
+    pub request: CanvasAgentRequest,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum CanvasAgentRequest {
     Command(CanvasCommand),
+
+    /// Applies the commands atomically (if any of them fails, none of them are applied).
+    Batch(Vec<CanvasCommand>),
+
     Query(CanvasQuery),
+
+    /// Subscribes to [`CanvasEvent`]s (the server should call [`CanvasAgentServer::subscribe()`]).
+    Subscribe,
+}
+
+/// Line sent by the server after the handshake.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CanvasAgentMessage {
+    Response(CanvasAgentResponse),
+    Event(CanvasEvent),
+}
+
+/// Response to a request (`{"id":0,"ok":...}` or `{"id":0,"error":{...}}`).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CanvasAgentResponse {
+    /// ID of the request (`None` if the request was too malformed to have an ID).
+    pub id: Option<u64>,
+
+    #[serde(flatten)]
+    pub result: CanvasAgentResult,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
+pub enum CanvasAgentResult {
+    /// Result value of the request (`null` for commands, a [`CanvasQueryValue`] for queries).
+    Ok(serde_json::Value),
+    Error(CanvasAgentError),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CanvasAgentError {
+    pub kind: CanvasAgentErrorKind,
+    pub message: String,
+}
+
+impl CanvasAgentError {
+    pub fn new(kind: CanvasAgentErrorKind, message: impl ToString) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for CanvasAgentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CanvasAgentError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CanvasAgentErrorKind {
+    /// The request line could not be parsed.
+    InvalidRequest,
+
+    /// A command failed (for batches, none of the commands were applied).
+    CommandFailed,
+
+    /// The client and the server don't share a protocol version (the connection is closed).
+    IncompatibleProtocol,
+
+    /// The editor failed to handle the request (e.g., reading the canvas file failed).
+    Internal,
+}
+
+/// Handshake response of the server (`{"ok":...}`, or `{"error":...}` if the client is rejected).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CanvasAgentServerInfo {
+    /// Negotiated protocol version.
+    pub protocol_version: u32,
+
+    /// Optional features supported by both the client and the server.
+    pub features: Vec<String>,
+
+    /// Canonical path of the canvas file (`None` if the server isn't registered to a file).
+    pub file: Option<PathBuf>,
+
+    /// Version of the canvas image as of the last [`CanvasAgentServer::publish()`].
+    pub version: Version,
+}
+
+impl CanvasAgentServerInfo {
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// First line sent by a client (`{"magic_number":"PATICA","min_protocol_version":1,...}`).
+#[derive(Debug, Serialize, Deserialize)]
 struct Handshake {
     magic_number: String,
+
+    /// Range of the protocol versions supported by the client (`0` if missing, i.e., a legacy client).
+    #[serde(default)]
+    min_protocol_version: u32,
+    #[serde(default)]
+    max_protocol_version: u32,
+
+    /// Optional features the client wants to use.
+    #[serde(default)]
+    features: Vec<String>,
+
+    /// Features the client can't work without (the client is rejected if the server doesn't support any of them).
+    #[serde(default)]
+    required_features: Vec<String>,
+
+    /// Session recorded with the image changes made by the client (see [`SESSION`][crate::SESSION]).
+    ///
+    /// If missing, the changes are recorded as made in the session of the editor.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session: Option<String>,
 }
 
 impl Handshake {
     fn new() -> Self {
         Self {
-            magic_number: "PATICA".to_string(),
+            magic_number: MAGIC_NUMBER.to_string(),
+            min_protocol_version: AGENT_PROTOCOL_VERSION,
+            max_protocol_version: AGENT_PROTOCOL_VERSION,
+            features: AGENT_FEATURES.iter().map(|f| f.to_string()).collect(),
+            required_features: Vec::new(),
+            session: None,
         }
     }
+
+    fn negotiate(
+        self,
+        server: &CanvasAgentServerInfo,
+    ) -> Result<CanvasAgentServerInfo, CanvasAgentError> {
+        let kind = CanvasAgentErrorKind::IncompatibleProtocol;
+        if self.magic_number != MAGIC_NUMBER {
+            return Err(CanvasAgentError::new(
+                kind,
+                format!(
+                    "Unexpected magic number {:?} (expected {MAGIC_NUMBER:?})",
+                    self.magic_number
+                ),
+            ));
+        }
+        if !(self.min_protocol_version..=self.max_protocol_version)
+            .contains(&server.protocol_version)
+        {
+            return Err(CanvasAgentError::new(
+                kind,
+                format!(
+                    "Unsupported protocol version (the client supports {}..={} but the editor supports {}); \
+                     use the same version of patica for both",
+                    self.min_protocol_version, self.max_protocol_version, server.protocol_version
+                ),
+            ));
+        }
+        let unsupported = self
+            .required_features
+            .iter()
+            .filter(|f| !server.features.contains(f))
+            .collect::<Vec<_>>();
+        if !unsupported.is_empty() {
+            return Err(CanvasAgentError::new(
+                kind,
+                format!(
+                    "The editor doesn't support the required features {unsupported:?}; \
+                     use the same version of patica for both"
+                ),
+            ));
+        }
+        Ok(CanvasAgentServerInfo {
+            features: server
+                .features
+                .iter()
+                .filter(|f| self.features.contains(f) || self.required_features.contains(f))
+                .cloned()
+                .collect(),
+            ..server.clone()
+        })
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-struct HandshakeResult {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Canvas file in the temporary directory (removed when dropped).
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("patica-test-{}-{name}.pati", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+
+        fn open(&self) -> CanvasFile {
+            CanvasFile::open(&self.0, true).expect("open")
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Runs `client` in another thread while serving the requests to `server`.
+    fn serve<T: Send + 'static>(
+        server: &mut CanvasAgentServer,
+        file: &mut CanvasFile,
+        client: impl FnOnce(CanvasAgentAddr) -> T + Send + 'static,
+    ) -> T {
+        let addr = server.addr().clone();
+        let handle = std::thread::spawn(move || client(addr));
+        while !handle.is_finished() {
+            for (client_id, frame) in server.poll_requests() {
+                server.handle_request(file, client_id, frame);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        handle.join().expect("client thread")
+    }
+
+    /// Connects to a TCP server without the client (for sending raw lines).
+    fn connect_raw(addr: &CanvasAgentAddr) -> (BufReader<TcpStream>, TcpStream) {
+        let CanvasAgentAddr::Port(port) = addr else {
+            panic!("unexpected address: {addr}");
+        };
+        let stream = TcpStream::connect(("127.0.0.1", *port)).expect("connect");
+        (BufReader::new(stream.try_clone().expect("clone")), stream)
+    }
+
+    const HANDSHAKE: &str =
+        r#"{"magic_number":"PATICA","min_protocol_version":1,"max_protocol_version":1}"#;
+
+    #[test]
+    fn responses_have_request_ids() {
+        let temp = TempFile::new("request-ids");
+        let mut file = temp.open();
+        let mut server = CanvasAgentServer::start().expect("start");
+        let lines = serve(&mut server, &mut file, |addr| {
+            let (reader, mut writer) = connect_raw(&addr);
+            let requests = [
+                HANDSHAKE,
+                r#"{"id":7,"request":{"query":"cursor"}}"#,
+                r#"{"id":3,"request":{"command":{"move":[1,0]}}}"#,
+                r#"{"id":5,"request":"unknown"}"#,
+                r#"{"id":1,"request":{"query":"cursor"}}"#,
+            ];
+            writer
+                .write_all(format!("{}\n", requests.join("\n")).as_bytes())
+                .expect("write");
+            reader.lines().take(5).collect::<Result<Vec<_>, _>>()
+        })
+        .expect("read");
+
+        // Invalid requests are answered as soon as they are read, so responses may be reordered,
+        // but the valid requests of a client are handled in order.
+        let responses = lines[1..]
+            .iter()
+            .map(|line| match serde_json::from_str(line).expect("parse") {
+                CanvasAgentMessage::Response(response) => (response.id, response.result),
+                message => panic!("unexpected message: {message:?}"),
+            })
+            .collect::<Vec<_>>();
+        let ok = |id, value| {
+            responses.iter().any(
+                |r| matches!(r, (Some(i), CanvasAgentResult::Ok(v)) if *i == id && *v == value),
+            )
+        };
+        assert!(ok(7, serde_json::json!({"cursor": [0, 0]})));
+        assert!(ok(3, serde_json::Value::Null));
+        assert!(ok(1, serde_json::json!({"cursor": [1, 0]})));
+        assert!(responses.iter().any(|r| matches!(
+            r,
+            (Some(5), CanvasAgentResult::Error(e)) if e.kind == CanvasAgentErrorKind::InvalidRequest
+        )));
+    }
+
+    #[test]
+    fn pipelined_responses_can_arrive_out_of_order() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).expect("bind");
+        let port = listener.local_addr().expect("addr").port();
+        let fake_server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("accept");
+            let mut reader = BufReader::new(stream.try_clone().expect("clone"));
+            let mut writer = stream;
+            let info = CanvasAgentServerInfo {
+                protocol_version: AGENT_PROTOCOL_VERSION,
+                features: Vec::new(),
+                file: None,
+                version: Version::default(),
+            };
+            let _: Handshake = recv(&mut reader).expect("handshake");
+            send(&mut writer, CanvasAgentResult::Ok(serde_json::json!(info))).expect("send");
+
+            let frames = (0..3)
+                .map(|_| recv::<CanvasAgentRequestFrame>(&mut reader).expect("request"))
+                .collect::<Vec<_>>();
+            let event = CanvasAgentMessage::Event(CanvasEvent::Cursor(Point::new(0, 0)));
+            send(&mut writer, event).expect("send");
+            for frame in frames.iter().rev() {
+                let response = CanvasAgentMessage::Response(CanvasAgentResponse {
+                    id: Some(frame.id),
+                    result: CanvasAgentResult::Ok(serde_json::json!(frame.id)),
+                });
+                send(&mut writer, response).expect("send");
+            }
+        });
+
+        let mut agent = CanvasAgent::connect(&CanvasAgentAddr::Port(port)).expect("connect");
+        let requests = [
+            CanvasQuery::Cursor,
+            CanvasQuery::Camera,
+            CanvasQuery::Version,
+        ]
+        .into_iter()
+        .map(CanvasAgentRequest::Query)
+        .collect();
+        let results = agent.pipeline(requests).expect("pipeline");
+        fake_server.join().expect("fake server");
+        let values = results
+            .into_iter()
+            .map(|r| r.expect("result"))
+            .collect::<Vec<_>>();
+        assert_eq!(values, [0, 1, 2].map(|id| serde_json::json!(id)));
+    }
+
+    #[test]
+    fn failed_batch_leaves_canvas_unchanged() {
+        use crate::command::SliceCommand;
+
+        let temp = TempFile::new("batch");
+        let mut file = temp.open();
+        let version = file.canvas().image().version();
+        let mut server = CanvasAgentServer::start().expect("start");
+        let (result, cursor) = serve(&mut server, &mut file, |addr| {
+            let mut agent = CanvasAgent::connect(&addr).expect("connect");
+            let result = agent
+                .request(CanvasAgentRequest::Batch(vec![
+                    CanvasCommand::Slice(SliceCommand::New("a".to_owned())),
+                    CanvasCommand::Move(Point::new(1, 0)),
+                    CanvasCommand::Slice(SliceCommand::Select("missing".to_owned())),
+                ]))
+                .expect("request");
+            (result, agent.query(CanvasQuery::Cursor).expect("query"))
+        });
+
+        let error = result.expect_err("batch should fail");
+        assert_eq!(error.kind, CanvasAgentErrorKind::CommandFailed);
+        assert!(matches!(cursor, CanvasQueryValue::Cursor(p) if p == Point::new(0, 0)));
+        assert_eq!(file.canvas().image().version(), version);
+        assert!(file.canvas().image().slices().is_empty());
+        assert_eq!(file.canvas().mode(), CanvasMode::Normal);
+    }
+
+    #[test]
+    fn changes_are_recorded_in_the_session_of_the_client() {
+        use crate::SESSION;
+        use pati::{Color, ImageCommand};
+
+        let temp = TempFile::new("session");
+        let mut file = temp.open();
+        file.set_session("editor".to_owned());
+        let mut server = CanvasAgentServer::start().expect("start");
+        serve(&mut server, &mut file, |addr| {
+            let draw = |x| {
+                CanvasCommand::Image(ImageCommand::draw_pixels(
+                    [(Point::new(x, 0), Color::rgb(255, 0, 0))].into_iter(),
+                ))
+            };
+            let mut agent =
+                CanvasAgent::connect_in_session(&addr, Some("agent".to_owned())).expect("connect");
+            agent.command(draw(0)).expect("command");
+            let mut legacy = CanvasAgent::connect(&addr).expect("connect");
+            legacy.command(draw(1)).expect("command");
+        });
+
+        let image = file.canvas().image();
+        let sessions = [2, 4].map(|v| {
+            let image = image.restore(Version::new(v)).expect("restore");
+            SESSION.get(image.metadata()).expect("get")
+        });
+        assert_eq!(sessions, ["agent", "editor"].map(|s| Some(s.to_owned())));
+    }
+
+    #[test]
+    fn slow_subscribers_receive_lagged_events() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).expect("bind");
+        let subscriber = TcpStream::connect(listener.local_addr().expect("addr")).expect("connect");
+        let (stream, _) = listener.accept().expect("accept");
+        let mut client = ClientState::new(Stream::Tcp(stream)).expect("client");
+
+        // Fill the socket buffers and then the pending messages without reading them.
+        let event = CanvasEvent::Cursor(Point::new(1, 2));
+        let mut sent = 0;
+        while client.pending_bytes() <= MAX_PENDING_BYTES {
+            client.enqueue_event(&event).expect("enqueue");
+            sent += 1;
+        }
+
+        // The next event replaces the unsent events with a `Lagged` event.
+        client.enqueue_event(&event).expect("enqueue");
+        sent += 1;
+        assert!(client.pending_bytes() < MAX_PENDING_BYTES);
+
+        // The remaining messages are sent before closing the stream.
+        drop(client);
+        let (mut received, mut dropped) = (0, 0);
+        for line in BufReader::new(subscriber).lines() {
+            match serde_json::from_str(&line.expect("read")).expect("parse") {
+                CanvasAgentMessage::Event(CanvasEvent::Lagged { dropped: n }) => dropped += n,
+                CanvasAgentMessage::Event(CanvasEvent::Cursor(_)) => received += 1,
+                message => panic!("unexpected message: {message:?}"),
+            }
+        }
+        assert!(dropped > 0);
+        assert_eq!(received + dropped, sent);
+    }
+
+    #[test]
+    fn lagged_events_keep_pending_responses() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).expect("bind");
+        let subscriber = TcpStream::connect(listener.local_addr().expect("addr")).expect("connect");
+        let (stream, _) = listener.accept().expect("accept");
+        let mut client = ClientState::new(Stream::Tcp(stream)).expect("client");
+
+        let event = CanvasEvent::Cursor(Point::new(1, 2));
+        while client.pending_bytes() <= MAX_PENDING_BYTES / 2 {
+            client.enqueue_event(&event).expect("enqueue");
+        }
+        client
+            .respond(Some(1), Ok(serde_json::Value::Null))
+            .expect("respond");
+        while client.pending_bytes() <= MAX_PENDING_BYTES {
+            client.enqueue_event(&event).expect("enqueue");
+        }
+        client.enqueue_event(&event).expect("enqueue");
+        drop(client);
+
+        let messages = BufReader::new(subscriber)
+            .lines()
+            .map(|line| serde_json::from_str(&line.expect("read")).expect("parse"))
+            .collect::<Vec<CanvasAgentMessage>>();
+        let response = messages
+            .iter()
+            .position(|m| matches!(m, CanvasAgentMessage::Response(r) if r.id == Some(1)))
+            .expect("the response should not be dropped");
+        let lagged = messages
+            .iter()
+            .position(|m| matches!(m, CanvasAgentMessage::Event(CanvasEvent::Lagged { .. })))
+            .expect("lagged");
+        assert!(response < lagged);
+        assert!(matches!(
+            messages.last(),
+            Some(CanvasAgentMessage::Event(CanvasEvent::Cursor(_)))
+        ));
+    }
+
+    #[test]
+    fn wait_request_blocks_until_a_request_arrives() {
+        let mut server = CanvasAgentServer::start().expect("start");
+        let addr = server.addr().clone();
+        let client = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            let mut agent = CanvasAgent::connect(&addr).expect("connect");
+            agent.query(CanvasQuery::Cursor).expect("query")
+        });
+
+        let temp = TempFile::new("wait");
+        let mut file = temp.open();
+        let (client_id, frame) = server.wait_request().expect("wait");
+        assert!(matches!(
+            frame.request,
+            CanvasAgentRequest::Query(CanvasQuery::Cursor)
+        ));
+        server.handle_request(&mut file, client_id, frame);
+        let value = client.join().expect("client");
+        assert!(matches!(value, CanvasQueryValue::Cursor(p) if p == Point::new(0, 0)));
+    }
+
+    #[test]
+    fn negotiate_works() {
+        let server = CanvasAgentServer::start().expect("start").info();
+        let info = Handshake::new().negotiate(&server).expect("negotiate");
+        assert_eq!(info.protocol_version, AGENT_PROTOCOL_VERSION);
+        assert_eq!(info.features, AGENT_FEATURES);
+
+        let handshake = Handshake {
+            features: vec!["batch".to_owned(), "unknown".to_owned()],
+            required_features: vec!["subscribe".to_owned()],
+            ..Handshake::new()
+        };
+        let info = handshake.negotiate(&server).expect("negotiate");
+        assert_eq!(info.features, ["batch", "subscribe"]);
+    }
+
+    #[test]
+    fn negotiate_rejects_incompatible_clients() {
+        let server = CanvasAgentServer::start().expect("start").info();
+        let handshakes = [
+            Handshake {
+                magic_number: "PATI".to_owned(),
+                ..Handshake::new()
+            },
+            Handshake {
+                min_protocol_version: AGENT_PROTOCOL_VERSION + 1,
+                max_protocol_version: AGENT_PROTOCOL_VERSION + 1,
+                ..Handshake::new()
+            },
+            // Legacy clients don't send the protocol versions.
+            serde_json::from_str(r#"{"magic_number":"PATICA"}"#).expect("parse"),
+            Handshake {
+                required_features: vec!["batch".to_owned(), "unknown".to_owned()],
+                ..Handshake::new()
+            },
+        ];
+        for handshake in handshakes {
+            let error = handshake
+                .negotiate(&server)
+                .expect_err("should be rejected");
+            assert_eq!(error.kind, CanvasAgentErrorKind::IncompatibleProtocol);
+        }
+    }
+
+    #[test]
+    fn incompatible_clients_are_disconnected() {
+        let temp = TempFile::new("incompatible");
+        let mut file = temp.open();
+        let mut server = CanvasAgentServer::start().expect("start");
+        for handshake in [
+            r#"{"magic_number":"PATICA","min_protocol_version":2,"max_protocol_version":3}"#,
+            r#"{"magic_number":"PATICA","min_protocol_version":1,"max_protocol_version":1,"required_features":["unknown"]}"#,
+        ] {
+            let lines = serve(&mut server, &mut file, move |addr| {
+                let (reader, mut writer) = connect_raw(&addr);
+                writer
+                    .write_all(format!("{handshake}\n").as_bytes())
+                    .expect("write");
+                reader.lines().collect::<Result<Vec<_>, _>>()
+            })
+            .expect("read");
+
+            // The error response is followed by EOF.
+            assert_eq!(lines.len(), 1);
+            let result = serde_json::from_str(&lines[0]).expect("parse");
+            assert!(matches!(
+                result,
+                CanvasAgentResult::Error(e) if e.kind == CanvasAgentErrorKind::IncompatibleProtocol
+            ));
+        }
+        assert!(server.clients.is_empty());
+    }
+
+    #[test]
+    fn discover_registered_port() {
+        let temp = TempFile::new("discover-port");
+        let mut file = temp.open();
+        let mut server = CanvasAgentServer::start().expect("start");
+        server.register(&temp.0).expect("register");
+
+        let addr = CanvasAgent::discover(&temp.0).expect("discover");
+        assert_eq!(&addr, server.addr());
+        let info = serve(&mut server, &mut file, |addr| {
+            CanvasAgent::connect(&addr)
+                .expect("connect")
+                .server_info()
+                .clone()
+        });
+        assert_eq!(
+            info.file,
+            Some(temp.0.canonicalize().expect("canonicalize"))
+        );
+
+        drop(server);
+        assert!(CanvasAgent::discover(&temp.0).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn discover_unix_socket() {
+        let temp = TempFile::new("discover-socket");
+        let mut file = temp.open();
+        let mut server = CanvasAgentServer::start_unix(&temp.0).expect("start");
+
+        let addr = CanvasAgent::discover(&temp.0).expect("discover");
+        assert_eq!(&addr, server.addr());
+        let CanvasAgentAddr::Socket(socket_path) = &addr else {
+            panic!("unexpected address: {addr}");
+        };
+        let dir = socket_path.parent().expect("parent");
+        let mode = std::fs::metadata(dir).expect("metadata").mode();
+        assert_eq!(mode & 0o077, 0);
+
+        let info = serve(&mut server, &mut file, |addr| {
+            CanvasAgent::connect(&addr)
+                .expect("connect")
+                .server_info()
+                .clone()
+        });
+        assert_eq!(
+            info.file,
+            Some(temp.0.canonicalize().expect("canonicalize"))
+        );
+
+        // Another server can't take over the socket.
+        assert!(CanvasAgentServer::start_unix(&temp.0).is_err());
+
+        drop(server);
+        assert!(CanvasAgent::discover(&temp.0).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_dir_rejects_shared_dirs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("patica-test-{}-dir", std::process::id()));
+        let uid = current_uid();
+        assert!(private_dir(dir.clone(), uid).is_ok());
+
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).expect("chmod");
+        assert!(private_dir(dir.clone(), uid).is_err());
+
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).expect("chmod");
+        assert!(private_dir(dir.clone(), uid.wrapping_add(1)).is_err());
+
+        std::fs::remove_dir(&dir).expect("remove");
+    }
+}
@@ -1,33 +1,63 @@
 use crate::{command::CanvasCommand, Canvas};
 use orfail::OrFail;
-use pati::{ImageCommandReader, ImageCommandWriter, Version};
+use pati::{FileHeader, ImageCommandReader, ImageCommandWriter, Version};
 use std::{
-    fs::File,
-    io::{BufReader, BufWriter},
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, ErrorKind},
+    num::NonZeroU32,
     path::Path,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+const CHECKSUM_INTERVAL: Option<NonZeroU32> = NonZeroU32::new(100);
+
 #[derive(Debug)]
 pub struct CanvasFile {
     canvas: Canvas,
     reader: ImageCommandReader<BufReader<File>>,
     writer: ImageCommandWriter<BufWriter<File>>,
     last_written_version: Version,
+
+    /// Session of the commands applied through this file (see [`CanvasFile::set_session()`]).
+    session: Option<String>,
 }
 
 impl CanvasFile {
     pub fn open<P: AsRef<Path>>(path: P, create: bool) -> orfail::Result<Self> {
-        let file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(create)
-            .open(&path)
-            .or_fail_with(|e| format!("Failed to open file {}: {e}", path.as_ref().display()))?;
+        let path = path.as_ref();
+        let open_failed = |e| format!("Failed to open file {}: {e}", path.display());
+
+        // Only the process that creates the file writes the header, so concurrent openers don't race on it.
+        // The file is opened in append mode so that writes never overwrite the commands of other writers.
+        let mut options = OpenOptions::new();
+        options.read(true).append(true);
+        let (file, created) = match options.clone().create_new(create).open(path) {
+            Ok(file) => (file, create),
+            Err(e) if create && e.kind() == ErrorKind::AlreadyExists => {
+                (options.open(path).or_fail_with(open_failed)?, false)
+            }
+            Err(e) => return Err(orfail::Failure::new(open_failed(e))),
+        };
+        let mut writer = ImageCommandWriter::new(BufWriter::new(file.try_clone().or_fail()?));
+        writer.set_checksum_interval(CHECKSUM_INTERVAL);
+        if created {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let creator = concat!("paticanvas/", env!("CARGO_PKG_VERSION"));
+            writer
+                .write_header(&FileHeader::new(creator, now))
+                .or_fail()?;
+        }
+        let mut reader = ImageCommandReader::new(BufReader::new(file));
+        reader.set_recover_checksum_mismatches(true);
         let mut this = Self {
             canvas: Canvas::new(),
-            reader: ImageCommandReader::new(BufReader::new(file.try_clone().or_fail()?)),
-            writer: ImageCommandWriter::new(BufWriter::new(file)),
+            reader,
+            writer,
             last_written_version: Version::default(),
+            session: None,
         };
         this.sync().or_fail()?;
         Ok(this)
@@ -37,6 +67,14 @@ impl CanvasFile {
         &self.canvas
     }
 
+    /// Gets the number of the checksum mismatches found while reading the file.
+    ///
+    /// Mismatches don't fail the reads because the commands of concurrent writers
+    /// can be interleaved between the sync and the write of a writer.
+    pub fn checksum_mismatches(&self) -> u32 {
+        self.reader.checksum_mismatches()
+    }
+
     pub fn sync(&mut self) -> orfail::Result<()> {
         while let Some(command) = self.reader.read_command().or_fail()? {
             self.canvas
@@ -47,9 +85,49 @@ impl CanvasFile {
         Ok(())
     }
 
+    /// Starts an editing session (see [`SESSION`][crate::SESSION]).
+    ///
+    /// The session is recorded lazily, along with the first command of the session that changes the image,
+    /// so just opening a file doesn't modify it.
+    pub fn set_session(&mut self, session_id: String) {
+        self.session = Some(session_id);
+    }
+
     pub fn command(&mut self, command: &CanvasCommand) -> orfail::Result<()> {
         self.sync().or_fail()?;
-        self.canvas.command(command).or_fail()?;
+        let session = self.session.clone();
+        self.canvas.set_session(session);
+        let result = self.canvas.command(command);
+        self.canvas.set_session(None);
+        result.or_fail()?;
+        self.write_applied_commands().or_fail()
+    }
+
+    /// Applies the given commands atomically.
+    ///
+    /// If any of the commands fails, the canvas is left unchanged and nothing is written to the file.
+    pub fn commands(&mut self, commands: &[CanvasCommand]) -> orfail::Result<()> {
+        let session = self.session.clone();
+        self.commands_in_session(session, commands).or_fail()
+    }
+
+    /// Like [`CanvasFile::commands()`] but records the image changes as made in the given session
+    /// (e.g., of an agent client) instead of the session of this file.
+    pub fn commands_in_session(
+        &mut self,
+        session: Option<String>,
+        commands: &[CanvasCommand],
+    ) -> orfail::Result<()> {
+        self.sync().or_fail()?;
+        self.canvas.set_session(session);
+        let result = self.canvas.commands(commands);
+        self.canvas.set_session(None);
+        result.or_fail()?;
+        self.write_applied_commands().or_fail()
+    }
+
+    fn write_applied_commands(&mut self) -> orfail::Result<()> {
+        self.writer.continue_from(&self.reader);
         for command in self
             .canvas
             .image()
@@ -57,7 +135,177 @@ impl CanvasFile {
         {
             self.writer.write_command(command).or_fail()?;
         }
+        self.reader.continue_from(&self.writer);
         self.last_written_version = self.canvas.image().version();
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SESSION;
+    use pati::{Color, ImageCommand, Point};
+    use std::path::PathBuf;
+
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "paticanvas-file-test-{}-{name}.jsonl",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn draw(x: i16) -> CanvasCommand {
+        CanvasCommand::Image(ImageCommand::draw_pixels(
+            [(Point::new(x, 0), Color::rgb(255, 0, 0))].into_iter(),
+        ))
+    }
+
+    #[test]
+    fn checksums_cover_single_and_batched_writes() {
+        let temp = TempFile::new("checksums");
+        let mut file = CanvasFile::open(&temp.0, true).expect("open");
+        for x in 0..10 {
+            file.command(&draw(x)).expect("command");
+        }
+        let batch = (10..110).map(draw).collect::<Vec<_>>();
+        file.commands(&batch).expect("commands");
+        for x in 110..250 {
+            file.command(&draw(x)).expect("command");
+        }
+        let version = file.canvas().image().version();
+        drop(file);
+
+        let contents = std::fs::read_to_string(&temp.0).expect("read");
+        assert_eq!(contents.matches("\"checksum\"").count(), 2);
+
+        let file = CanvasFile::open(&temp.0, false).expect("reopen");
+        assert_eq!(file.canvas().image().version(), version);
+        assert_eq!(file.canvas().image().pixels().len(), 250);
+    }
+
+    #[test]
+    fn session_is_recorded_with_the_first_image_change() {
+        let temp = TempFile::new("session");
+        let mut file = CanvasFile::open(&temp.0, true).expect("open");
+        file.set_session("alice".to_owned());
+        file.command(&CanvasCommand::Move(Point::new(1, 0)))
+            .expect("command");
+        assert_eq!(file.canvas().image().version(), Version::default());
+
+        file.command(&draw(0)).expect("command");
+        file.command(&draw(1)).expect("command");
+        let image = file.canvas().image();
+        assert_eq!(image.version(), Version::default() + 3);
+        let session = image.restore(Version::default() + 2).expect("restore");
+        assert_eq!(
+            SESSION.get(session.metadata()).expect("get"),
+            Some("alice".to_owned())
+        );
+        drop(file);
+
+        // Opening a file doesn't modify it.
+        let contents = std::fs::read(&temp.0).expect("read");
+        let mut file = CanvasFile::open(&temp.0, false).expect("reopen");
+        file.set_session("bob".to_owned());
+        file.command(&CanvasCommand::Move(Point::new(1, 0)))
+            .expect("command");
+        drop(file);
+        assert_eq!(std::fs::read(&temp.0).expect("read"), contents);
+        let file = CanvasFile::open(&temp.0, false).expect("reopen");
+        assert_eq!(file.canvas().image().version(), Version::default() + 3);
+    }
+
+    #[test]
+    fn sessions_are_recorded_per_author() {
+        let temp = TempFile::new("authors");
+        let mut file = CanvasFile::open(&temp.0, true).expect("open");
+        file.set_session("alice".to_owned());
+        file.command(&draw(0)).expect("command");
+        file.commands_in_session(Some("agent".to_owned()), &[draw(1), draw(2)])
+            .expect("commands");
+        file.command(&draw(3)).expect("command");
+
+        let image = file.canvas().image();
+        let sessions = (1..=image.version().get())
+            .map(|v| {
+                let image = image.restore(Version::new(v)).expect("restore");
+                SESSION.get(image.metadata()).expect("get")
+            })
+            .collect::<Vec<_>>();
+        let expected = [
+            "alice", "alice", "agent", "agent", "agent", "alice", "alice",
+        ];
+        assert_eq!(sessions, expected.map(|s| Some(s.to_owned())));
+    }
+
+    #[test]
+    fn header_is_written_only_by_the_creator() {
+        let temp = TempFile::new("header");
+        let mut a = CanvasFile::open(&temp.0, true).expect("open");
+        let mut b = CanvasFile::open(&temp.0, true).expect("open");
+        a.command(&draw(0)).expect("command");
+        b.command(&draw(1)).expect("command");
+        drop((a, b));
+
+        let contents = std::fs::read_to_string(&temp.0).expect("read");
+        assert_eq!(contents.matches("\"format_version\"").count(), 1);
+        let file = CanvasFile::open(&temp.0, false).expect("reopen");
+        assert_eq!(file.canvas().image().pixels().len(), 2);
+    }
+
+    #[test]
+    fn checksums_cover_commands_of_other_writers() {
+        let temp = TempFile::new("writers");
+        let mut a = CanvasFile::open(&temp.0, true).expect("open");
+        let mut b = CanvasFile::open(&temp.0, false).expect("open");
+        for x in 0..150 {
+            if x % 3 == 0 {
+                b.command(&draw(x)).expect("command");
+            } else {
+                a.command(&draw(x)).expect("command");
+            }
+        }
+        a.sync().expect("sync");
+        b.sync().expect("sync");
+        assert_eq!(a.canvas().image().pixels().len(), 150);
+        assert_eq!(b.canvas().image().pixels().len(), 150);
+        drop((a, b));
+
+        let file = CanvasFile::open(&temp.0, false).expect("reopen");
+        assert_eq!(file.canvas().image().pixels().len(), 150);
+    }
+
+    #[test]
+    fn checksum_mismatches_are_not_fatal() {
+        let temp = TempFile::new("mismatch");
+        let mut file = CanvasFile::open(&temp.0, true).expect("open");
+        for x in 0..100 {
+            file.command(&draw(x)).expect("command");
+        }
+        drop(file);
+
+        // Emulate a command of another writer interleaved before the checksum record.
+        let contents = std::fs::read_to_string(&temp.0).expect("read");
+        let (commands, checksum) = contents.split_at(contents.find("{\"checksum\"").expect("find"));
+        let foreign = serde_json::to_string(&ImageCommand::anchor("a", None)).expect("json");
+        std::fs::write(&temp.0, format!("{commands}{foreign}\n{checksum}")).expect("write");
+
+        let mut file = CanvasFile::open(&temp.0, false).expect("reopen");
+        assert_eq!(file.checksum_mismatches(), 1);
+        assert_eq!(file.canvas().image().pixels().len(), 100);
+        file.command(&draw(100)).expect("command");
+    }
+}
@@ -1,5 +1,6 @@
 use pati::{ImageCommand, Point};
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -8,4 +9,52 @@ pub enum CanvasCommand {
     Scale(i8),
     Quit,
     Image(ImageCommand),
+    Slice(SliceCommand),
+    History(HistoryCommand),
+    Float(FloatCommand),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SliceCommand {
+    /// Creates a 1x1 slice at the cursor and selects it.
+    New(String),
+    Select(String),
+    Deselect,
+    Move(Point),
+    Resize(Point),
+    Remove,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryCommand {
+    /// Moves the viewed version by the given delta (entering the history mode if needed).
+    Scrub(i32),
+
+    /// Restores the pixels at the viewed version and leaves the history mode.
+    Restore,
+
+    /// Leaves the history mode without changing the image.
+    Exit,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FloatCommand {
+    /// Loads a PNG or BMP file into the floating pixels placed at the cursor.
+    Import {
+        path: PathBuf,
+
+        /// If `true`, the image is downsampled by the detected pixel scale.
+        #[serde(default)]
+        downsample: bool,
+    },
+    Move(Point),
+
+    /// Draws the floating pixels onto the image.
+    Commit,
+
+    /// Discards the floating pixels.
+    Cancel,
 }
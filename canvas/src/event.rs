@@ -0,0 +1,24 @@
+use crate::query::CanvasMode;
+use pati::{ImageCommand, Point, Version};
+use serde::{Deserialize, Serialize};
+
+/// Change notification sent to subscribed agents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CanvasEvent {
+    /// An image command has been applied (`version` is the version after the command is applied).
+    Image {
+        version: Version,
+        command: ImageCommand,
+    },
+    Cursor(Point),
+    Camera(Point),
+    Mode(CanvasMode),
+
+    /// Some events have been dropped because the agent didn't read them fast enough.
+    ///
+    /// The agent should re-query the state it mirrors.
+    Lagged {
+        dropped: usize,
+    },
+}
@@ -0,0 +1,743 @@
+use orfail::OrFail;
+use pati::{Color, ImageCommand, Point};
+use std::{io::Read, num::NonZeroU32, path::Path};
+
+/// Raster image decoded from a PNG or BMP file.
+#[derive(Debug, Clone)]
+pub struct ImportedImage {
+    width: u32,
+    height: u32,
+    pixels: Vec<Color>,
+}
+
+impl ImportedImage {
+    /// Loads a PNG or BMP file (detected by the file signature).
+    pub fn load<P: AsRef<Path>>(path: P) -> orfail::Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)
+            .or_fail_with(|e| format!("Failed to read file {}: {e}", path.display()))?;
+        if bytes.starts_with(b"\x89PNG") {
+            Self::decode_png(&bytes[..]).or_fail()
+        } else if bytes.starts_with(b"BM") {
+            Self::decode_bmp(&bytes).or_fail()
+        } else {
+            Err(orfail::Failure::new(format!(
+                "Unsupported image format: {}",
+                path.display()
+            )))
+        }
+    }
+
+    pub fn decode_png<R: Read>(reader: R) -> orfail::Result<Self> {
+        let mut decoder = png::Decoder::new(reader);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().or_fail()?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).or_fail()?;
+        let bytes = &buf[..info.buffer_size()];
+        let pixels = match info.color_type {
+            png::ColorType::Rgba => bytes
+                .chunks_exact(4)
+                .map(|c| Color::rgba(c[0], c[1], c[2], c[3]))
+                .collect(),
+            png::ColorType::Rgb => bytes
+                .chunks_exact(3)
+                .map(|c| Color::rgb(c[0], c[1], c[2]))
+                .collect(),
+            png::ColorType::GrayscaleAlpha => bytes
+                .chunks_exact(2)
+                .map(|c| Color::rgba(c[0], c[0], c[0], c[1]))
+                .collect(),
+            png::ColorType::Grayscale => bytes.iter().map(|&v| Color::rgb(v, v, v)).collect(),
+            png::ColorType::Indexed => {
+                return Err(orfail::Failure::new("Unexpected indexed PNG output"));
+            }
+        };
+        Ok(Self {
+            width: info.width,
+            height: info.height,
+            pixels,
+        })
+    }
+
+    /// Decodes an uncompressed 8, 24 or 32 bits-per-pixel BMP image.
+    ///
+    /// 32 bits-per-pixel images may have channel masks (`BI_BITFIELDS` compression).
+    pub fn decode_bmp(bytes: &[u8]) -> orfail::Result<Self> {
+        let u16_at = |i: usize| {
+            bytes
+                .get(i..i + 2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        };
+        let u32_at = |i: usize| {
+            bytes
+                .get(i..i + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        let truncated = || "Truncated BMP file".to_owned();
+
+        let data_offset = u32_at(10).or_fail_with(|()| truncated())? as usize;
+        let header_size = u32_at(14).or_fail_with(|()| truncated())? as usize;
+        let width = u32_at(18).or_fail_with(|()| truncated())? as i32;
+        let height = u32_at(22).or_fail_with(|()| truncated())? as i32;
+        let bpp = u16_at(28).or_fail_with(|()| truncated())?;
+        let compression = u32_at(30).or_fail_with(|()| truncated())?;
+        (width > 0 && height != 0)
+            .or_fail_with(|()| format!("Invalid BMP size: {width}x{height}"))?;
+        matches!((bpp, compression), (8, 0) | (24, 0) | (32, 0) | (32, 3)).or_fail_with(|()| {
+            format!("Unsupported BMP format: bpp={bpp}, compression={compression}")
+        })?;
+
+        let palette = if bpp == 8 {
+            let colors = match u32_at(46).or_fail_with(|()| truncated())? {
+                0 => 256,
+                n => n as usize,
+            };
+            let start = 14 + header_size;
+            let entries = bytes
+                .get(start..start + colors * 4)
+                .or_fail_with(|()| truncated())?;
+            entries
+                .chunks_exact(4)
+                .map(|c| Color::rgb(c[2], c[1], c[0]))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        // The red, green, blue and alpha masks of 32 bits-per-pixel images.
+        // They follow a `BITMAPINFOHEADER` (or are part of the larger headers),
+        // and only the `BITMAPV3INFOHEADER` or later headers have the alpha mask.
+        let masks = if compression == 3 {
+            let mask = |i: usize| u32_at(54 + i * 4).or_fail_with(|()| truncated());
+            let alpha = if header_size >= 56 { mask(3)? } else { 0 };
+            [mask(0)?, mask(1)?, mask(2)?, alpha]
+        } else {
+            [0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0xff00_0000]
+        };
+
+        let width = width as u32;
+        let top_down = height < 0;
+        let height = height.unsigned_abs();
+        let bytes_per_pixel = usize::from(bpp / 8);
+        let row_len = width as usize * bytes_per_pixel;
+        let stride = row_len.div_ceil(4) * 4;
+
+        // Check the size before allocating the pixels (the last row may lack its padding).
+        let data_end = stride
+            .checked_mul(height as usize - 1)
+            .and_then(|n| n.checked_add(data_offset))
+            .and_then(|n| n.checked_add(row_len));
+        data_end
+            .is_some_and(|end| end <= bytes.len())
+            .or_fail_with(|()| truncated())?;
+
+        let mut pixels = Vec::with_capacity(width as usize * height as usize);
+        for y in 0..height as usize {
+            let row = if top_down { y } else { height as usize - 1 - y };
+            let start = data_offset + row * stride;
+            let row = bytes
+                .get(start..start + row_len)
+                .or_fail_with(|()| truncated())?;
+            for c in row.chunks_exact(bytes_per_pixel) {
+                let color = match bpp {
+                    8 => palette
+                        .get(usize::from(c[0]))
+                        .copied()
+                        .or_fail_with(|()| format!("Invalid BMP palette index: {}", c[0]))?,
+                    24 => Color::rgb(c[2], c[1], c[0]),
+                    _ => {
+                        let v = u32::from_le_bytes([c[0], c[1], c[2], c[3]]);
+                        let [r, g, b, a] = masks.map(|mask| channel(v, mask));
+                        Color::rgba(r, g, b, if masks[3] == 0 { 255 } else { a })
+                    }
+                };
+                pixels.push(color);
+            }
+        }
+        if bpp == 32 && compression == 0 && pixels.iter().all(|c| c.a == 0) {
+            // The alpha channel is unused.
+            for c in &mut pixels {
+                c.a = 255;
+            }
+        }
+        Ok(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Detects the size of a logical pixel of an upscaled image.
+    ///
+    /// The result is the greatest common divisor of the lengths of all same-color runs in rows and columns.
+    pub fn pixel_scale(&self) -> NonZeroU32 {
+        let mut scale = gcd(self.width, self.height);
+        let width = self.width as usize;
+        for y in 0..self.height as usize {
+            let row = &self.pixels[y * width..][..width];
+            scale = run_lengths(row.iter()).fold(scale, gcd);
+        }
+        for x in 0..width {
+            let column = self.pixels[x..].iter().step_by(width);
+            scale = run_lengths(column).fold(scale, gcd);
+        }
+        NonZeroU32::new(scale).unwrap_or(NonZeroU32::MIN)
+    }
+
+    /// Shrinks this image by taking the top-left pixel of each `scale` x `scale` block.
+    pub fn downsample(&self, scale: NonZeroU32) -> Self {
+        let scale = scale.get();
+        let width = self.width / scale;
+        let height = self.height / scale;
+        let pixels = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| self.pixels[(y * scale * self.width + x * scale) as usize])
+            .collect();
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// Resizes this image to the given size by averaging the source pixels covered by each pixel (alpha-weighted).
+    ///
+    /// Fails if the number of the pixels of the resized image doesn't fit in `u32`.
+    pub fn resize(&self, width: NonZeroU32, height: NonZeroU32) -> orfail::Result<Self> {
+        let (width, height) = (width.get(), height.get());
+        let len = width
+            .checked_mul(height)
+            .or_fail_with(|()| format!("Too large image size: {width}x{height}"))?;
+        // The source coordinate of the `i`-th of `n` destination pixels (`n >= i`, so it is within `len`).
+        let source =
+            |i: u32, len: u32, n: u32| (u64::from(i) * u64::from(len) / u64::from(n)) as u32;
+        let mut pixels = Vec::with_capacity(len as usize);
+        for y in 0..height {
+            let y0 = source(y, self.height, height);
+            let y1 = source(y + 1, self.height, height).max(y0 + 1);
+            for x in 0..width {
+                let x0 = source(x, self.width, width);
+                let x1 = source(x + 1, self.width, width).max(x0 + 1);
+                let mut sum = [0u64; 4];
+                let mut count = 0u64;
+                for sy in y0..y1.min(self.height) {
+                    for sx in x0..x1.min(self.width) {
+                        let c = self.pixels[sy as usize * self.width as usize + sx as usize];
+                        let a = u64::from(c.a);
+                        sum[0] += u64::from(c.r) * a;
+                        sum[1] += u64::from(c.g) * a;
+                        sum[2] += u64::from(c.b) * a;
+                        sum[3] += a;
+                        count += 1;
+                    }
+                }
+                let color = match sum[3] {
+                    0 => Color::rgba(0, 0, 0, 0),
+                    a => Color::rgba(
+                        (sum[0] / a) as u8,
+                        (sum[1] / a) as u8,
+                        (sum[2] / a) as u8,
+                        (a / count.max(1)) as u8,
+                    ),
+                };
+                pixels.push(color);
+            }
+        }
+        Ok(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    /// Maps the colors of this image onto the given palette.
+    ///
+    /// The nearest palette color is chosen by the CIELAB (CIE76) distance.
+    /// Pixels whose alpha is less than 128 become fully transparent, and the others become opaque.
+    pub fn quantize(&mut self, palette: &[Color], dither: Dither) {
+        quantize(&mut self.pixels, self.width as usize, palette, dither);
+    }
+
+    /// Gets an iterator over the non fully transparent pixels placed at the given offset.
+    ///
+    /// Fails if some of the pixels are out of the coordinate range.
+    pub fn pixels(
+        &self,
+        offset: Point,
+    ) -> orfail::Result<impl '_ + Iterator<Item = (Point, Color)>> {
+        if !self.pixels.is_empty() {
+            let max = |offset: i16, len: u32| {
+                let v = i64::from(offset) + i64::from(len) - 1;
+                i16::try_from(v).or_fail_with(|_| {
+                    format!("Coordinate {v} is out of range (the image is too large)")
+                })
+            };
+            max(offset.x, self.width).or_fail()?;
+            max(offset.y, self.height).or_fail()?;
+        }
+
+        // As the bottom-right pixel fits in the coordinate range, so do all the pixels.
+        let width = self.width as usize;
+        Ok(self
+            .pixels
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.a != 0)
+            .map(move |(i, &c)| {
+                let point = Point::new((i % width) as i16, (i / width) as i16);
+                (offset + point, c)
+            }))
+    }
+
+    /// Makes a command to draw the pixels of this image at the given offset.
+    pub fn to_command(&self, offset: Point) -> orfail::Result<ImageCommand> {
+        Ok(ImageCommand::draw_pixels(self.pixels(offset).or_fail()?))
+    }
+}
+
+/// Dithering method of [`ImportedImage::quantize()`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Dither {
+    /// No dithering (nearest palette color).
+    #[default]
+    None,
+
+    /// Floyd–Steinberg error diffusion.
+    FloydSteinberg,
+
+    /// Atkinson error diffusion (diffuses 3/4 of the error).
+    Atkinson,
+
+    /// Ordered dithering with a 4x4 Bayer matrix.
+    Bayer,
+}
+
+const BAYER4: [[f32; 4]; 4] = [
+    [0.0, 8.0, 2.0, 10.0],
+    [12.0, 4.0, 14.0, 6.0],
+    [3.0, 11.0, 1.0, 9.0],
+    [15.0, 7.0, 13.0, 5.0],
+];
+
+/// Strength of the Bayer threshold offsets (in 0..=255 RGB units).
+const BAYER_SPREAD: f32 = 48.0;
+
+fn quantize(pixels: &mut [Color], width: usize, palette: &[Color], dither: Dither) {
+    if palette.is_empty() || width == 0 {
+        return;
+    }
+    let palette = palette
+        .iter()
+        .map(|&c| (c, to_lab(rgb(c))))
+        .collect::<Vec<_>>();
+    let nearest = |c: [f32; 3]| {
+        let lab = to_lab(c);
+        palette
+            .iter()
+            .min_by(|a, b| distance(a.1, lab).total_cmp(&distance(b.1, lab)))
+            .map(|(c, _)| Color::rgb(c.r, c.g, c.b))
+            .expect("unreachable")
+    };
+
+    let height = pixels.len() / width;
+    let mut work = pixels.iter().map(|&c| rgb(c)).collect::<Vec<_>>();
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            if pixels[i].a < 128 {
+                pixels[i] = Color::rgba(0, 0, 0, 0);
+                continue;
+            }
+            let mut c = work[i];
+            if dither == Dither::Bayer {
+                let offset = (BAYER4[y % 4][x % 4] / 16.0 - 0.5) * BAYER_SPREAD;
+                c = c.map(|v| v + offset);
+            }
+            let q = nearest(c);
+            pixels[i] = q;
+
+            let error = [
+                work[i][0] - f32::from(q.r),
+                work[i][1] - f32::from(q.g),
+                work[i][2] - f32::from(q.b),
+            ];
+            let targets: &[(isize, usize, f32)] = match dither {
+                Dither::FloydSteinberg => &[
+                    (1, 0, 7.0 / 16.0),
+                    (-1, 1, 3.0 / 16.0),
+                    (0, 1, 5.0 / 16.0),
+                    (1, 1, 1.0 / 16.0),
+                ],
+                Dither::Atkinson => &[
+                    (1, 0, 1.0 / 8.0),
+                    (2, 0, 1.0 / 8.0),
+                    (-1, 1, 1.0 / 8.0),
+                    (0, 1, 1.0 / 8.0),
+                    (1, 1, 1.0 / 8.0),
+                    (0, 2, 1.0 / 8.0),
+                ],
+                Dither::None | Dither::Bayer => &[],
+            };
+            for &(dx, dy, weight) in targets {
+                let tx = x as isize + dx;
+                let ty = y + dy;
+                if tx < 0 || tx as usize >= width || ty >= height {
+                    continue;
+                }
+                let target = &mut work[ty * width + tx as usize];
+                for (t, e) in target.iter_mut().zip(error) {
+                    *t += e * weight;
+                }
+            }
+        }
+    }
+}
+
+/// Extracts the channel selected by a BMP channel mask, scaled to 8 bits.
+fn channel(value: u32, mask: u32) -> u8 {
+    if mask == 0 {
+        return 0;
+    }
+    let max = u64::from(mask >> mask.trailing_zeros());
+    let v = u64::from((value & mask) >> mask.trailing_zeros());
+    ((v * 255 + max / 2) / max) as u8
+}
+
+fn rgb(c: Color) -> [f32; 3] {
+    [f32::from(c.r), f32::from(c.g), f32::from(c.b)]
+}
+
+/// Converts an sRGB color (0..=255, may be out of range due to dithering) to CIELAB (D65).
+fn to_lab(c: [f32; 3]) -> [f32; 3] {
+    let linear = c.map(|v| {
+        let v = (v / 255.0).clamp(0.0, 1.0);
+        if v <= 0.04045 {
+            v / 12.92
+        } else {
+            ((v + 0.055) / 1.055).powf(2.4)
+        }
+    });
+    let [r, g, b] = linear;
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let f = |t: f32| {
+        if t > 0.008856 {
+            t.cbrt()
+        } else {
+            7.787 * t + 16.0 / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y).powi(2)).sum()
+}
+
+fn run_lengths<'a>(mut colors: impl Iterator<Item = &'a Color>) -> impl Iterator<Item = u32> {
+    let mut current = colors.next().map(|&c| (c, 1));
+    std::iter::from_fn(move || {
+        let (color, mut n) = current?;
+        for &c in colors.by_ref() {
+            if c != color {
+                current = Some((c, 1));
+                return Some(n);
+            }
+            n += 1;
+        }
+        current = None;
+        Some(n)
+    })
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const R: Color = Color::rgb(255, 0, 0);
+    const G: Color = Color::rgb(0, 255, 0);
+    const B: Color = Color::rgb(0, 0, 255);
+    const W: Color = Color::rgb(255, 255, 255);
+
+    /// Makes a BMP file having a `BITMAPINFOHEADER`.
+    fn bmp(bpp: u16, width: i32, height: i32, palette: &[[u8; 4]], data: &[u8]) -> Vec<u8> {
+        let data_offset = 14 + 40 + palette.len() as u32 * 4;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"BM");
+        bytes.extend_from_slice(&(data_offset + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&data_offset.to_le_bytes());
+        bytes.extend_from_slice(&40u32.to_le_bytes());
+        bytes.extend_from_slice(&width.to_le_bytes());
+        bytes.extend_from_slice(&height.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&bpp.to_le_bytes());
+        bytes.extend_from_slice(&[0; 16]);
+        bytes.extend_from_slice(&(palette.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        for entry in palette {
+            bytes.extend_from_slice(entry);
+        }
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn decode_bmp_works() {
+        // Bottom-up 24-bit rows (BGR) padded to 4 bytes.
+        let data = [0, 0, 255, 0, 255, 0, 0, 0, 255, 0, 0, 255, 255, 255, 0, 0];
+        let image = ImportedImage::decode_bmp(&bmp(24, 2, 2, &[], &data)).expect("decode");
+        assert_eq!((image.width(), image.height()), (2, 2));
+        assert_eq!(image.pixels, [B, W, R, G]);
+
+        // Top-down 8-bit rows.
+        let palette = [[0, 0, 255, 0], [255, 0, 0, 0]];
+        let data = [0, 1, 0, 0, 1, 0, 0, 0];
+        let image = ImportedImage::decode_bmp(&bmp(8, 2, -2, &palette, &data)).expect("decode");
+        assert_eq!(image.pixels, [R, B, B, R]);
+
+        // 32-bit pixels with an unused alpha channel are opaque.
+        let data = [0, 0, 255, 0];
+        let image = ImportedImage::decode_bmp(&bmp(32, 1, 1, &[], &data)).expect("decode");
+        assert_eq!(image.pixels, [R]);
+
+        let bytes = bmp(24, 2, 2, &[], &data);
+        assert!(ImportedImage::decode_bmp(&bytes).is_err());
+    }
+
+    #[test]
+    fn decode_bmp_rejects_oversized_headers() {
+        let data = [0, 0, 255, 0];
+        let bytes = bmp(24, i32::MAX, i32::MAX, &[], &data);
+        assert!(ImportedImage::decode_bmp(&bytes).is_err());
+        let bytes = bmp(32, 1, i32::MIN, &[], &data);
+        assert!(ImportedImage::decode_bmp(&bytes).is_err());
+
+        // Truncated pixel data.
+        let bytes = bmp(32, 1, 2, &[], &data);
+        assert!(ImportedImage::decode_bmp(&bytes).is_err());
+    }
+
+    #[test]
+    fn decode_bmp_reads_channel_masks() {
+        // `BI_BITFIELDS` masks follow the `BITMAPINFOHEADER` (placed where the palette would be).
+        let masks = [0xff00_0000u32, 0x00ff_0000, 0x0000_ff00].map(u32::to_le_bytes);
+        let data = [0x80, 0xff, 0x00, 0x00];
+        let mut bytes = bmp(32, 1, 1, &masks, &data);
+        bytes[30] = 3;
+        let image = ImportedImage::decode_bmp(&bytes).expect("decode");
+        assert_eq!(image.pixels, [Color::rgb(0, 0, 255)]);
+
+        // 10 bits per channel.
+        let masks = [0x3ff0_0000u32, 0x000f_fc00, 0x0000_03ff].map(u32::to_le_bytes);
+        let data = (0x3ffu32 << 20 | 0x200 << 10).to_le_bytes();
+        let mut bytes = bmp(32, 1, 1, &masks, &data);
+        bytes[30] = 3;
+        let image = ImportedImage::decode_bmp(&bytes).expect("decode");
+        assert_eq!(image.pixels, [Color::rgb(255, 128, 0)]);
+    }
+
+    #[test]
+    fn decode_png_works() {
+        let mut bytes = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, 2, 1);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().expect("header");
+        writer
+            .write_image_data(&[255, 0, 0, 255, 0, 0, 255, 128])
+            .expect("data");
+        writer.finish().expect("finish");
+
+        let image = ImportedImage::decode_png(&bytes[..]).expect("decode");
+        assert_eq!((image.width(), image.height()), (2, 1));
+        assert_eq!(image.pixels, [R, Color::rgba(0, 0, 255, 128)]);
+        assert_eq!(
+            image
+                .pixels(Point::new(10, 20))
+                .expect("pixels")
+                .collect::<Vec<_>>(),
+            [
+                (Point::new(10, 20), R),
+                (Point::new(11, 20), Color::rgba(0, 0, 255, 128))
+            ]
+        );
+    }
+
+    #[test]
+    fn pixels_fail_on_coordinate_overflow() {
+        let image = ImportedImage {
+            width: 2,
+            height: 1,
+            pixels: vec![R, B],
+        };
+        assert!(image.to_command(Point::new(i16::MAX - 1, 0)).is_ok());
+        assert!(image.to_command(Point::new(i16::MAX, 0)).is_err());
+        assert!(image.to_command(Point::new(0, i16::MAX)).is_ok());
+
+        let wide = ImportedImage {
+            width: 0x8001,
+            height: 1,
+            pixels: vec![R; 0x8001],
+        };
+        assert!(wide.to_command(Point::new(0, 0)).is_err());
+        assert!(wide.to_command(Point::new(-1, 0)).is_ok());
+    }
+
+    fn gray(v: u8) -> Color {
+        Color::rgb(v, v, v)
+    }
+
+    fn quantized(pixels: &[Color], width: u32, palette: &[Color], dither: Dither) -> String {
+        let mut image = ImportedImage {
+            width,
+            height: pixels.len() as u32 / width,
+            pixels: pixels.to_vec(),
+        };
+        image.quantize(palette, dither);
+        image
+            .pixels
+            .chunks(width as usize)
+            .map(|row| {
+                row.iter()
+                    .map(|c| match (c.a, c.r) {
+                        (0, _) => '.',
+                        (_, 0) => 'B',
+                        _ => 'W',
+                    })
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    #[test]
+    fn resize_works() {
+        let image = ImportedImage {
+            width: 2,
+            height: 2,
+            pixels: vec![R, Color::rgba(0, 0, 255, 0), R, B],
+        };
+        let resized = image
+            .resize(NonZeroU32::MIN, NonZeroU32::MIN)
+            .expect("resize");
+        // Transparent pixels don't contribute to the color, but to the alpha.
+        assert_eq!(resized.pixels, [Color::rgba(170, 0, 85, 191)]);
+
+        let resized = image
+            .resize(NonZeroU32::new(4).unwrap(), NonZeroU32::MIN)
+            .expect("resize");
+        assert_eq!(
+            resized.pixels,
+            [
+                R,
+                R,
+                Color::rgba(0, 0, 255, 127),
+                Color::rgba(0, 0, 255, 127)
+            ]
+        );
+
+        let huge = NonZeroU32::new(0x10000).unwrap();
+        assert!(image.resize(huge, huge).is_err());
+    }
+
+    #[test]
+    fn quantize_uses_cielab_distance() {
+        // The gray is nearer in RGB, but the red is nearer in CIELAB.
+        let palette = [R, gray(128)];
+        let mut image = ImportedImage {
+            width: 1,
+            height: 1,
+            pixels: vec![Color::rgb(180, 60, 60)],
+        };
+        image.quantize(&palette, Dither::None);
+        assert_eq!(image.pixels, [R]);
+    }
+
+    #[test]
+    fn quantize_cuts_alpha_at_128() {
+        let pixels = [
+            Color::rgba(255, 255, 255, 127),
+            Color::rgba(255, 255, 255, 128),
+            Color::rgba(0, 0, 0, 255),
+        ];
+        let palette = [gray(0), W];
+        assert_eq!(quantized(&pixels, 3, &palette, Dither::None), ".WB");
+        let mut image = ImportedImage {
+            width: 3,
+            height: 1,
+            pixels: pixels.to_vec(),
+        };
+        image.quantize(&palette, Dither::None);
+        assert_eq!(image.pixels[1].a, 255);
+    }
+
+    #[test]
+    fn dither_works() {
+        let palette = [gray(0), W];
+        let gradient = (0..8).map(|i| gray(i * 36)).collect::<Vec<_>>();
+        assert_eq!(quantized(&gradient, 8, &palette, Dither::None), "BBBBWWWW");
+        assert_eq!(
+            quantized(&gradient, 8, &palette, Dither::FloydSteinberg),
+            "BBBWBWWW"
+        );
+        assert_eq!(
+            quantized(&gradient, 8, &palette, Dither::Atkinson),
+            "BBBWWWWW"
+        );
+
+        // A 50% gray (which is slightly nearer to white in CIELAB).
+        let flat = [gray(128); 16];
+        assert_eq!(
+            quantized(&flat, 4, &palette, Dither::None),
+            "WWWW/WWWW/WWWW/WWWW"
+        );
+        assert_eq!(
+            quantized(&flat, 4, &palette, Dither::FloydSteinberg),
+            "WBWB/BWBW/WBWB/BWBW"
+        );
+        assert_eq!(
+            quantized(&flat, 4, &palette, Dither::Atkinson),
+            "WBWW/WBBW/BWWB/BWWB"
+        );
+        // The cells whose Bayer thresholds are less than 5 fall below the black / white boundary.
+        assert_eq!(
+            quantized(&flat, 4, &palette, Dither::Bayer),
+            "BWBW/WBWW/BWBW/WWWW"
+        );
+    }
+
+    #[test]
+    fn pixel_scale_and_downsample_work() {
+        let image = ImportedImage {
+            width: 4,
+            height: 2,
+            pixels: vec![R, R, B, B, R, R, B, B],
+        };
+        let scale = image.pixel_scale();
+        assert_eq!(scale.get(), 2);
+        let image = image.downsample(scale);
+        assert_eq!((image.width(), image.height()), (2, 1));
+        assert_eq!(image.pixels, [R, B]);
+    }
+}
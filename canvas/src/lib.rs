@@ -2,10 +2,22 @@ mod canvas;
 mod canvas_agent;
 mod canvas_file;
 mod command;
+mod event;
+mod import;
+mod metadata;
 mod query;
+mod transport;
 
 pub use canvas::Canvas;
-pub use canvas_agent::{CanvasAgent, CanvasAgentRequest, CanvasAgentServer};
+pub use canvas_agent::{
+    CanvasAgent, CanvasAgentAddr, CanvasAgentClientId, CanvasAgentError, CanvasAgentErrorKind,
+    CanvasAgentMessage, CanvasAgentRequest, CanvasAgentRequestFrame, CanvasAgentResponse,
+    CanvasAgentResult, CanvasAgentServer, CanvasAgentServerInfo, CanvasSubscription,
+    AGENT_FEATURES, AGENT_PROTOCOL_VERSION,
+};
 pub use canvas_file::CanvasFile;
-pub use command::CanvasCommand;
-pub use query::{CanvasQuery, CanvasQueryValue};
+pub use command::{CanvasCommand, FloatCommand, HistoryCommand, SliceCommand};
+pub use event::CanvasEvent;
+pub use import::{Dither, ImportedImage};
+pub use metadata::{BACKGROUND_COLOR, BRUSH_COLOR, SESSION, TILE_PROPERTIES};
+pub use query::{CanvasMode, CanvasQuery, CanvasQueryValue};
@@ -0,0 +1,27 @@
+use pati::{Color, MetadataKey, MetadataNamespace, MetadataSchema};
+use std::collections::BTreeMap;
+
+pub const BACKGROUND_COLOR: MetadataKey<Color> =
+    MetadataKey::new("patica.background_color", Color::default);
+
+pub const BRUSH_COLOR: MetadataKey<Color> = MetadataKey::new("patica.brush_color", Color::default);
+
+/// Identifier of the editing session that applies the subsequent commands.
+pub const SESSION: MetadataKey<Option<String>> =
+    MetadataKey::new("patica.session", Option::default);
+
+/// Properties of the tiles exported to Tiled (see `patica export-tiled`).
+///
+/// The item name is a point `{x},{y}` in image coordinates, and the properties apply to the tile covering the point.
+pub const TILE_PROPERTIES: MetadataNamespace<BTreeMap<String, serde_json::Value>> =
+    MetadataNamespace::new("patica.tile");
+
+pub fn schema() -> MetadataSchema {
+    let mut schema = MetadataSchema::new();
+    schema
+        .register(BACKGROUND_COLOR)
+        .register(BRUSH_COLOR)
+        .register(SESSION)
+        .register_namespace(TILE_PROPERTIES);
+    schema
+}
@@ -1,6 +1,6 @@
-use pati::{Color, Point};
+use pati::{Color, Point, Version};
 use serde::{Deserialize, Serialize};
-use std::num::NonZeroU8;
+use std::{collections::BTreeMap, num::NonZeroU8};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -11,6 +11,29 @@ pub enum CanvasQuery {
     BackgroundColor,
     Scale,
     Fps,
+
+    /// Color of the pixel at the given point.
+    Pixel(Point),
+
+    /// Pixels in the given region (inclusive).
+    Pixels {
+        start: Point,
+        end: Point,
+    },
+    Anchors,
+
+    /// Metadata value of the given key.
+    Metadata(String),
+    Version,
+
+    /// Bounding box (inclusive) of the pixels.
+    BoundingBox,
+
+    /// Region (inclusive) of the selected slice, whose points are the marked points.
+    ///
+    /// The region is returned instead of the points, as a slice can cover billions of points.
+    MarkedPoints,
+    Mode,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -22,4 +45,28 @@ pub enum CanvasQueryValue {
     BackgroundColor(Color),
     Scale(NonZeroU8),
     Fps(NonZeroU8),
+    Pixel(Option<Color>),
+    Pixels(Vec<(Point, Color)>),
+    Anchors(BTreeMap<String, Point>),
+    Metadata(Option<serde_json::Value>),
+    Version(Version),
+    BoundingBox(Option<(Point, Point)>),
+    MarkedPoints(Option<(Point, Point)>),
+    Mode(CanvasMode),
+}
+
+/// Editing mode of a canvas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CanvasMode {
+    Normal,
+
+    /// A slice is selected.
+    Slice,
+
+    /// Floating pixels are placed.
+    Float,
+
+    /// A past version is being viewed.
+    History,
 }
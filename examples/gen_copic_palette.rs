@@ -1,6 +1,6 @@
 use copic_colors::{Family, Group, Value};
 use orfail::OrFail;
-use pati::{Color, ImageCommand, Point};
+use pati::{Color, ImageCommand, Point, Slice};
 use std::{
     collections::{BTreeMap, HashMap},
     io::Write,
@@ -25,6 +25,14 @@ fn main() -> pagurus::Result<()> {
         Some(Point::new(PALETTE_WIDTH - 1, PALETTE_HEIGHT - 1)),
     ))
     .or_fail()?;
+    write_command(ImageCommand::slice(
+        "palette",
+        Some(Slice::new(
+            Point::new(0, 0),
+            Point::new(PALETTE_WIDTH - 1, PALETTE_HEIGHT - 1),
+        )),
+    ))
+    .or_fail()?;
     write_command(ImageCommand::anchor(
         "origin",
         Some(Point::new(PALETTE_WIDTH / 2, PALETTE_HEIGHT / 2)),
@@ -1,8 +1,12 @@
-use crate::{Color, Point};
+use crate::{
+    record::{Checksum, Record},
+    Color, FileHeader, Point, Slice,
+};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::BTreeMap,
-    io::{BufRead, Write},
+    io::{BufRead, Error, ErrorKind, Write},
+    num::NonZeroU32,
 };
 
 /// [`Image`][crate::Image] command.
@@ -21,6 +25,17 @@ pub enum ImageCommand {
         point: Option<Point>,
     },
 
+    /// Slice command.
+    Slice {
+        /// Slice name.
+        name: String,
+
+        /// Slice region and properties.
+        ///
+        /// If `None`, the slice is removed.
+        slice: Option<Slice>,
+    },
+
     /// Put command.
     Put {
         /// Metadata item name.
@@ -29,6 +44,17 @@ pub enum ImageCommand {
         /// Metadata item value.
         value: serde_json::Value,
     },
+
+    /// Merge command.
+    ///
+    /// The value is applied to the metadata item as a JSON merge patch ([RFC 7396](https://www.rfc-editor.org/rfc/rfc7396)).
+    Merge {
+        /// Metadata item name.
+        name: String,
+
+        /// Merge patch.
+        value: serde_json::Value,
+    },
 }
 
 impl ImageCommand {
@@ -61,6 +87,14 @@ impl ImageCommand {
         }
     }
 
+    /// Makes a slice command.
+    pub fn slice(name: impl Into<String>, slice: Option<Slice>) -> Self {
+        Self::Slice {
+            name: name.into(),
+            slice,
+        }
+    }
+
     /// Makes a put command.
     pub fn put(name: impl Into<String>, value: serde_json::Value) -> Self {
         Self::Put {
@@ -68,6 +102,14 @@ impl ImageCommand {
             value,
         }
     }
+
+    /// Makes a merge command.
+    pub fn merge(name: impl Into<String>, value: serde_json::Value) -> Self {
+        Self::Merge {
+            name: name.into(),
+            value,
+        }
+    }
 }
 
 /// Patch command that is used to draw or erase pixels.
@@ -120,17 +162,68 @@ impl PatchEntry {
 #[derive(Debug)]
 pub struct ImageCommandWriter<W> {
     inner: W,
+    checksum: Checksum,
+    checksum_interval: Option<NonZeroU32>,
 }
 
 impl<W: Write> ImageCommandWriter<W> {
     /// Makes a new [`ImageCommandWriter`] instance.
     pub const fn new(inner: W) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            checksum: Checksum::new(),
+            checksum_interval: None,
+        }
+    }
+
+    /// Sets the interval (number of commands) of checksum records.
+    ///
+    /// If `None` (the default), checksum records are only written by [`ImageCommandWriter::write_checksum()`].
+    pub fn set_checksum_interval(&mut self, interval: Option<NonZeroU32>) {
+        self.checksum_interval = interval;
+    }
+
+    /// Takes over the checksum state of the given reader.
+    ///
+    /// This is needed to append commands to a stream that has been read by the reader.
+    /// If the reader and this writer share the same stream, call [`ImageCommandReader::continue_from()`] after writing
+    /// so that the reader's state also covers the written commands.
+    pub fn continue_from<R>(&mut self, reader: &ImageCommandReader<R>) {
+        self.checksum = reader.checksum;
+    }
+
+    /// Writes the given header.
+    ///
+    /// This must be called before writing any commands.
+    pub fn write_header(&mut self, header: &FileHeader) -> std::io::Result<()> {
+        self.write_record(&Record::Header(header.clone()))
     }
 
     /// Writes the given command.
     pub fn write_command(&mut self, command: &ImageCommand) -> std::io::Result<()> {
-        serde_json::to_writer(&mut self.inner, command)?;
+        let mut line = serde_json::to_vec(command)?;
+        line.push(b'\n');
+        self.inner.write_all(&line)?;
+        self.checksum.update(&line);
+        if self
+            .checksum_interval
+            .is_some_and(|n| self.checksum.commands() >= n.get())
+        {
+            self.write_checksum()?;
+        }
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    /// Writes a checksum record over the commands since the previous checksum record.
+    pub fn write_checksum(&mut self) -> std::io::Result<()> {
+        self.write_record(&Record::Checksum(self.checksum.to_record()))?;
+        self.checksum = Checksum::new();
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &Record) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.inner, record)?;
         writeln!(self.inner)?;
         self.inner.flush()?;
         Ok(())
@@ -138,10 +231,19 @@ impl<W: Write> ImageCommandWriter<W> {
 }
 
 /// [`ImageCommand`] reader.
+///
+/// Header and checksum records in the stream are handled by this reader transparently:
+/// an error is returned if the stream has a newer format version than supported or a checksum does not match
+/// (see also [`ImageCommandReader::set_recover_checksum_mismatches()`]).
 #[derive(Debug)]
 pub struct ImageCommandReader<R> {
     inner: R,
     line: String,
+    header: Option<FileHeader>,
+    checksum: Checksum,
+    is_first_record: bool,
+    recover_checksum_mismatches: bool,
+    checksum_mismatches: u32,
 }
 
 impl<R: BufRead> ImageCommandReader<R> {
@@ -150,19 +252,87 @@ impl<R: BufRead> ImageCommandReader<R> {
         Self {
             inner,
             line: String::new(),
+            header: None,
+            checksum: Checksum::new(),
+            is_first_record: true,
+            recover_checksum_mismatches: false,
+            checksum_mismatches: 0,
         }
     }
 
     /// Reads a command.
     pub fn read_command(&mut self) -> std::io::Result<Option<ImageCommand>> {
-        if 0 == self.inner.read_line(&mut self.line)? {
-            Ok(None)
-        } else if self.line.ends_with('\n') {
-            let command = serde_json::from_str(&self.line)?;
+        loop {
+            if 0 == self.inner.read_line(&mut self.line)? || !self.line.ends_with('\n') {
+                return Ok(None);
+            }
+            let is_first_record = std::mem::take(&mut self.is_first_record);
+            if !Record::is_record_line(&self.line) {
+                let command = serde_json::from_str(&self.line)?;
+                self.checksum.update(self.line.as_bytes());
+                self.line.clear();
+                return Ok(Some(command));
+            }
+            match serde_json::from_str(&self.line)? {
+                Record::Header(header) => {
+                    if !is_first_record {
+                        return Err(invalid_data("Header record must be the first record"));
+                    }
+                    if header.format_version > FileHeader::FORMAT_VERSION {
+                        return Err(invalid_data(format!(
+                            "Unsupported format version {} (this reader supports up to {}); please upgrade",
+                            header.format_version,
+                            FileHeader::FORMAT_VERSION
+                        )));
+                    }
+                    self.header = Some(header);
+                }
+                Record::Checksum(record) => {
+                    let expected = self.checksum.to_record();
+                    if record != expected && self.recover_checksum_mismatches {
+                        self.checksum_mismatches += 1;
+                    } else if record != expected {
+                        return Err(invalid_data(format!(
+                            "Checksum mismatch: expected {expected:?}, but got {record:?}"
+                        )));
+                    }
+                    self.checksum = Checksum::new();
+                }
+            }
             self.line.clear();
-            Ok(Some(command))
-        } else {
-            Ok(None)
         }
     }
 }
+
+impl<R> ImageCommandReader<R> {
+    /// Takes over the checksum state of the given writer.
+    ///
+    /// This is needed to read commands that are appended (by others) to a stream after the writer's commands.
+    pub fn continue_from<W>(&mut self, writer: &ImageCommandWriter<W>) {
+        self.checksum = writer.checksum;
+    }
+
+    /// Gets the header of the stream if it has been read.
+    pub fn header(&self) -> Option<&FileHeader> {
+        self.header.as_ref()
+    }
+
+    /// Makes checksum mismatches recoverable instead of failing the read.
+    ///
+    /// If enabled, the commands covered by a mismatched checksum record are still returned,
+    /// the reader resynchronizes at the record, and the mismatch is counted (see [`ImageCommandReader::checksum_mismatches()`]).
+    /// This is useful for streams appended by multiple writers,
+    /// as commands of another writer can be interleaved between the sync and the write of a writer.
+    pub fn set_recover_checksum_mismatches(&mut self, recover: bool) {
+        self.recover_checksum_mismatches = recover;
+    }
+
+    /// Gets the number of the checksum mismatches recovered so far.
+    pub fn checksum_mismatches(&self) -> u32 {
+        self.checksum_mismatches
+    }
+}
+
+fn invalid_data(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
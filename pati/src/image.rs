@@ -1,4 +1,7 @@
-use crate::{log::Log, Color, ImageCommand, PatchEntry, PatchImageCommand, Point, Version};
+use crate::{
+    log::Log, metadata::merge_patch, replay::Replay, Color, ImageCommand, PatchEntry,
+    PatchImageCommand, Point, ReplayStep, Slice, Version,
+};
 use std::{
     cmp::Ordering,
     collections::BTreeMap,
@@ -46,6 +49,11 @@ impl VersionedImage {
         self.image.anchors()
     }
 
+    /// Gets the all slices in this image.
+    pub fn slices(&self) -> &BTreeMap<String, Slice> {
+        self.image.slices()
+    }
+
     /// Gets the all metadata in this image.
     pub fn metadata(&self) -> &BTreeMap<String, serde_json::Value> {
         self.image.metadata()
@@ -70,6 +78,87 @@ impl VersionedImage {
         &self.log.commands()[i..]
     }
 
+    /// Restores the image at the given version.
+    ///
+    /// Returns `None` if the version is newer than the current version.
+    pub fn restore(&self, version: Version) -> Option<Image> {
+        self.log.restore_image(version)
+    }
+
+    /// Rolls back this image to the given version, discarding the commands applied after it.
+    ///
+    /// Returns `false` if the version is newer than the current version.
+    pub fn rollback(&mut self, version: Version) -> bool {
+        if version == self.version() {
+            return true;
+        }
+        let Some(image) = self.log.restore_image(version) else {
+            return false;
+        };
+        self.image = image;
+        self.log.truncate(version);
+        true
+    }
+
+    /// Makes a [`Replay`] stepping through the images after the given version.
+    pub fn replay(&self, since: Version, step: ReplayStep) -> Replay<'_> {
+        let since = since.min(self.version());
+        let image = self.log.restore_image(since).expect("unreachable");
+        Replay::new(self.applied_commands(since), since, image, step)
+    }
+
+    /// Finds the command that last drew or erased the pixel at the given point.
+    ///
+    /// Returns the version right after the command was applied and the command itself,
+    /// or `None` if the pixel has never been touched.
+    pub fn blame(&self, point: Point) -> Option<(Version, &ImageCommand)> {
+        let (i, _) = self.pixel_changes(point, point).into_iter().next_back()?;
+        Some((Version(i as u32 + 1), &self.log.commands()[i]))
+    }
+
+    /// Makes a map from each pixel point in the given range to the version
+    /// right after the command that last drew or erased the pixel.
+    pub fn blame_range<R>(&self, range: R) -> BTreeMap<Point, Version>
+    where
+        R: RangeBounds<Point>,
+    {
+        let (start, end) = rect_bounds(&range);
+        self.pixel_changes(start, end)
+            .into_iter()
+            .map(|(i, point)| (point, Version(i as u32 + 1)))
+            .collect()
+    }
+
+    /// Replays the logged patches and collects the (command index, point) pairs of the pixels
+    /// in the given rectangle whose colors were actually changed (redrawing the same color isn't a change).
+    fn pixel_changes(&self, start: Point, end: Point) -> Vec<(usize, Point)> {
+        let contains =
+            |p: &Point| (start.x..=end.x).contains(&p.x) && (start.y..=end.y).contains(&p.y);
+        let mut pixels = BTreeMap::new();
+        let mut changes = Vec::new();
+        for (i, command) in self.log.commands().iter().enumerate() {
+            let ImageCommand::Patch(c) = command else {
+                continue;
+            };
+            let mut before = BTreeMap::new();
+            for entry in c.entries() {
+                for &point in entry.points.iter().filter(|p| contains(p)) {
+                    let old = match entry.color {
+                        Some(color) => pixels.insert(point, color),
+                        None => pixels.remove(&point),
+                    };
+                    before.entry(point).or_insert(old);
+                }
+            }
+            for (point, old) in before {
+                if pixels.get(&point).copied() != old {
+                    changes.push((i, point));
+                }
+            }
+        }
+        changes
+    }
+
     /// Calculates the diff between the current image and the image at the given version.
     pub fn diff(&self, version: Version) -> Option<PatchImageCommand> {
         let image = self.log.restore_image(version)?;
@@ -82,6 +171,7 @@ impl VersionedImage {
 pub struct Image {
     pixels: BTreeMap<Point, Color>,
     anchors: BTreeMap<String, Point>,
+    slices: BTreeMap<String, Slice>,
     metadata: BTreeMap<String, serde_json::Value>,
 }
 
@@ -114,6 +204,11 @@ impl Image {
         &self.anchors
     }
 
+    /// Gets the all slices in this image.
+    pub fn slices(&self) -> &BTreeMap<String, Slice> {
+        &self.slices
+    }
+
     /// Gets the all metadata in this image.
     pub fn metadata(&self) -> &BTreeMap<String, serde_json::Value> {
         &self.metadata
@@ -132,6 +227,13 @@ impl Image {
                     self.anchors.remove(name).is_some()
                 }
             }
+            ImageCommand::Slice { name, slice } => {
+                if let Some(slice) = slice {
+                    self.slices.insert(name.clone(), slice.clone()).as_ref() != Some(slice)
+                } else {
+                    self.slices.remove(name).is_some()
+                }
+            }
             ImageCommand::Put { name, value } => {
                 if value.is_null() {
                     self.metadata.remove(name).is_some()
@@ -139,6 +241,15 @@ impl Image {
                     self.metadata.insert(name.clone(), value.clone()) != Some(value.clone())
                 }
             }
+            ImageCommand::Merge { name, value } => {
+                let mut merged = self
+                    .metadata
+                    .get(name)
+                    .cloned()
+                    .unwrap_or(serde_json::Value::Null);
+                merge_patch(&mut merged, value);
+                self.apply(&ImageCommand::put(name.clone(), merged))
+            }
         }
     }
 
@@ -215,6 +326,24 @@ impl Image {
     }
 }
 
+/// Converts the given range into the top-left and bottom-right points (inclusive) of a rectangle.
+fn rect_bounds<R>(range: &R) -> (Point, Point)
+where
+    R: RangeBounds<Point>,
+{
+    let start = match range.start_bound() {
+        Bound::Included(&p) => p,
+        Bound::Excluded(&p) => Point::new(p.x + 1, p.y + 1),
+        Bound::Unbounded => Point::new(i16::MIN, i16::MIN),
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&p) => p,
+        Bound::Excluded(&p) => Point::new(p.x - 1, p.y - 1),
+        Bound::Unbounded => Point::new(i16::MAX, i16::MAX),
+    };
+    (start, end)
+}
+
 #[derive(Debug)]
 struct RangePixels<'a> {
     image: &'a Image,
@@ -228,16 +357,7 @@ impl<'a> RangePixels<'a> {
     where
         R: RangeBounds<Point>,
     {
-        let start = match range.start_bound() {
-            Bound::Included(&p) => p,
-            Bound::Excluded(&p) => Point::new(p.x + 1, p.y + 1),
-            Bound::Unbounded => Point::new(i16::MIN, i16::MIN),
-        };
-        let end = match range.end_bound() {
-            Bound::Included(&p) => p,
-            Bound::Excluded(&p) => Point::new(p.x - 1, p.y - 1),
-            Bound::Unbounded => Point::new(i16::MAX, i16::MAX),
-        };
+        let (start, end) = rect_bounds(&range);
         let row = image.pixels.range(start..=end);
         Self {
             image,
@@ -265,3 +385,82 @@ impl<'a> Iterator for RangePixels<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blame_works() {
+        let mut image = VersionedImage::new();
+        let p0 = Point::new(0, 0);
+        let p1 = Point::new(1, 0);
+        let color = Color::rgb(1, 2, 3);
+        image.apply(&ImageCommand::patch(vec![PatchEntry::draw(
+            color,
+            vec![p0, p1],
+        )]));
+        image.apply(&ImageCommand::anchor("a", Some(p0)));
+        image.apply(&ImageCommand::patch(vec![PatchEntry::erase(vec![p1])]));
+
+        assert_eq!(image.blame(p0).map(|(v, _)| v), Some(Version(1)));
+        assert_eq!(image.blame(p1).map(|(v, _)| v), Some(Version(3)));
+        assert!(image.blame(Point::new(2, 0)).is_none());
+
+        let blame = image.blame_range(p0..=p1);
+        assert_eq!(blame.get(&p0), Some(&Version(1)));
+        assert_eq!(blame.get(&p1), Some(&Version(3)));
+        assert_eq!(image.blame_range(p1..=p1).len(), 1);
+    }
+
+    #[test]
+    fn blame_ignores_unchanged_pixels() {
+        let mut image = VersionedImage::new();
+        let p0 = Point::new(0, 0);
+        let p1 = Point::new(1, 0);
+        let color = Color::rgb(1, 2, 3);
+        image.apply(&ImageCommand::patch(vec![PatchEntry::draw(
+            color,
+            vec![p0],
+        )]));
+        image.apply(&ImageCommand::patch(vec![PatchEntry::draw(
+            color,
+            vec![p0, p1],
+        )]));
+
+        assert_eq!(image.blame(p0).map(|(v, _)| v), Some(Version(1)));
+        assert_eq!(image.blame(p1).map(|(v, _)| v), Some(Version(2)));
+        let blame = image.blame_range(p0..=p1);
+        assert_eq!(blame.get(&p0), Some(&Version(1)));
+        assert_eq!(blame.get(&p1), Some(&Version(2)));
+    }
+
+    #[test]
+    fn rollback_works() {
+        let mut image = VersionedImage::new();
+        let color = Color::rgb(1, 2, 3);
+        for x in 0..1200 {
+            image.apply(&ImageCommand::patch(vec![PatchEntry::draw(
+                color,
+                vec![Point::new(x, 0)],
+            )]));
+        }
+        assert!(!image.rollback(Version(1201)));
+        assert!(image.rollback(Version(1200)));
+        assert_eq!(image.pixels().len(), 1200);
+
+        assert!(image.rollback(Version(900)));
+        assert_eq!(image.version(), Version(900));
+        assert_eq!(image.pixels().len(), 900);
+        assert_eq!(image.applied_commands(Version(0)).len(), 900);
+
+        // Snapshots after the version are discarded too.
+        for x in 0..200 {
+            image.apply(&ImageCommand::patch(vec![PatchEntry::erase(vec![
+                Point::new(x, 0),
+            ])]));
+        }
+        let restored = image.restore(Version(1000)).expect("restore");
+        assert_eq!(restored.pixels().len(), 800);
+    }
+}
@@ -9,11 +9,19 @@
 mod command;
 mod image;
 mod log;
+mod metadata;
 mod pixel;
+mod record;
+mod replay;
+mod slice;
 
 pub use self::command::{
     ImageCommand, ImageCommandReader, ImageCommandWriter, PatchEntry, PatchImageCommand,
 };
 pub use self::image::{Image, VersionedImage};
 pub use self::log::Version;
+pub use self::metadata::{merge_patch, Metadata, MetadataKey, MetadataNamespace, MetadataSchema};
 pub use self::pixel::{Color, Point};
+pub use self::record::FileHeader;
+pub use self::replay::{Replay, ReplayStep};
+pub use self::slice::{Insets, Slice};
@@ -9,6 +9,18 @@ use serde::{Deserialize, Serialize};
 )]
 pub struct Version(pub(crate) u32);
 
+impl Version {
+    /// Makes a new [`Version`] instance.
+    pub const fn new(n: u32) -> Self {
+        Self(n)
+    }
+
+    /// Gets the number of applied commands.
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+}
+
 impl std::ops::Add<u32> for Version {
     type Output = Self;
 
@@ -50,6 +62,12 @@ impl Log {
         &self.commands
     }
 
+    /// Discards the commands applied after the given version.
+    pub fn truncate(&mut self, version: Version) {
+        self.commands.truncate(version.0 as usize);
+        self.snapshots.retain(|s| s.version <= version);
+    }
+
     pub fn restore_image(&self, version: Version) -> Option<Image> {
         if self.latest_image_version() < version {
             return None;
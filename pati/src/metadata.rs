@@ -0,0 +1,314 @@
+use crate::ImageCommand;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::BTreeMap, marker::PhantomData};
+
+/// Metadata items of an [`Image`][crate::Image].
+pub type Metadata = BTreeMap<String, serde_json::Value>;
+
+/// Typed key of a metadata item.
+#[derive(Debug)]
+pub struct MetadataKey<T> {
+    name: &'static str,
+    default: fn() -> T,
+}
+
+impl<T> MetadataKey<T> {
+    /// Makes a new [`MetadataKey`] instance.
+    ///
+    /// `default` is used when the item is absent in the metadata.
+    pub const fn new(name: &'static str, default: fn() -> T) -> Self {
+        Self { name, default }
+    }
+
+    /// Gets the name of this key.
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<T: DeserializeOwned> MetadataKey<T> {
+    /// Gets the value of this key from the given metadata.
+    pub fn get(&self, metadata: &Metadata) -> serde_json::Result<T> {
+        match metadata.get(self.name) {
+            None => Ok((self.default)()),
+            Some(value) => serde_json::from_value(value.clone()),
+        }
+    }
+}
+
+impl<T: Serialize> MetadataKey<T> {
+    /// Makes a put command to set the value of this key.
+    pub fn put(&self, value: &T) -> serde_json::Result<ImageCommand> {
+        Ok(ImageCommand::put(self.name, serde_json::to_value(value)?))
+    }
+}
+
+impl<T> MetadataKey<T> {
+    /// Makes a merge command to update a part of the value of this key.
+    pub fn merge(&self, patch: serde_json::Value) -> ImageCommand {
+        ImageCommand::merge(self.name, patch)
+    }
+}
+
+impl<T> Clone for MetadataKey<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for MetadataKey<T> {}
+
+/// Typed namespace of metadata items.
+///
+/// The name of an item in a namespace is `{prefix}.{name}`.
+#[derive(Debug)]
+pub struct MetadataNamespace<T> {
+    prefix: &'static str,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T> MetadataNamespace<T> {
+    /// Makes a new [`MetadataNamespace`] instance.
+    pub const fn new(prefix: &'static str) -> Self {
+        Self {
+            prefix,
+            _value: PhantomData,
+        }
+    }
+
+    /// Gets the prefix of this namespace.
+    pub const fn prefix(&self) -> &'static str {
+        self.prefix
+    }
+
+    /// Gets the full metadata item name of the given name in this namespace.
+    pub fn item_name(&self, name: &str) -> String {
+        format!("{}.{}", self.prefix, name)
+    }
+
+    /// Returns `true` if the given metadata item name belongs to this namespace.
+    pub fn contains(&self, item_name: &str) -> bool {
+        self.strip_prefix(item_name).is_some()
+    }
+
+    fn strip_prefix<'a>(&self, item_name: &'a str) -> Option<&'a str> {
+        item_name
+            .strip_prefix(self.prefix)
+            .and_then(|s| s.strip_prefix('.'))
+    }
+}
+
+impl<T: DeserializeOwned> MetadataNamespace<T> {
+    /// Gets the value of the given name in this namespace.
+    pub fn get(&self, metadata: &Metadata, name: &str) -> Option<serde_json::Result<T>> {
+        metadata
+            .get(&self.item_name(name))
+            .map(|value| serde_json::from_value(value.clone()))
+    }
+
+    /// Gets an iterator over the items in this namespace.
+    ///
+    /// The names of the items are yielded without the namespace prefix.
+    pub fn iter<'a>(
+        &'a self,
+        metadata: &'a Metadata,
+    ) -> impl 'a + Iterator<Item = (&'a str, serde_json::Result<T>)> {
+        metadata
+            .range(format!("{}.", self.prefix)..)
+            .map_while(|(item_name, value)| {
+                let name = self.strip_prefix(item_name)?;
+                Some((name, serde_json::from_value(value.clone())))
+            })
+    }
+}
+
+impl<T: Serialize> MetadataNamespace<T> {
+    /// Makes a put command to set the value of the given name in this namespace.
+    pub fn put(&self, name: &str, value: &T) -> serde_json::Result<ImageCommand> {
+        Ok(ImageCommand::put(
+            self.item_name(name),
+            serde_json::to_value(value)?,
+        ))
+    }
+}
+
+impl<T> MetadataNamespace<T> {
+    /// Makes a put command to remove the item of the given name in this namespace.
+    pub fn remove(&self, name: &str) -> ImageCommand {
+        ImageCommand::put(self.item_name(name), serde_json::Value::Null)
+    }
+}
+
+impl<T> Clone for MetadataNamespace<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for MetadataNamespace<T> {}
+
+type Validator = fn(&serde_json::Value) -> serde_json::Result<()>;
+
+fn validate<T: DeserializeOwned>(value: &serde_json::Value) -> serde_json::Result<()> {
+    T::deserialize(value).map(|_| ())
+}
+
+/// Set of registered metadata keys and namespaces used to validate metadata updates.
+///
+/// Items that are neither registered keys nor in registered namespaces are not validated.
+#[derive(Debug, Default, Clone)]
+pub struct MetadataSchema {
+    keys: BTreeMap<&'static str, Validator>,
+    namespaces: BTreeMap<&'static str, Validator>,
+}
+
+impl MetadataSchema {
+    /// Makes a new empty [`MetadataSchema`] instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the given key.
+    pub fn register<T: DeserializeOwned>(&mut self, key: MetadataKey<T>) -> &mut Self {
+        self.keys.insert(key.name, validate::<T>);
+        self
+    }
+
+    /// Registers the given namespace.
+    pub fn register_namespace<T: DeserializeOwned>(
+        &mut self,
+        namespace: MetadataNamespace<T>,
+    ) -> &mut Self {
+        self.namespaces.insert(namespace.prefix, validate::<T>);
+        self
+    }
+
+    /// Validates the given metadata item value.
+    ///
+    /// `null` is always valid as it means the removal of the item.
+    pub fn validate(&self, name: &str, value: &serde_json::Value) -> serde_json::Result<()> {
+        if value.is_null() {
+            return Ok(());
+        }
+        if let Some(validate) = self.keys.get(name) {
+            return validate(value);
+        }
+        for (&prefix, validate) in &self.namespaces {
+            if MetadataNamespace::<()>::new(prefix).contains(name) {
+                return validate(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates the metadata item value that will be set if the given command is applied to an image having the given metadata.
+    pub fn validate_command(
+        &self,
+        metadata: &Metadata,
+        command: &ImageCommand,
+    ) -> serde_json::Result<()> {
+        match command {
+            ImageCommand::Put { name, value } => self.validate(name, value),
+            ImageCommand::Merge { name, value } => {
+                let mut merged = metadata
+                    .get(name)
+                    .cloned()
+                    .unwrap_or(serde_json::Value::Null);
+                merge_patch(&mut merged, value);
+                self.validate(name, &merged)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Applies the given JSON merge patch ([RFC 7396](https://www.rfc-editor.org/rfc/rfc7396)) to the target value.
+pub fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::new());
+    }
+    let serde_json::Value::Object(target) = target else {
+        unreachable!();
+    };
+    for (name, value) in patch {
+        if value.is_null() {
+            target.remove(name);
+        } else {
+            merge_patch(
+                target
+                    .entry(name.clone())
+                    .or_insert(serde_json::Value::Null),
+                value,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Color, Image};
+    use serde_json::json;
+
+    const BACKGROUND_COLOR: MetadataKey<Color> =
+        MetadataKey::new("background_color", Color::default);
+    const FRAMES: MetadataNamespace<u32> = MetadataNamespace::new("frames");
+
+    #[test]
+    fn typed_key_and_namespace_work() {
+        let mut image = Image::new();
+        assert_eq!(
+            BACKGROUND_COLOR.get(image.metadata()).ok(),
+            Some(Color::default())
+        );
+
+        let color = Color::rgb(10, 20, 30);
+        assert!(image.apply(&BACKGROUND_COLOR.put(&color).unwrap()));
+        assert_eq!(BACKGROUND_COLOR.get(image.metadata()).ok(), Some(color));
+
+        assert!(image.apply(&FRAMES.put("a", &1).unwrap()));
+        assert!(image.apply(&FRAMES.put("b", &2).unwrap()));
+        assert!(image.apply(&ImageCommand::put("framesx", json!(3))));
+        assert!(image.apply(&ImageCommand::put("g", json!(4))));
+        let items = FRAMES
+            .iter(image.metadata())
+            .map(|(name, value)| (name, value.unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(items, [("a", 1), ("b", 2)]);
+    }
+
+    #[test]
+    fn schema_validation_works() {
+        let mut schema = MetadataSchema::new();
+        schema.register(BACKGROUND_COLOR).register_namespace(FRAMES);
+
+        let image = Image::new();
+        let valid = ImageCommand::put("background_color", json!([1, 2, 3]));
+        let invalid = ImageCommand::put("background_color", json!("red"));
+        assert!(schema.validate_command(image.metadata(), &valid).is_ok());
+        assert!(schema.validate_command(image.metadata(), &invalid).is_err());
+        assert!(schema.validate("frames.a", &json!(1)).is_ok());
+        assert!(schema.validate("frames.a", &json!("1")).is_err());
+        assert!(schema.validate("unknown", &json!("1")).is_ok());
+    }
+
+    #[test]
+    fn merge_patch_works() {
+        let mut image = Image::new();
+        assert!(image.apply(&ImageCommand::put("x", json!({"a": 1, "b": {"c": 2}}))));
+        assert!(image.apply(&ImageCommand::merge("x", json!({"b": {"c": 3, "d": 4}}))));
+        assert!(image.apply(&ImageCommand::merge("x", json!({"a": null}))));
+        assert!(!image.apply(&ImageCommand::merge("x", json!({"a": null}))));
+        assert_eq!(
+            image.metadata().get("x"),
+            Some(&json!({"b": {"c": 3, "d": 4}}))
+        );
+
+        assert!(image.apply(&ImageCommand::merge("x", serde_json::Value::Null)));
+        assert!(image.metadata().get("x").is_none());
+    }
+}
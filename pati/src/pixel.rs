@@ -86,6 +86,21 @@ impl Point {
     pub const fn new(x: i16, y: i16) -> Self {
         Self { x, y }
     }
+
+    /// Gets the bounding box (inclusive) of the given points.
+    ///
+    /// Returns `None` if `points` is empty.
+    pub fn bounding_box(points: impl IntoIterator<Item = Self>) -> Option<(Self, Self)> {
+        let mut bbox: Option<(Self, Self)> = None;
+        for point in points {
+            let (start, end) = bbox.get_or_insert((point, point));
+            start.x = start.x.min(point.x);
+            start.y = start.y.min(point.y);
+            end.x = end.x.max(point.x);
+            end.y = end.y.max(point.y);
+        }
+        bbox
+    }
 }
 
 impl std::ops::Add for Point {
@@ -135,3 +150,17 @@ impl From<Point> for (i16, i16) {
         (point.x, point.y)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounding_box_works() {
+        assert_eq!(Point::bounding_box([]), None);
+        assert_eq!(
+            Point::bounding_box([Point::new(3, -1), Point::new(-2, 4), Point::new(0, 0)]),
+            Some((Point::new(-2, -1), Point::new(3, 4)))
+        );
+    }
+}
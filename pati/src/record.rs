@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+
+/// Header record of a pati stream.
+///
+/// If present, this must be the first record of the stream.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileHeader {
+    /// Format version of the stream.
+    pub format_version: u32,
+
+    /// Name of the program that created the stream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creator: Option<String>,
+
+    /// Creation time of the stream (seconds since the UNIX epoch).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+}
+
+impl FileHeader {
+    /// The latest format version supported by this crate.
+    pub const FORMAT_VERSION: u32 = 1;
+
+    /// Makes a new [`FileHeader`] instance with the latest format version.
+    pub fn new(creator: impl Into<String>, created_at: u64) -> Self {
+        Self {
+            format_version: Self::FORMAT_VERSION,
+            creator: Some(creator.into()),
+            created_at: Some(created_at),
+        }
+    }
+}
+
+/// Checksum record over the command lines since the previous checksum record (or the start of the stream).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ChecksumRecord {
+    pub commands: u32,
+    pub crc32: u32,
+}
+
+/// Non-command record of a pati stream.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Record {
+    Header(FileHeader),
+    Checksum(ChecksumRecord),
+}
+
+impl Record {
+    const TAGS: [&'static str; 2] = ["\"header\"", "\"checksum\""];
+
+    /// Returns `true` if the given line is a header or checksum record (rather than an [`ImageCommand`][crate::ImageCommand]).
+    ///
+    /// Only the tag of the line is inspected; the line still needs to be decoded.
+    pub fn is_record_line(line: &str) -> bool {
+        let Some(rest) = line.trim_start().strip_prefix('{') else {
+            return false;
+        };
+        let rest = rest.trim_start();
+        Self::TAGS.iter().any(|tag| rest.starts_with(tag))
+    }
+}
+
+/// Running CRC-32 (IEEE) over command lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Checksum {
+    commands: u32,
+    crc: u32,
+}
+
+impl Checksum {
+    pub const fn new() -> Self {
+        Self {
+            commands: 0,
+            crc: !0,
+        }
+    }
+
+    pub fn commands(self) -> u32 {
+        self.commands
+    }
+
+    pub fn update(&mut self, line: &[u8]) {
+        for &b in line {
+            self.crc ^= u32::from(b);
+            for _ in 0..8 {
+                self.crc = if self.crc & 1 == 1 {
+                    (self.crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    self.crc >> 1
+                };
+            }
+        }
+        self.commands += 1;
+    }
+
+    pub fn to_record(self) -> ChecksumRecord {
+        ChecksumRecord {
+            commands: self.commands,
+            crc32: !self.crc,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ImageCommand, ImageCommandReader, ImageCommandWriter, Point};
+    use std::num::NonZeroU32;
+
+    fn read_all(data: &[u8]) -> std::io::Result<usize> {
+        let mut reader = ImageCommandReader::new(data);
+        let mut n = 0;
+        while reader.read_command()?.is_some() {
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    #[test]
+    fn header_and_checksum_work() {
+        let mut buf = Vec::new();
+        let mut writer = ImageCommandWriter::new(&mut buf);
+        writer.set_checksum_interval(NonZeroU32::new(2));
+        writer.write_header(&FileHeader::new("test", 0)).unwrap();
+        for i in 0..5 {
+            let command = ImageCommand::anchor("a", Some(Point::new(i, 0)));
+            writer.write_command(&command).unwrap();
+        }
+        let data = String::from_utf8(buf).unwrap();
+        assert_eq!(data.lines().count(), 1 + 5 + 2);
+        assert_eq!(read_all(data.as_bytes()).ok(), Some(5));
+
+        let tampered = data.replacen("[1,0]", "[9,0]", 1);
+        assert!(read_all(tampered.as_bytes()).is_err());
+
+        let newer = data.replacen("\"format_version\":1", "\"format_version\":100", 1);
+        assert!(read_all(newer.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn checksum_mismatches_can_be_recovered() {
+        let mut buf = Vec::new();
+        let mut writer = ImageCommandWriter::new(&mut buf);
+        writer.set_checksum_interval(NonZeroU32::new(2));
+        for i in 0..4 {
+            let command = ImageCommand::anchor("a", Some(Point::new(i, 0)));
+            writer.write_command(&command).unwrap();
+        }
+
+        // Another writer appends a command between the sync and the write of the writer above.
+        let data = String::from_utf8(buf).unwrap();
+        let (first, rest) = data.split_at(data.find('\n').unwrap() + 1);
+        let foreign = serde_json::to_string(&ImageCommand::anchor("b", None)).unwrap();
+        let data = format!("{first}{foreign}\n{rest}");
+        assert!(read_all(data.as_bytes()).is_err());
+
+        let mut reader = ImageCommandReader::new(data.as_bytes());
+        reader.set_recover_checksum_mismatches(true);
+        let mut n = 0;
+        while reader.read_command().unwrap().is_some() {
+            n += 1;
+        }
+        assert_eq!(n, 5);
+        assert_eq!(reader.checksum_mismatches(), 1);
+    }
+
+    #[test]
+    fn command_decode_errors_are_reported() {
+        let error = read_all(b"{\"anchor\":{\"name\":1,\"point\":null}}\n").expect_err("error");
+        assert!(
+            error.to_string().contains("invalid type: integer `1`"),
+            "{error}"
+        );
+
+        let error = read_all(b"{\"checksum\":{\"commands\":1}}\n").expect_err("error");
+        assert!(
+            error.to_string().contains("missing field `crc32`"),
+            "{error}"
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{Image, ImageCommand, Version};
+use std::num::NonZeroU32;
+
+/// Granularity of [`Replay`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReplayStep {
+    /// Yields the image every N commands.
+    Commands(NonZeroU32),
+
+    /// Yields the image every action group.
+    ///
+    /// An action group is a run of commands that ends with a pixel-changing (patch) command,
+    /// so that every yielded image (except possibly the last one) has a visible change.
+    ActionGroups,
+}
+
+impl Default for ReplayStep {
+    fn default() -> Self {
+        Self::Commands(NonZeroU32::MIN)
+    }
+}
+
+/// Stepper over the images at each version of a [`VersionedImage`][crate::VersionedImage].
+///
+/// This is created by [`VersionedImage::replay()`][crate::VersionedImage::replay].
+/// The image is updated in place, so callers should clone only the images they keep.
+#[derive(Debug)]
+pub struct Replay<'a> {
+    commands: &'a [ImageCommand],
+    version: Version,
+    image: Image,
+    step: ReplayStep,
+}
+
+impl<'a> Replay<'a> {
+    pub(crate) fn new(
+        commands: &'a [ImageCommand],
+        version: Version,
+        image: Image,
+        step: ReplayStep,
+    ) -> Self {
+        Self {
+            commands,
+            version,
+            image,
+            step,
+        }
+    }
+
+    fn apply_next(&mut self) -> Option<&'a ImageCommand> {
+        let (command, commands) = self.commands.split_first()?;
+        self.image.apply(command);
+        self.commands = commands;
+        self.version = self.version + 1;
+        Some(command)
+    }
+
+    /// Advances the replay by one step and returns the version and the image at that point.
+    ///
+    /// Returns `None` when all commands have been replayed.
+    pub fn step(&mut self) -> Option<(Version, &Image)> {
+        if self.commands.is_empty() {
+            return None;
+        }
+        match self.step {
+            ReplayStep::Commands(n) => {
+                for _ in 0..n.get() {
+                    if self.apply_next().is_none() {
+                        break;
+                    }
+                }
+            }
+            ReplayStep::ActionGroups => {
+                while let Some(command) = self.apply_next() {
+                    if matches!(command, ImageCommand::Patch(_)) {
+                        break;
+                    }
+                }
+            }
+        }
+        Some((self.version, &self.image))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Color, Point, VersionedImage};
+
+    fn collect(mut replay: Replay) -> Vec<(u32, usize)> {
+        let mut versions = Vec::new();
+        while let Some((v, image)) = replay.step() {
+            versions.push((v.0, image.pixels().len()));
+        }
+        versions
+    }
+
+    #[test]
+    fn replay_works() {
+        let mut image = VersionedImage::new();
+        let draw =
+            |x| ImageCommand::draw_pixels([(Point::new(x, 0), Color::rgb(0, 0, 0))].into_iter());
+        image.apply(&draw(0));
+        image.apply(&ImageCommand::anchor("a", Some(Point::new(0, 0))));
+        image.apply(&draw(1));
+        image.apply(&draw(2));
+
+        let step = ReplayStep::Commands(NonZeroU32::new(3).unwrap());
+        let versions = collect(image.replay(Version::default(), step));
+        assert_eq!(versions, [(3, 2), (4, 3)]);
+
+        let versions = collect(image.replay(Version(1), ReplayStep::ActionGroups));
+        assert_eq!(versions, [(3, 2), (4, 3)]);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::Point;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Named rectangular region of an [`Image`][crate::Image].
+///
+/// The corners of a deserialized slice are normalized so that `start` is the top-left point.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "SliceData")]
+pub struct Slice {
+    /// Top-left point (inclusive).
+    pub start: Point,
+
+    /// Bottom-right point (inclusive).
+    pub end: Point,
+
+    /// Pivot point relative to `start`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pivot: Option<Point>,
+
+    /// Nine-slice insets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nine_slice: Option<Insets>,
+
+    /// Tags.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+
+    /// User defined properties.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, serde_json::Value>,
+}
+
+impl Slice {
+    /// Makes a new [`Slice`] instance covering the rectangle spanned by the given two points.
+    pub fn new(a: Point, b: Point) -> Self {
+        Self {
+            start: Point::new(a.x.min(b.x), a.y.min(b.y)),
+            end: Point::new(a.x.max(b.x), a.y.max(b.y)),
+            pivot: None,
+            nine_slice: None,
+            tags: Vec::new(),
+            properties: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the width of this slice.
+    pub fn width(&self) -> u16 {
+        self.end.x.abs_diff(self.start.x).saturating_add(1)
+    }
+
+    /// Gets the height of this slice.
+    pub fn height(&self) -> u16 {
+        self.end.y.abs_diff(self.start.y).saturating_add(1)
+    }
+
+    /// Returns `true` if the given point is in this slice.
+    pub fn contains(&self, point: Point) -> bool {
+        (self.start.x..=self.end.x).contains(&point.x)
+            && (self.start.y..=self.end.y).contains(&point.y)
+    }
+
+    /// Gets an iterator over the points in this slice.
+    pub fn points(&self) -> impl Iterator<Item = Point> {
+        let Point { x: x0, y: y0 } = self.start;
+        let Point { x: x1, y: y1 } = self.end;
+        (y0..=y1).flat_map(move |y| (x0..=x1).map(move |x| Point::new(x, y)))
+    }
+
+    /// Moves this slice by the given delta.
+    pub fn translate(&mut self, delta: Point) {
+        self.start = self.start + delta;
+        self.end = self.end + delta;
+    }
+
+    /// Moves the bottom-right point of this slice by the given delta.
+    ///
+    /// The size of the slice never gets smaller than 1x1.
+    pub fn resize(&mut self, delta: Point) {
+        let end = self.end + delta;
+        self.end = Point::new(end.x.max(self.start.x), end.y.max(self.start.y));
+    }
+}
+
+#[derive(Deserialize)]
+struct SliceData {
+    start: Point,
+    end: Point,
+    #[serde(default)]
+    pivot: Option<Point>,
+    #[serde(default)]
+    nine_slice: Option<Insets>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    properties: BTreeMap<String, serde_json::Value>,
+}
+
+impl From<SliceData> for Slice {
+    fn from(data: SliceData) -> Self {
+        Self {
+            pivot: data.pivot,
+            nine_slice: data.nine_slice,
+            tags: data.tags,
+            properties: data.properties,
+            ..Self::new(data.start, data.end)
+        }
+    }
+}
+
+/// Insets of a nine-slice.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Insets {
+    /// Left inset.
+    pub left: u16,
+
+    /// Top inset.
+    pub top: u16,
+
+    /// Right inset.
+    pub right: u16,
+
+    /// Bottom inset.
+    pub bottom: u16,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Image, ImageCommand};
+
+    #[test]
+    fn slice_command_works() {
+        let mut image = Image::new();
+        let mut slice = Slice::new(Point::new(3, 4), Point::new(0, 0));
+        assert_eq!((slice.width(), slice.height()), (4, 5));
+        assert!(image.apply(&ImageCommand::slice("a", Some(slice.clone()))));
+        assert!(!image.apply(&ImageCommand::slice("a", Some(slice.clone()))));
+
+        slice.translate(Point::new(1, 1));
+        slice.resize(Point::new(-10, 2));
+        assert_eq!(slice.start, Point::new(1, 1));
+        assert_eq!(slice.end, Point::new(1, 7));
+        assert!(image.apply(&ImageCommand::slice("a", Some(slice.clone()))));
+        assert_eq!(image.slices().get("a"), Some(&slice));
+
+        assert!(image.apply(&ImageCommand::slice("a", None)));
+        assert!(image.slices().is_empty());
+    }
+
+    #[test]
+    fn deserialized_slice_is_normalized() {
+        let slice: Slice = serde_json::from_str(r#"{"start":[3,0],"end":[0,4]}"#).unwrap();
+        assert_eq!(slice.start, Point::new(0, 0));
+        assert_eq!(slice.end, Point::new(3, 4));
+        assert_eq!((slice.width(), slice.height()), (4, 5));
+    }
+}
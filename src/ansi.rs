@@ -0,0 +1,151 @@
+use crate::bitmap::Bitmap;
+use orfail::OrFail;
+use pati::Color;
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorMode {
+    /// 24-bit colors.
+    Truecolor,
+
+    /// xterm 256 colors.
+    Ansi256,
+}
+
+impl ColorMode {
+    /// Detects the color mode of the terminal by the `COLORTERM` environment variable.
+    pub fn detect() -> Self {
+        match std::env::var("COLORTERM") {
+            Ok(v) if v == "truecolor" || v == "24bit" => Self::Truecolor,
+            _ => Self::Ansi256,
+        }
+    }
+
+    fn write_color<W: Write>(self, writer: &mut W, layer: u8, color: Color) -> orfail::Result<()> {
+        match self {
+            Self::Truecolor => write!(
+                writer,
+                "\x1b[{layer}8;2;{};{};{}m",
+                color.r, color.g, color.b
+            ),
+            Self::Ansi256 => write!(writer, "\x1b[{layer}8;5;{}m", to_ansi256(color)),
+        }
+        .or_fail()
+    }
+}
+
+/// Writes the given bitmap using ANSI escape sequences and half-block characters (two pixels per cell).
+///
+/// Fully transparent pixels are not drawn.
+pub fn write_image<W: Write>(
+    mut writer: W,
+    bitmap: &Bitmap,
+    mode: ColorMode,
+) -> orfail::Result<()> {
+    const FG: u8 = 3;
+    const BG: u8 = 4;
+    let visible = |x, y| bitmap.get_pixel(x, y).filter(|c| c.a != 0);
+    for y in (0..bitmap.height()).step_by(2) {
+        for x in 0..bitmap.width() {
+            match (visible(x, y), visible(x, y + 1)) {
+                (Some(top), Some(bottom)) => {
+                    mode.write_color(&mut writer, FG, top).or_fail()?;
+                    mode.write_color(&mut writer, BG, bottom).or_fail()?;
+                    write!(writer, "▀").or_fail()?;
+                }
+                (Some(top), None) => {
+                    mode.write_color(&mut writer, FG, top).or_fail()?;
+                    write!(writer, "▀").or_fail()?;
+                }
+                (None, Some(bottom)) => {
+                    mode.write_color(&mut writer, FG, bottom).or_fail()?;
+                    write!(writer, "▄").or_fail()?;
+                }
+                (None, None) => {
+                    write!(writer, " ").or_fail()?;
+                    continue;
+                }
+            }
+            write!(writer, "\x1b[0m").or_fail()?;
+        }
+        writeln!(writer).or_fail()?;
+    }
+    Ok(())
+}
+
+/// Converts the given color to the nearest xterm 256 color (the 6x6x6 color cube or the grayscale ramp).
+fn to_ansi256(color: Color) -> u8 {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    let nearest_level = |v: u8| {
+        (0..6)
+            .min_by_key(|&i| (i32::from(LEVELS[i]) - i32::from(v)).abs())
+            .expect("unreachable")
+    };
+    let (r, g, b) = (
+        nearest_level(color.r),
+        nearest_level(color.g),
+        nearest_level(color.b),
+    );
+    let cube = (16 + 36 * r + 6 * g + b) as u8;
+    let cube_color = Color::rgb(LEVELS[r], LEVELS[g], LEVELS[b]);
+
+    let average = (u32::from(color.r) + u32::from(color.g) + u32::from(color.b)) / 3;
+    let gray_index = (average.saturating_sub(3) / 10).min(23) as u8;
+    let gray_value = 8 + 10 * gray_index;
+    let gray_color = Color::rgb(gray_value, gray_value, gray_value);
+
+    if distance(color, gray_color) < distance(color, cube_color) {
+        232 + gray_index
+    } else {
+        cube
+    }
+}
+
+fn distance(a: Color, b: Color) -> u32 {
+    let d = |x: u8, y: u8| (i32::from(x) - i32::from(y)).unsigned_abs().pow(2);
+    d(a.r, b.r) + d(a.g, b.g) + d(a.b, b.b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_image_works() {
+        let mut bitmap = Bitmap::new(2, 3, None).expect("bitmap");
+        bitmap.set_pixel(0, 0, Color::rgb(255, 0, 0));
+        bitmap.set_pixel(0, 1, Color::rgb(0, 0, 255));
+        bitmap.set_pixel(1, 1, Color::rgb(0, 255, 0));
+        bitmap.set_pixel(1, 2, Color::rgb(255, 255, 255));
+
+        let mut output = Vec::new();
+        write_image(&mut output, &bitmap, ColorMode::Truecolor).expect("write");
+        assert_eq!(
+            String::from_utf8(output).expect("UTF-8"),
+            concat!(
+                "\x1b[38;2;255;0;0m\x1b[48;2;0;0;255m▀\x1b[0m",
+                "\x1b[38;2;0;255;0m▄\x1b[0m\n",
+                " \x1b[38;2;255;255;255m▀\x1b[0m\n",
+            )
+        );
+
+        let mut output = Vec::new();
+        write_image(&mut output, &bitmap, ColorMode::Ansi256).expect("write");
+        assert_eq!(
+            String::from_utf8(output).expect("UTF-8"),
+            concat!(
+                "\x1b[38;5;196m\x1b[48;5;21m▀\x1b[0m",
+                "\x1b[38;5;46m▄\x1b[0m\n",
+                " \x1b[38;5;231m▀\x1b[0m\n",
+            )
+        );
+    }
+
+    #[test]
+    fn to_ansi256_works() {
+        assert_eq!(to_ansi256(Color::rgb(0, 0, 0)), 16);
+        assert_eq!(to_ansi256(Color::rgb(255, 0, 0)), 196);
+        assert_eq!(to_ansi256(Color::rgb(128, 128, 128)), 244);
+        assert_eq!(to_ansi256(Color::rgb(135, 95, 215)), 98);
+    }
+}
@@ -0,0 +1,901 @@
+//! Aseprite (`.ase` / `.aseprite`) file importer.
+//!
+//! See <https://github.com/aseprite/aseprite/blob/main/docs/ase-file-specs.md> for the file format.
+use crate::{
+    bitmap::Bitmap,
+    clock::Ticks,
+    frame::{EmbeddedFrame, Frame, FRAMES},
+};
+use orfail::OrFail;
+use pati::{Color, ImageCommand, Point, Slice};
+use std::{collections::BTreeMap, io::Read, num::NonZeroU8, path::PathBuf};
+
+const HEADER_MAGIC: u16 = 0xA5E0;
+const FRAME_MAGIC: u16 = 0xF1FA;
+
+const CHUNK_OLD_PALETTE: u16 = 0x0004;
+const CHUNK_LAYER: u16 = 0x2004;
+const CHUNK_CEL: u16 = 0x2005;
+const CHUNK_TAGS: u16 = 0x2018;
+const CHUNK_PALETTE: u16 = 0x2019;
+const CHUNK_SLICE: u16 = 0x2022;
+
+/// Palette indices of Aseprite files fit in a byte.
+const MAX_PALETTE_SIZE: usize = 256;
+
+const LAYER_FLAG_VISIBLE: u16 = 1;
+const LAYER_FLAG_BACKGROUND: u16 = 8;
+const LAYER_TYPE_NORMAL: u16 = 0;
+
+/// Decoded Aseprite file.
+///
+/// As pati images have no layers, the visible layers of each frame are flattened.
+#[derive(Debug, Clone)]
+pub struct AsepriteFile {
+    pub width: u32,
+    pub height: u32,
+    pub frames: Vec<AsepriteFrame>,
+    pub tags: Vec<AsepriteTag>,
+    pub slices: Vec<AsepriteSlice>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AsepriteFrame {
+    pub duration_ms: u32,
+    pub image: Bitmap,
+}
+
+#[derive(Debug, Clone)]
+pub struct AsepriteTag {
+    pub name: String,
+    pub from: usize,
+    pub to: usize,
+}
+
+/// Slice at the first frame.
+#[derive(Debug, Clone)]
+pub struct AsepriteSlice {
+    pub name: String,
+    pub start: Point,
+    pub end: Point,
+    pub pivot: Option<Point>,
+}
+
+impl AsepriteFile {
+    pub fn decode(bytes: &[u8]) -> orfail::Result<Self> {
+        let mut r = ByteReader::new(bytes);
+        let _file_size = r.u32().or_fail()?;
+        (r.u16().or_fail()? == HEADER_MAGIC)
+            .or_fail_with(|()| "Not an Aseprite file".to_owned())?;
+        let frame_count = r.u16().or_fail()?;
+        let width = u32::from(r.u16().or_fail()?);
+        let height = u32::from(r.u16().or_fail()?);
+        let depth = r.u16().or_fail()?;
+        matches!(depth, 8 | 16 | 32)
+            .or_fail_with(|()| format!("Unsupported color depth: {depth}"))?;
+        let flags = r.u32().or_fail()?;
+        r.skip(2 + 4 + 4).or_fail()?;
+        let transparent_index = r.u8().or_fail()?;
+        r.skip(128 - 29).or_fail()?;
+
+        let mut decoder = Decoder {
+            depth,
+            layer_opacity_valid: flags & 1 != 0,
+            transparent_index,
+            palette: Vec::new(),
+            layers: Vec::new(),
+            cels: BTreeMap::new(),
+        };
+        let mut durations = Vec::new();
+        let mut tags = Vec::new();
+        let mut slices = Vec::new();
+        for frame_index in 0..usize::from(frame_count) {
+            let frame_size = r.u32().or_fail()? as usize;
+            let mut frame = ByteReader::new(r.bytes(frame_size.saturating_sub(4)).or_fail()?);
+            (frame.u16().or_fail()? == FRAME_MAGIC)
+                .or_fail_with(|()| format!("Invalid frame magic number: frame={frame_index}"))?;
+            let old_chunk_count = frame.u16().or_fail()?;
+            durations.push(u32::from(frame.u16().or_fail()?));
+            frame.skip(2).or_fail()?;
+            let chunk_count = match frame.u32().or_fail()? {
+                0 => u32::from(old_chunk_count),
+                n => n,
+            };
+            for _ in 0..chunk_count {
+                let chunk_size = frame.u32().or_fail()? as usize;
+                let chunk_type = frame.u16().or_fail()?;
+                let mut chunk =
+                    ByteReader::new(frame.bytes(chunk_size.saturating_sub(6)).or_fail()?);
+                match chunk_type {
+                    CHUNK_OLD_PALETTE if decoder.palette.is_empty() => {
+                        decoder.decode_old_palette(&mut chunk).or_fail()?
+                    }
+                    CHUNK_PALETTE => decoder.decode_palette(&mut chunk).or_fail()?,
+                    CHUNK_LAYER => decoder.decode_layer(&mut chunk).or_fail()?,
+                    CHUNK_CEL => decoder.decode_cel(frame_index, &mut chunk).or_fail()?,
+                    CHUNK_TAGS => tags = decode_tags(&mut chunk).or_fail()?,
+                    CHUNK_SLICE => slices.extend(decode_slice(&mut chunk).or_fail()?),
+                    _ => {}
+                }
+            }
+        }
+
+        let frames = durations
+            .into_iter()
+            .enumerate()
+            .map(|(i, duration_ms)| {
+                let image = decoder.render(i, width, height).or_fail()?;
+                Ok(AsepriteFrame { duration_ms, image })
+            })
+            .collect::<orfail::Result<Vec<_>>>()?;
+        Ok(Self {
+            width,
+            height,
+            frames,
+            tags,
+            slices,
+        })
+    }
+
+    /// Converts this file into pati commands.
+    ///
+    /// - Frames are drawn side by side (separated by one pixel) starting at `offset`.
+    ///   Each frame has the anchors `frame.{i}.start` / `frame.{i}.end` and the slice `frame.{i}`
+    ///   whose tags are the Aseprite tags containing the frame and whose `duration` property is the frame duration.
+    /// - If there are multiple frames, they are also embedded as patica frames (played at `fps`)
+    ///   into the stage area below the frames, delimited by the anchors `stage.start` / `stage.end`.
+    ///   `path` is the pati file path that the frames refer to.
+    /// - Slices are mapped to the anchors `{name}.start` / `{name}.end` (and `{name}.pivot`).
+    ///
+    /// Fails if the frames don't fit in the coordinate range of pati images.
+    pub fn to_commands(
+        &self,
+        offset: Point,
+        path: PathBuf,
+        fps: NonZeroU8,
+    ) -> orfail::Result<Vec<ImageCommand>> {
+        let mut commands = Vec::new();
+        let (width, height) = (i64::from(self.width), i64::from(self.height));
+        let (ox, oy) = (i64::from(offset.x), i64::from(offset.y));
+        let stage = if self.frames.len() > 1 {
+            Some((
+                checked_point(ox, oy + height + 1).or_fail()?,
+                checked_point(ox + width - 1, oy + 2 * height).or_fail()?,
+            ))
+        } else {
+            None
+        };
+        let mut ticks = 0;
+        for (i, frame) in self.frames.iter().enumerate() {
+            let x0 = ox + i as i64 * (width + 1);
+            let start = checked_point(x0, oy).or_fail()?;
+            let end = checked_point(x0 + width - 1, oy + height - 1).or_fail()?;
+
+            // As `end` fits in the coordinate range, so do all the pixels of the frame.
+            let pixels = (0..self.height)
+                .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+                .filter_map(|(x, y)| {
+                    let color = frame.image.get_pixel(x, y)?;
+                    (color.a != 0).then(|| (start + Point::new(x as i16, y as i16), color))
+                });
+            commands.push(ImageCommand::draw_pixels(pixels));
+
+            let start_anchor = format!("frame.{i}.start");
+            let end_anchor = format!("frame.{i}.end");
+            commands.push(ImageCommand::anchor(&start_anchor, Some(start)));
+            commands.push(ImageCommand::anchor(&end_anchor, Some(end)));
+
+            let mut slice = Slice::new(start, end);
+            slice.tags = self
+                .tags
+                .iter()
+                .filter(|t| (t.from..=t.to).contains(&i))
+                .map(|t| t.name.clone())
+                .collect();
+            slice
+                .properties
+                .insert("duration".to_owned(), frame.duration_ms.into());
+            commands.push(ImageCommand::slice(format!("frame.{i}"), Some(slice)));
+
+            if let Some((stage, _)) = stage {
+                let frame_ticks =
+                    (u64::from(frame.duration_ms) * u64::from(fps.get())).div_ceil(1000) as u32;
+                let embedded = EmbeddedFrame::new(
+                    Frame {
+                        name: format!("frame.{i}"),
+                        path: path.clone(),
+                        top_left_anchor: start_anchor,
+                        bottom_right_anchor: end_anchor,
+                        start_ticks: Ticks::new(ticks),
+                        end_ticks: Ticks::new(ticks + frame_ticks.max(1)),
+                    },
+                    stage,
+                );
+                ticks += frame_ticks.max(1);
+                commands.push(
+                    FRAMES
+                        .put(&format!("{i:04}"), &embedded)
+                        .expect("unreachable"),
+                );
+            }
+        }
+        if let Some((stage_start, stage_end)) = stage {
+            commands.push(ImageCommand::anchor("stage.start", Some(stage_start)));
+            commands.push(ImageCommand::anchor("stage.end", Some(stage_end)));
+        }
+
+        let translate = |p: Point, delta: Point| {
+            checked_point(
+                i64::from(p.x) + i64::from(delta.x),
+                i64::from(p.y) + i64::from(delta.y),
+            )
+        };
+        for slice in &self.slices {
+            let start = translate(offset, slice.start).or_fail()?;
+            commands.push(ImageCommand::anchor(
+                format!("{}.start", slice.name),
+                Some(start),
+            ));
+            commands.push(ImageCommand::anchor(
+                format!("{}.end", slice.name),
+                Some(translate(offset, slice.end).or_fail()?),
+            ));
+            if let Some(pivot) = slice.pivot {
+                commands.push(ImageCommand::anchor(
+                    format!("{}.pivot", slice.name),
+                    Some(translate(start, pivot).or_fail()?),
+                ));
+            }
+        }
+        Ok(commands)
+    }
+}
+
+/// Makes a point, failing if the coordinates are out of the range of pati images.
+fn checked_point(x: i64, y: i64) -> orfail::Result<Point> {
+    let convert = |v: i64| {
+        i16::try_from(v).or_fail_with(|_| {
+            format!(
+                "Coordinate {v} is out of range (the image is too large or has too many frames)"
+            )
+        })
+    };
+    Ok(Point::new(convert(x)?, convert(y)?))
+}
+
+#[derive(Debug)]
+struct Layer {
+    flags: u16,
+    layer_type: u16,
+    child_level: u16,
+    opacity: u8,
+}
+
+#[derive(Debug)]
+enum Cel {
+    Image {
+        x: i16,
+        y: i16,
+        opacity: u8,
+        width: u32,
+        height: u32,
+        pixels: Vec<Color>,
+    },
+    Linked(usize),
+}
+
+#[derive(Debug)]
+struct Decoder {
+    depth: u16,
+    layer_opacity_valid: bool,
+    transparent_index: u8,
+    palette: Vec<Color>,
+    layers: Vec<Layer>,
+    cels: BTreeMap<(usize, usize), Cel>,
+}
+
+impl Decoder {
+    fn decode_old_palette(&mut self, r: &mut ByteReader) -> orfail::Result<()> {
+        let mut index = 0;
+        for _ in 0..r.u16().or_fail()? {
+            index += usize::from(r.u8().or_fail()?);
+            let count = match r.u8().or_fail()? {
+                0 => 256,
+                n => usize::from(n),
+            };
+            for _ in 0..count {
+                let c = r.bytes(3).or_fail()?;
+                self.set_palette_color(index, Color::rgb(c[0], c[1], c[2]))
+                    .or_fail()?;
+                index += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_palette(&mut self, r: &mut ByteReader) -> orfail::Result<()> {
+        let _size = r.u32().or_fail()?;
+        let first = r.u32().or_fail()? as usize;
+        let last = r.u32().or_fail()? as usize;
+        r.skip(8).or_fail()?;
+        (first <= last && last < MAX_PALETTE_SIZE)
+            .or_fail_with(|()| format!("Invalid palette index range: {first}..={last}"))?;
+        for index in first..=last {
+            let flags = r.u16().or_fail()?;
+            let c = r.bytes(4).or_fail()?;
+            self.set_palette_color(index, Color::rgba(c[0], c[1], c[2], c[3]))
+                .or_fail()?;
+            if flags & 1 != 0 {
+                r.string().or_fail()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_palette_color(&mut self, index: usize, color: Color) -> orfail::Result<()> {
+        (index < MAX_PALETTE_SIZE)
+            .or_fail_with(|()| format!("Too large palette index: {index}"))?;
+        if self.palette.len() <= index {
+            self.palette.resize(index + 1, Color::rgba(0, 0, 0, 0));
+        }
+        self.palette[index] = color;
+        Ok(())
+    }
+
+    fn decode_layer(&mut self, r: &mut ByteReader) -> orfail::Result<()> {
+        let flags = r.u16().or_fail()?;
+        let layer_type = r.u16().or_fail()?;
+        let child_level = r.u16().or_fail()?;
+        r.skip(2 + 2 + 2).or_fail()?;
+        let opacity = r.u8().or_fail()?;
+        self.layers.push(Layer {
+            flags,
+            layer_type,
+            child_level,
+            opacity: if self.layer_opacity_valid {
+                opacity
+            } else {
+                255
+            },
+        });
+        Ok(())
+    }
+
+    fn decode_cel(&mut self, frame: usize, r: &mut ByteReader) -> orfail::Result<()> {
+        let layer = usize::from(r.u16().or_fail()?);
+        let x = r.i16().or_fail()?;
+        let y = r.i16().or_fail()?;
+        let opacity = r.u8().or_fail()?;
+        let cel_type = r.u16().or_fail()?;
+        r.skip(2 + 5).or_fail()?;
+        let cel = match cel_type {
+            0 | 2 => {
+                let width = u32::from(r.u16().or_fail()?);
+                let height = u32::from(r.u16().or_fail()?);
+                let data = if cel_type == 0 {
+                    r.rest().to_vec()
+                } else {
+                    let mut data = Vec::new();
+                    flate2::read::ZlibDecoder::new(r.rest())
+                        .read_to_end(&mut data)
+                        .or_fail_with(|e| format!("Failed to decompress cel: {e}"))?;
+                    data
+                };
+                let is_background = self
+                    .layers
+                    .get(layer)
+                    .is_some_and(|l| l.flags & LAYER_FLAG_BACKGROUND != 0);
+                let pixels = self.decode_pixels(&data, is_background).or_fail()?;
+                (pixels.len() >= (width * height) as usize)
+                    .or_fail_with(|()| format!("Truncated cel: frame={frame}, layer={layer}"))?;
+                Cel::Image {
+                    x,
+                    y,
+                    opacity,
+                    width,
+                    height,
+                    pixels,
+                }
+            }
+            1 => Cel::Linked(usize::from(r.u16().or_fail()?)),
+            _ => return Ok(()), // Tilemap cels are not supported.
+        };
+        self.cels.insert((frame, layer), cel);
+        Ok(())
+    }
+
+    fn decode_pixels(&self, data: &[u8], is_background: bool) -> orfail::Result<Vec<Color>> {
+        let pixels = match self.depth {
+            32 => data
+                .chunks_exact(4)
+                .map(|c| Color::rgba(c[0], c[1], c[2], c[3]))
+                .collect(),
+            16 => data
+                .chunks_exact(2)
+                .map(|c| Color::rgba(c[0], c[0], c[0], c[1]))
+                .collect(),
+            _ => data
+                .iter()
+                .map(|&i| {
+                    if i == self.transparent_index && !is_background {
+                        Color::rgba(0, 0, 0, 0)
+                    } else {
+                        self.palette
+                            .get(usize::from(i))
+                            .copied()
+                            .unwrap_or(Color::rgba(0, 0, 0, 0))
+                    }
+                })
+                .collect(),
+        };
+        Ok(pixels)
+    }
+
+    fn render(&self, frame: usize, width: u32, height: u32) -> orfail::Result<Bitmap> {
+        let mut bitmap = Bitmap::new(width, height, None).or_fail()?;
+        // Visibility of the ancestor groups (indexed by child level).
+        let mut visible_levels = Vec::<bool>::new();
+        for (index, layer) in self.layers.iter().enumerate() {
+            let level = usize::from(layer.child_level);
+            visible_levels.truncate(level);
+            let parent_visible = visible_levels.iter().all(|&v| v);
+            let visible = parent_visible && layer.flags & LAYER_FLAG_VISIBLE != 0;
+            visible_levels.push(visible);
+            if !visible || layer.layer_type != LAYER_TYPE_NORMAL {
+                continue;
+            }
+
+            let mut cel = self.cels.get(&(frame, index));
+            if let Some(Cel::Linked(linked_frame)) = cel {
+                cel = self.cels.get(&(*linked_frame, index));
+            }
+            let Some(Cel::Image {
+                x,
+                y,
+                opacity,
+                width: cel_width,
+                height: cel_height,
+                pixels,
+            }) = cel
+            else {
+                continue;
+            };
+            let opacity = u32::from(*opacity) * u32::from(layer.opacity) / 255;
+            for cy in 0..*cel_height {
+                for cx in 0..*cel_width {
+                    let mut color = pixels[(cy * cel_width + cx) as usize];
+                    color.a = (u32::from(color.a) * opacity / 255) as u8;
+                    let px = i32::from(*x) + cx as i32;
+                    let py = i32::from(*y) + cy as i32;
+                    if px >= 0 && py >= 0 {
+                        bitmap.blend_pixel(px as u32, py as u32, color);
+                    }
+                }
+            }
+        }
+        Ok(bitmap)
+    }
+}
+
+fn decode_tags(r: &mut ByteReader) -> orfail::Result<Vec<AsepriteTag>> {
+    let count = r.u16().or_fail()?;
+    r.skip(8).or_fail()?;
+    let mut tags = Vec::new();
+    for _ in 0..count {
+        let from = usize::from(r.u16().or_fail()?);
+        let to = usize::from(r.u16().or_fail()?);
+        r.skip(1 + 2 + 6 + 3 + 1).or_fail()?;
+        let name = r.string().or_fail()?;
+        tags.push(AsepriteTag { name, from, to });
+    }
+    Ok(tags)
+}
+
+fn decode_slice(r: &mut ByteReader) -> orfail::Result<Option<AsepriteSlice>> {
+    let key_count = r.u32().or_fail()?;
+    let flags = r.u32().or_fail()?;
+    r.skip(4).or_fail()?;
+    let name = r.string().or_fail()?;
+    let mut slice = None;
+    for _ in 0..key_count {
+        let frame = r.u32().or_fail()?;
+        let x = r.i32().or_fail()?;
+        let y = r.i32().or_fail()?;
+        let w = r.u32().or_fail()?;
+        let h = r.u32().or_fail()?;
+        if flags & 1 != 0 {
+            r.skip(16).or_fail()?;
+        }
+        let pivot = if flags & 2 != 0 {
+            let x = r.i32().or_fail()?;
+            let y = r.i32().or_fail()?;
+            Some(checked_point(i64::from(x), i64::from(y)).or_fail()?)
+        } else {
+            None
+        };
+        if frame == 0 && w > 0 && h > 0 {
+            let (x, y) = (i64::from(x), i64::from(y));
+            slice = Some(AsepriteSlice {
+                name: name.clone(),
+                start: checked_point(x, y).or_fail()?,
+                end: checked_point(x + i64::from(w) - 1, y + i64::from(h) - 1).or_fail()?,
+                pivot,
+            });
+        }
+    }
+    Ok(slice)
+}
+
+#[derive(Debug)]
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn bytes(&mut self, n: usize) -> orfail::Result<&'a [u8]> {
+        (n <= self.bytes.len()).or_fail_with(|()| "Truncated Aseprite file".to_owned())?;
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.bytes)
+    }
+
+    fn skip(&mut self, n: usize) -> orfail::Result<()> {
+        self.bytes(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> orfail::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> orfail::Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn i16(&mut self) -> orfail::Result<i16> {
+        self.u16().map(|v| v as i16)
+    }
+
+    fn u32(&mut self) -> orfail::Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn i32(&mut self) -> orfail::Result<i32> {
+        self.u32().map(|v| v as i32)
+    }
+
+    fn string(&mut self) -> orfail::Result<String> {
+        let len = usize::from(self.u16()?);
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn chunk(chunk_type: u16, data: &[u8]) -> Vec<u8> {
+        let mut bytes = (data.len() as u32 + 6).to_le_bytes().to_vec();
+        bytes.extend(chunk_type.to_le_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    fn frame(duration_ms: u16, chunks: &[Vec<u8>]) -> Vec<u8> {
+        let data = chunks.concat();
+        let mut bytes = (data.len() as u32 + 16).to_le_bytes().to_vec();
+        bytes.extend(FRAME_MAGIC.to_le_bytes());
+        bytes.extend((chunks.len() as u16).to_le_bytes());
+        bytes.extend(duration_ms.to_le_bytes());
+        bytes.extend([0; 2]);
+        bytes.extend((chunks.len() as u32).to_le_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    fn cel(cel_type: u16, width: u16, height: u16, data: &[u8]) -> Vec<u8> {
+        cel_at(0, 0, 255, cel_type, width, height, data)
+    }
+
+    fn cel_at(
+        layer: u16,
+        x: i16,
+        opacity: u8,
+        cel_type: u16,
+        width: u16,
+        height: u16,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(layer.to_le_bytes());
+        bytes.extend(x.to_le_bytes());
+        bytes.extend(0i16.to_le_bytes()); // y
+        bytes.push(opacity);
+        bytes.extend(cel_type.to_le_bytes());
+        bytes.extend([0; 2 + 5]); // z-index, reserved
+        bytes.extend(width.to_le_bytes());
+        bytes.extend(height.to_le_bytes());
+        bytes.extend(data);
+        chunk(CHUNK_CEL, &bytes)
+    }
+
+    fn linked_cel(layer: u16, frame: u16) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(layer.to_le_bytes());
+        bytes.extend([0; 2 + 2]); // x, y
+        bytes.push(255); // opacity
+        bytes.extend(1u16.to_le_bytes());
+        bytes.extend([0; 2 + 5]); // z-index, reserved
+        bytes.extend(frame.to_le_bytes());
+        chunk(CHUNK_CEL, &bytes)
+    }
+
+    fn layer(flags: u16, layer_type: u16, child_level: u16, opacity: u8) -> Vec<u8> {
+        let mut layer = Vec::new();
+        layer.extend(flags.to_le_bytes());
+        layer.extend(layer_type.to_le_bytes());
+        layer.extend(child_level.to_le_bytes());
+        layer.extend([0; 6]); // default size, blend mode
+        layer.push(opacity);
+        layer.extend([0; 3]);
+        layer.extend(5u16.to_le_bytes());
+        layer.extend(b"Layer");
+        chunk(CHUNK_LAYER, &layer)
+    }
+
+    fn palette() -> Vec<u8> {
+        let mut palette = Vec::new();
+        palette.extend(4u32.to_le_bytes()); // size
+        palette.extend(0u32.to_le_bytes()); // first
+        palette.extend(3u32.to_le_bytes()); // last
+        palette.extend([0; 8]);
+        for rgba in [
+            [0, 0, 0, 0],
+            [255, 0, 0, 255],
+            [0, 0, 255, 255],
+            [0, 255, 0, 255],
+        ] {
+            palette.extend(0u16.to_le_bytes());
+            palette.extend(rgba);
+        }
+        chunk(CHUNK_PALETTE, &palette)
+    }
+
+    fn tags(tags: &[(u16, u16, &str)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend((tags.len() as u16).to_le_bytes());
+        bytes.extend([0; 8]);
+        for (from, to, name) in tags {
+            bytes.extend(from.to_le_bytes());
+            bytes.extend(to.to_le_bytes());
+            bytes.extend([0; 1 + 2 + 6 + 3 + 1]); // direction, repeat, reserved, color
+            bytes.extend((name.len() as u16).to_le_bytes());
+            bytes.extend(name.as_bytes());
+        }
+        chunk(CHUNK_TAGS, &bytes)
+    }
+
+    /// Makes a slice chunk with a key at `frame` (`(x, y, width, height)`) and a pivot.
+    fn slice(name: &str, frame: u32, bounds: (i32, i32, u32, u32), pivot: (i32, i32)) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(1u32.to_le_bytes()); // keys
+        bytes.extend(2u32.to_le_bytes()); // flags (has pivot)
+        bytes.extend([0; 4]);
+        bytes.extend((name.len() as u16).to_le_bytes());
+        bytes.extend(name.as_bytes());
+        bytes.extend(frame.to_le_bytes());
+        bytes.extend(bounds.0.to_le_bytes());
+        bytes.extend(bounds.1.to_le_bytes());
+        bytes.extend(bounds.2.to_le_bytes());
+        bytes.extend(bounds.3.to_le_bytes());
+        bytes.extend(pivot.0.to_le_bytes());
+        bytes.extend(pivot.1.to_le_bytes());
+        chunk(CHUNK_SLICE, &bytes)
+    }
+
+    /// Makes an 8-bit indexed file (the transparent index is 0).
+    fn file(width: u16, height: u16, frames: &[Vec<u8>]) -> Vec<u8> {
+        let frame_count = frames.len() as u16;
+        let frames = frames.concat();
+        let mut header = Vec::new();
+        header.extend((128 + frames.len() as u32).to_le_bytes());
+        header.extend(HEADER_MAGIC.to_le_bytes());
+        header.extend(frame_count.to_le_bytes());
+        header.extend(width.to_le_bytes());
+        header.extend(height.to_le_bytes());
+        header.extend(8u16.to_le_bytes()); // depth
+        header.extend(1u32.to_le_bytes()); // flags (layer opacity is valid)
+        header.extend([0; 2 + 4 + 4]);
+        header.push(0); // transparent index
+        header.resize(128, 0);
+        [header, frames].concat()
+    }
+
+    /// Makes a 2x1 indexed image with two frames (a raw cel and a zlib compressed cel).
+    fn fixture() -> Vec<u8> {
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(&[2, 1]).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let frames = [
+            frame(
+                100,
+                &[
+                    palette(),
+                    layer(LAYER_FLAG_VISIBLE, LAYER_TYPE_NORMAL, 0, 255),
+                    cel(0, 2, 1, &[1, 0]),
+                ],
+            ),
+            frame(200, &[cel(2, 2, 1, &compressed)]),
+        ];
+        file(2, 1, &frames)
+    }
+
+    #[test]
+    fn decode_works() {
+        let file = AsepriteFile::decode(&fixture()).unwrap();
+        assert_eq!((file.width, file.height), (2, 1));
+        assert_eq!(file.frames.len(), 2);
+
+        let red = Color::rgb(255, 0, 0);
+        let blue = Color::rgb(0, 0, 255);
+        let frame = &file.frames[0];
+        assert_eq!(frame.duration_ms, 100);
+        assert_eq!(frame.image.get_pixel(0, 0), Some(red));
+        assert_eq!(frame.image.get_pixel(1, 0).map_or(0, |c| c.a), 0);
+
+        let frame = &file.frames[1];
+        assert_eq!(frame.duration_ms, 200);
+        assert_eq!(frame.image.get_pixel(0, 0), Some(blue));
+        assert_eq!(frame.image.get_pixel(1, 0), Some(red));
+    }
+
+    #[test]
+    fn decode_layers_works() {
+        const GROUP: u16 = 1;
+        let visible = LAYER_FLAG_VISIBLE;
+        let frames = [
+            frame(
+                100,
+                &[
+                    palette(),
+                    layer(visible, LAYER_TYPE_NORMAL, 0, 255),
+                    // Hidden layer.
+                    layer(0, LAYER_TYPE_NORMAL, 0, 255),
+                    // Visible layer in a hidden group.
+                    layer(0, GROUP, 0, 255),
+                    layer(visible, LAYER_TYPE_NORMAL, 1, 255),
+                    // Half transparent layer.
+                    layer(visible, LAYER_TYPE_NORMAL, 0, 128),
+                    cel_at(0, 0, 255, 0, 1, 1, &[1]),
+                    cel_at(1, 1, 255, 0, 1, 1, &[3]),
+                    cel_at(3, 1, 255, 0, 1, 1, &[3]),
+                    cel_at(4, 1, 255, 0, 1, 1, &[2]),
+                ],
+            ),
+            // The first layer is linked to the first frame, and the cel of the last layer is half transparent.
+            frame(100, &[linked_cel(0, 0), cel_at(4, 1, 128, 0, 1, 1, &[2])]),
+        ];
+        let file = AsepriteFile::decode(&file(2, 1, &frames)).unwrap();
+        let pixels = |frame: &AsepriteFrame| {
+            [0, 1].map(|x| {
+                frame
+                    .image
+                    .get_pixel(x, 0)
+                    .unwrap_or(Color::rgba(0, 0, 0, 0))
+            })
+        };
+        let red = Color::rgb(255, 0, 0);
+        assert_eq!(pixels(&file.frames[0]), [red, Color::rgba(0, 0, 255, 128)]);
+        assert_eq!(pixels(&file.frames[1]), [red, Color::rgba(0, 0, 255, 64)]);
+    }
+
+    #[test]
+    fn decode_tags_and_slices_works() {
+        let frames = [
+            frame(
+                100,
+                &[
+                    palette(),
+                    layer(LAYER_FLAG_VISIBLE, LAYER_TYPE_NORMAL, 0, 255),
+                    tags(&[(0, 1, "walk"), (1, 1, "last")]),
+                    slice("hitbox", 0, (1, 0, 1, 1), (0, 0)),
+                    // Slices without a key at the first frame are ignored.
+                    slice("later", 1, (0, 0, 1, 1), (0, 0)),
+                ],
+            ),
+            frame(100, &[]),
+        ];
+        let file = AsepriteFile::decode(&file(2, 1, &frames)).unwrap();
+        let tags = file
+            .tags
+            .iter()
+            .map(|t| (t.name.as_str(), t.from, t.to))
+            .collect::<Vec<_>>();
+        assert_eq!(tags, [("walk", 0, 1), ("last", 1, 1)]);
+        assert_eq!(file.slices.len(), 1);
+        assert_eq!(file.slices[0].name, "hitbox");
+        assert_eq!(
+            (
+                file.slices[0].start,
+                file.slices[0].end,
+                file.slices[0].pivot
+            ),
+            (Point::new(1, 0), Point::new(1, 0), Some(Point::new(0, 0)))
+        );
+
+        let fps = NonZeroU8::new(30).unwrap();
+        let commands = file
+            .to_commands(Point::new(10, 20), PathBuf::from("a.pati"), fps)
+            .unwrap();
+        let mut image = pati::VersionedImage::new();
+        for command in &commands {
+            image.apply(command);
+        }
+        assert_eq!(image.slices()["frame.0"].tags, ["walk"]);
+        assert_eq!(image.slices()["frame.1"].tags, ["walk", "last"]);
+        assert_eq!(image.anchors()["hitbox.start"], Point::new(11, 20));
+        assert_eq!(image.anchors()["hitbox.end"], Point::new(11, 20));
+        assert_eq!(image.anchors()["hitbox.pivot"], Point::new(11, 20));
+        assert_eq!(image.anchors()["stage.start"], Point::new(10, 22));
+    }
+
+    #[test]
+    fn decode_rejects_oversized_palettes() {
+        let layer = layer(LAYER_FLAG_VISIBLE, LAYER_TYPE_NORMAL, 0, 255);
+        let palette = |first: u32, last: u32| {
+            let mut bytes = Vec::new();
+            bytes.extend(1u32.to_le_bytes()); // size
+            bytes.extend(first.to_le_bytes());
+            bytes.extend(last.to_le_bytes());
+            bytes.extend([0; 8]);
+            bytes.extend(0u16.to_le_bytes());
+            bytes.extend([255, 0, 0, 255]);
+            chunk(CHUNK_PALETTE, &bytes)
+        };
+        let frames = [frame(100, &[palette(255, 255), layer.clone()])];
+        assert!(AsepriteFile::decode(&file(1, 1, &frames)).is_ok());
+        for (first, last) in [(0xFFFF_FFF0, 0xFFFF_FFF0), (0, 256), (3, 2)] {
+            let frames = [frame(100, &[palette(first, last), layer.clone()])];
+            assert!(AsepriteFile::decode(&file(1, 1, &frames)).is_err());
+        }
+
+        // The indices of the old palette chunk run past 255.
+        let old_palette = |skips: [u8; 2]| {
+            let mut bytes = Vec::new();
+            bytes.extend(2u16.to_le_bytes()); // packets
+            for skip in skips {
+                bytes.extend([skip, 1]);
+                bytes.extend([255, 0, 0]);
+            }
+            chunk(CHUNK_OLD_PALETTE, &bytes)
+        };
+        let frames = [frame(100, &[old_palette([254, 0]), layer.clone()])];
+        assert!(AsepriteFile::decode(&file(1, 1, &frames)).is_ok());
+        let frames = [frame(100, &[old_palette([255, 0]), layer])];
+        assert!(AsepriteFile::decode(&file(1, 1, &frames)).is_err());
+    }
+
+    #[test]
+    fn to_commands_fails_on_coordinate_overflow() {
+        let file = AsepriteFile::decode(&fixture()).unwrap();
+        let fps = NonZeroU8::new(30).unwrap();
+        let path = PathBuf::from("a.pati");
+        assert!(file
+            .to_commands(Point::new(0, 0), path.clone(), fps)
+            .is_ok());
+        assert!(file
+            .to_commands(Point::new(i16::MAX - 3, 0), path, fps)
+            .is_err());
+    }
+}
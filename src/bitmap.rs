@@ -0,0 +1,164 @@
+use orfail::OrFail;
+use pati::{Color, Point};
+use std::num::NonZeroU32;
+
+const TRANSPARENT: Color = Color::rgba(0, 0, 0, 0);
+
+/// Maximum number of the pixels of a bitmap (1 GiB of RGBA pixels).
+pub const MAX_PIXELS: u32 = 1 << 28;
+
+/// Raster of a rectangular region of an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitmap {
+    width: u32,
+    height: u32,
+    pixels: Vec<Color>,
+}
+
+impl Bitmap {
+    /// Makes a bitmap filled with `background` (or transparent).
+    ///
+    /// Fails if the number of the pixels exceeds [`MAX_PIXELS`].
+    pub fn new(width: u32, height: u32, background: Option<Color>) -> orfail::Result<Self> {
+        let len = width
+            .checked_mul(height)
+            .filter(|&len| len <= MAX_PIXELS)
+            .or_fail_with(|()| {
+                format!("Too large bitmap: {width}x{height} (up to {MAX_PIXELS} pixels)")
+            })?;
+        Ok(Self {
+            width,
+            height,
+            pixels: vec![background.unwrap_or(TRANSPARENT); len as usize],
+        })
+    }
+
+    /// Makes a bitmap of the region from `start` to `end` (inclusive).
+    pub fn from_pixels(
+        start: Point,
+        end: Point,
+        pixels: impl Iterator<Item = (Point, Color)>,
+        background: Option<Color>,
+    ) -> orfail::Result<Self> {
+        let width = (end.x as i32 - start.x as i32 + 1).max(0) as u32;
+        let height = (end.y as i32 - start.y as i32 + 1).max(0) as u32;
+        let mut this = Self::new(width, height, background).or_fail()?;
+        for (point, color) in pixels {
+            let x = point.x as i32 - start.x as i32;
+            let y = point.y as i32 - start.y as i32;
+            if x < 0 || y < 0 {
+                continue;
+            }
+            this.blend_pixel(x as u32, y as u32, color);
+        }
+        Ok(this)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[Color] {
+        &self.pixels
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> Option<Color> {
+        (x < self.width && y < self.height).then(|| self.pixels[(y * self.width + x) as usize])
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, color: Color) {
+        if x < self.width && y < self.height {
+            self.pixels[(y * self.width + x) as usize] = color;
+        }
+    }
+
+    /// Draws the given color over the pixel at the given position using alpha blending.
+    pub fn blend_pixel(&mut self, x: u32, y: u32, color: Color) {
+        if let Some(dst) = self.get_pixel(x, y) {
+            self.set_pixel(x, y, blend(dst, color));
+        }
+    }
+
+    /// Copies the given bitmap into this bitmap with its top-left corner at the given position.
+    pub fn copy_from(&mut self, x: u32, y: u32, src: &Bitmap) {
+        for sy in 0..src.height {
+            for sx in 0..src.width {
+                let color = src.pixels[(sy * src.width + sx) as usize];
+                self.set_pixel(x + sx, y + sy, color);
+            }
+        }
+    }
+
+    /// Makes a bitmap enlarged by the given integer factor (nearest neighbor).
+    pub fn scale(&self, factor: NonZeroU32) -> orfail::Result<Self> {
+        let n = factor.get();
+        let (width, height) = self.scaled_size(n).or_fail()?;
+        let mut scaled = Self::new(width, height, None).or_fail()?;
+        for y in 0..scaled.height {
+            for x in 0..scaled.width {
+                let i = ((y / n) * self.width + x / n) as usize;
+                scaled.pixels[(y * scaled.width + x) as usize] = self.pixels[i];
+            }
+        }
+        Ok(scaled)
+    }
+
+    /// Returns the size of this bitmap enlarged by `n`.
+    pub fn scaled_size(&self, n: u32) -> orfail::Result<(u32, u32)> {
+        let scale = |v: u32| {
+            v.checked_mul(n)
+                .or_fail_with(|()| format!("Too large scale factor: {n}"))
+        };
+        Ok((scale(self.width).or_fail()?, scale(self.height).or_fail()?))
+    }
+
+    pub fn to_rgba_bytes(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|c| [c.r, c.g, c.b, c.a])
+            .collect()
+    }
+}
+
+fn blend(dst: Color, src: Color) -> Color {
+    match (dst.a, src.a) {
+        (_, 255) | (0, _) => src,
+        (_, 0) => dst,
+        _ => {
+            let sa = src.a as u32;
+            let da = dst.a as u32 * (255 - sa) / 255;
+            let a = sa + da;
+            let mix = |s: u8, d: u8| ((s as u32 * sa + d as u32 * da) / a) as u8;
+            Color::rgba(
+                mix(src.r, dst.r),
+                mix(src.g, dst.g),
+                mix(src.b, dst.b),
+                a as u8,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn too_large_bitmaps_are_rejected() {
+        assert!(Bitmap::new(u32::MAX, 2, None).is_err());
+        assert!(Bitmap::new(0x10000, 0x10000, None).is_err());
+        assert!(Bitmap::new(MAX_PIXELS / 2, 3, None).is_err());
+        assert!(Bitmap::new(MAX_PIXELS / 1024 + 1, 1024, None).is_err());
+        assert!(Bitmap::new(MAX_PIXELS, 0, None).is_ok());
+
+        let bitmap = Bitmap::new(2, 1, None).expect("bitmap");
+        assert!(bitmap.scale(NonZeroU32::MAX).is_err());
+        assert!(bitmap.scale(NonZeroU32::new(0x4000).unwrap()).is_err());
+        let scaled = bitmap.scale(NonZeroU32::new(3).unwrap()).expect("scale");
+        assert_eq!((scaled.width(), scaled.height()), (6, 3));
+    }
+}
@@ -1,11 +1,37 @@
-use crate::{game::Game, model::Model};
+use crate::{
+    ansi::ColorMode,
+    aseprite::AsepriteFile,
+    bitmap::Bitmap,
+    clock::{Ticks, Time},
+    frame::{self, EmbeddedFrame, FRAMES},
+    game::Game,
+    graphics::GraphicsProtocol,
+    model::Model,
+    palette::{Palette, PaletteFormat, PALETTE_SLICE},
+    sheet::{Layout, Sheet, Sprite, Tag},
+    tiled::Tileset,
+    upscale::Upscaler,
+};
 use orfail::OrFail;
 use pagurus::Game as _;
 use pagurus_tui::{TuiSystem, TuiSystemOptions};
-use paticanvas::{CanvasAgentRequest, CanvasAgentServer, CanvasFile};
-use std::path::PathBuf;
+use pati::{Color, ImageCommand, ImageCommandReader, Point, ReplayStep, Version, VersionedImage};
+use paticanvas::{
+    Canvas, CanvasAgent, CanvasAgentAddr, CanvasAgentServer, CanvasCommand, CanvasFile,
+    CanvasQuery, Dither, ImportedImage, BACKGROUND_COLOR, SESSION,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{BufReader, BufWriter, Write},
+    num::{NonZeroU32, NonZeroU8},
+    ops::Range,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 const ENV_PATICA_PORT: &str = "PATICA_PORT";
+const ENV_PATICA_SOCKET: &str = "PATICA_SOCKET";
 
 // use crate::{
 //     clock::Ticks,
@@ -29,12 +55,22 @@ const ENV_PATICA_PORT: &str = "PATICA_PORT";
 #[clap(version, about)]
 pub enum Args {
     Open(OpenCommand),
-    // Apply(ApplyCommand), // TODO: Rename to Command
+    Serve(ServeCommand),
+    Timelapse(TimelapseCommand),
+    Blame(BlameCommand),
+    Cat(CatCommand),
+    Export(ExportCommand),
+    ExportAnimation(ExportAnimationCommand),
+    ExportSheet(ExportSheetCommand),
+    ExportTiled(ExportTiledCommand),
+    Import(ImportCommand),
+    #[clap(subcommand)]
+    Palette(PaletteCommand),
+    Command(CommandCommand),
+    Query(QueryCommand),
+    Subscribe(SubscribeCommand),
     // Include(IncludeCommand),
     // Embed(EmbedCommand),
-    // Export(ExportCommand),
-    // #[clap(subcommand)]
-    // Get(GetCommand), // TODO: Rename to Query
 }
 
 impl Args {
@@ -45,11 +81,21 @@ impl Args {
                 println!();
                 e
             }),
-            // Self::Apply(cmd) => cmd.run().or_fail(),
+            Self::Serve(cmd) => cmd.run().or_fail(),
+            Self::Timelapse(cmd) => cmd.run().or_fail(),
+            Self::Blame(cmd) => cmd.run().or_fail(),
+            Self::Cat(cmd) => cmd.run().or_fail(),
+            Self::Export(cmd) => cmd.run().or_fail(),
+            Self::ExportAnimation(cmd) => cmd.run().or_fail(),
+            Self::ExportSheet(cmd) => cmd.run().or_fail(),
+            Self::ExportTiled(cmd) => cmd.run().or_fail(),
+            Self::Import(cmd) => cmd.run().or_fail(),
+            Self::Palette(cmd) => cmd.run().or_fail(),
+            Self::Command(cmd) => cmd.run().or_fail(),
+            Self::Query(cmd) => cmd.run().or_fail(),
+            Self::Subscribe(cmd) => cmd.run().or_fail(),
             // Self::Include(cmd) => cmd.run().or_fail(),
             // Self::Embed(cmd) => cmd.run().or_fail(),
-            // Self::Export(cmd) => cmd.run().or_fail(),
-            // Self::Get(cmd) => cmd.run().or_fail(),
         }
     }
 }
@@ -63,10 +109,17 @@ pub struct OpenCommand {
 impl OpenCommand {
     fn run(&self) -> orfail::Result<()> {
         let canvas_file = CanvasFile::open(&self.path, true).or_fail()?;
+        warn_checksum_mismatches(&self.path, canvas_file.checksum_mismatches());
         let mut game = Game::new(Model::new(canvas_file));
+        game.model_mut().file_mut().set_session(session_id());
 
-        let mut agent_server = CanvasAgentServer::start().or_fail()?;
-        std::env::set_var(ENV_PATICA_PORT, agent_server.port().to_string());
+        let mut agent_server = start_agent_server(&self.path, None).or_fail()?;
+        agent_server.publish(game.model().canvas()).or_fail()?;
+        match agent_server.addr() {
+            CanvasAgentAddr::Port(port) => std::env::set_var(ENV_PATICA_PORT, port.to_string()),
+            #[cfg(unix)]
+            CanvasAgentAddr::Socket(path) => std::env::set_var(ENV_PATICA_SOCKET, path),
+        }
 
         let options = TuiSystemOptions {
             disable_mouse: true,
@@ -78,35 +131,1330 @@ impl OpenCommand {
             if !game.handle_event(&mut system, event).or_fail()? {
                 break;
             }
-            self.poll_and_handle_request(&mut game, &mut agent_server)
+            for (from, frame) in agent_server.poll_requests() {
+                agent_server.handle_request(game.model_mut().file_mut(), from, frame);
+            }
+            agent_server.publish(game.model().canvas()).or_fail()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Serve agent requests (`patica command` / `patica query`) for a pati file without the UI
+///
+/// Requests of all connected clients are handled as they arrive.
+/// The server exits when it receives the `quit` command.
+#[derive(Debug, clap::Args)]
+pub struct ServeCommand {
+    path: PathBuf,
+
+    /// Port to listen on (default: the Unix domain socket of the file)
+    #[clap(long)]
+    port: Option<u16>,
+}
+
+impl ServeCommand {
+    fn run(&self) -> orfail::Result<()> {
+        let mut file = CanvasFile::open(&self.path, true).or_fail()?;
+        warn_checksum_mismatches(&self.path, file.checksum_mismatches());
+        file.set_session(session_id());
+
+        let mut server = start_agent_server(&self.path, self.port).or_fail()?;
+        server.publish(file.canvas()).or_fail()?;
+        match server.addr() {
+            CanvasAgentAddr::Port(port) => println!("{port}"),
+            #[cfg(unix)]
+            CanvasAgentAddr::Socket(path) => println!("{}", path.display()),
+        }
+        std::io::stdout().flush().or_fail()?;
+
+        while !file.canvas().quit() {
+            let (from, frame) = server.wait_request().or_fail()?;
+            server.handle_request(&mut file, from, frame);
+            server.publish(file.canvas()).or_fail()?;
+        }
+        Ok(())
+    }
+}
+
+/// Starts the agent server of the given file (on the Unix domain socket of the file unless `port` is specified).
+fn start_agent_server(path: &Path, port: Option<u16>) -> orfail::Result<CanvasAgentServer> {
+    #[cfg(unix)]
+    if port.is_none() {
+        return CanvasAgentServer::start_unix(path).or_fail();
+    }
+    let mut server = CanvasAgentServer::start_on(port.unwrap_or(0)).or_fail()?;
+    server.register(path).or_fail()?;
+    Ok(server)
+}
+
+/// Render the editing history of an image as an animated GIF or a PNG sequence
+#[derive(Debug, clap::Args)]
+pub struct TimelapseCommand {
+    path: PathBuf,
+
+    /// Output path (`*.gif` for an animated GIF, otherwise a directory to write PNG files into)
+    #[clap(short, long)]
+    output: PathBuf,
+
+    /// Render a frame every N commands
+    #[clap(long, default_value = "1")]
+    every: NonZeroU32,
+
+    /// Render a frame every action group (a run of commands ending with a pixel change)
+    #[clap(long, conflicts_with = "every")]
+    groups: bool,
+
+    /// Frames per second of the animated GIF
+    #[clap(long, default_value = "10")]
+    fps: NonZeroU8,
+
+    /// Integer upscaling factor
+    #[clap(long, default_value = "1")]
+    scale: NonZeroU32,
+}
+
+impl TimelapseCommand {
+    fn run(&self) -> orfail::Result<()> {
+        let image = load_image(&self.path).or_fail()?;
+        let drawn_points = image
+            .applied_commands(Version::default())
+            .iter()
+            .filter_map(|command| match command {
+                ImageCommand::Patch(c) => Some(c.entries()),
+                _ => None,
+            })
+            .flatten()
+            .filter(|entry| entry.color.is_some())
+            .flat_map(|entry| entry.points.iter().copied());
+        let (start, end) = Point::bounding_box(drawn_points)
+            .or_fail_with(|()| format!("No pixels have been drawn in {}", self.path.display()))?;
+
+        let step = if self.groups {
+            ReplayStep::ActionGroups
+        } else {
+            ReplayStep::Commands(self.every)
+        };
+        let mut replay = image.replay(Version::default(), step);
+        let frames = std::iter::from_fn(|| {
+            let (version, image) = replay.step()?;
+            let background = BACKGROUND_COLOR.get(image.metadata()).ok();
+            let bitmap = Bitmap::from_pixels(
+                start,
+                end,
+                image.pixels().iter().map(|(p, c)| (*p, *c)),
+                background,
+            )
+            .and_then(|bitmap| bitmap.scale(self.scale));
+            Some(bitmap.map(|bitmap| (version, bitmap)))
+        });
+
+        if self.output.extension().is_some_and(|ext| ext == "gif") {
+            let file = std::fs::File::create(&self.output).or_fail()?;
+            let delay = Duration::from_secs(1) / u32::from(self.fps.get());
+            crate::gif::write_animation(
+                BufWriter::new(file),
+                frames.map(|frame| frame.map(|(_, bitmap)| (bitmap, delay))),
+                0,
+            )
+            .or_fail()?;
+        } else {
+            std::fs::create_dir_all(&self.output).or_fail()?;
+            for frame in frames {
+                let (version, bitmap) = frame.or_fail()?;
+                let path = self.output.join(format!("{:06}.png", version.get()));
+                let file = std::fs::File::create(&path).or_fail()?;
+                crate::png::write_image(BufWriter::new(file), &bitmap).or_fail()?;
+            }
+        }
+        println!("Rendered to {}", self.output.display());
+        Ok(())
+    }
+}
+
+/// Show the command that last drew or erased the pixel at the given point
+#[derive(Debug, clap::Args)]
+pub struct BlameCommand {
+    path: PathBuf,
+
+    #[clap(allow_hyphen_values = true)]
+    x: i16,
+
+    #[clap(allow_hyphen_values = true)]
+    y: i16,
+
+    /// Show the blame map of the region of this width starting at the given point
+    #[clap(long, default_value_t = 1)]
+    width: u16,
+
+    /// Show the blame map of the region of this height starting at the given point
+    #[clap(long, default_value_t = 1)]
+    height: u16,
+}
+
+impl BlameCommand {
+    fn run(&self) -> orfail::Result<()> {
+        let image = load_image(&self.path).or_fail()?;
+        let start = Point::new(self.x, self.y);
+        if self.width == 1 && self.height == 1 {
+            let Some((version, command)) = image.blame(start) else {
+                print_json(serde_json::Value::Null).or_fail()?;
+                return Ok(());
+            };
+            let session = image
+                .restore(version)
+                .map(|image| SESSION.get(image.metadata()))
+                .transpose()
+                .or_fail()?
+                .flatten();
+            print_json(serde_json::json!({
+                "version": version,
+                "command": command,
+                "session": session,
+            }))
+            .or_fail()?;
+        } else {
+            let extent = |length: u16| {
+                i16::try_from(length.max(1) - 1)
+                    .or_fail_with(|e| format!("Too large region size {length}: {e}"))
+            };
+            let end = Point::new(
+                self.x.saturating_add(extent(self.width)?),
+                self.y.saturating_add(extent(self.height)?),
+            );
+            let blame = image
+                .blame_range(start..=end)
+                .into_iter()
+                .map(|(point, version)| serde_json::json!({"point": point, "version": version}))
+                .collect::<Vec<_>>();
+            print_json(blame).or_fail()?;
+        }
+        Ok(())
+    }
+}
+
+/// Print an image to stdout using ANSI escape sequences
+#[derive(Debug, clap::Args)]
+pub struct CatCommand {
+    path: PathBuf,
+
+    #[clap(flatten)]
+    region: RegionArgs,
+
+    /// Integer upscaling factor
+    #[clap(long, default_value = "1")]
+    scale: NonZeroU32,
+
+    /// Fill the background with the canvas background color
+    #[clap(long)]
+    background: bool,
+
+    /// Color mode of the `cells` output (default: `truecolor` if `$COLORTERM` is `truecolor` or `24bit`, otherwise `ansi256`)
+    #[clap(long, value_enum)]
+    color: Option<ColorMode>,
+
+    /// Graphics protocol (default: detected from the terminal, falling back to `cells`)
+    #[clap(long, value_enum)]
+    graphics: Option<GraphicsProtocol>,
+
+    /// Pixel-art upscaling filter applied before `--scale`
+    #[clap(long, value_enum)]
+    filter: Option<Upscaler>,
+}
+
+impl CatCommand {
+    fn run(&self) -> orfail::Result<()> {
+        let image = load_image(&self.path).or_fail()?;
+        let (start, end) = self.region.resolve(&image).or_fail()?;
+        let background = if self.background {
+            Some(BACKGROUND_COLOR.get(image.metadata()).or_fail()?)
+        } else {
+            None
+        };
+        let mut bitmap =
+            Bitmap::from_pixels(start, end, image.range_pixels(start..=end), background)
                 .or_fail()?;
+        if let Some(filter) = self.filter {
+            bitmap = filter.apply(&bitmap).or_fail()?;
         }
+        let bitmap = bitmap.scale(self.scale).or_fail()?;
+        let stdout = std::io::stdout();
+        let mut stdout = BufWriter::new(stdout.lock());
+        match self.graphics.unwrap_or_else(GraphicsProtocol::detect) {
+            GraphicsProtocol::Cells => {
+                let mode = self.color.unwrap_or_else(ColorMode::detect);
+                crate::ansi::write_image(&mut stdout, &bitmap, mode).or_fail()?;
+            }
+            protocol => protocol.write_image(&mut stdout, &bitmap).or_fail()?,
+        }
+        stdout.flush().or_fail()?;
+        Ok(())
+    }
+}
+
+/// Export an image to a PNG or SVG file
+#[derive(Debug, clap::Args)]
+pub struct ExportCommand {
+    path: PathBuf,
+
+    /// Output file path (`.svg` for SVG, otherwise PNG; default: the input path with `.png` extension)
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+
+    #[clap(flatten)]
+    region: RegionArgs,
+
+    /// Integer upscaling factor
+    #[clap(long, default_value = "1")]
+    scale: NonZeroU32,
+
+    /// Fill the background with the canvas background color
+    #[clap(long)]
+    background: bool,
+
+    /// Pixel-art upscaling filter applied before `--scale` (PNG only)
+    #[clap(long, value_enum)]
+    filter: Option<Upscaler>,
+}
+
+impl ExportCommand {
+    fn run(&self) -> orfail::Result<()> {
+        let output = self
+            .output
+            .clone()
+            .unwrap_or_else(|| self.path.with_extension("png"));
+        let image = load_image(&self.path).or_fail()?;
+        let (start, end) = self.region.resolve(&image).or_fail()?;
+        let background = if self.background {
+            Some(BACKGROUND_COLOR.get(image.metadata()).or_fail()?)
+        } else {
+            None
+        };
+        let pixels = || image.range_pixels(start..=end);
+        let bitmap = if output.extension().is_some_and(|ext| ext == "svg") {
+            self.filter
+                .is_none()
+                .or_fail_with(|()| "`--filter` is not supported for SVG output".to_owned())?;
+            None
+        } else {
+            let mut bitmap = Bitmap::from_pixels(start, end, pixels(), background).or_fail()?;
+            if let Some(filter) = self.filter {
+                bitmap = filter.apply(&bitmap).or_fail()?;
+            }
+            Some(bitmap.scale(self.scale).or_fail()?)
+        };
 
+        let file = std::fs::File::create(&output)
+            .or_fail_with(|e| format!("Failed to create file {}: {e}", output.display()))?;
+        let writer = BufWriter::new(file);
+        if let Some(bitmap) = bitmap {
+            crate::png::write_image(writer, &bitmap).or_fail()?;
+        } else {
+            crate::svg::write_image(writer, start, end, pixels(), self.scale, background)
+                .or_fail()?;
+        }
+        println!("Exported to {}", output.display());
         Ok(())
     }
+}
 
-    fn poll_and_handle_request(
-        &self,
-        game: &mut Game,
-        server: &mut CanvasAgentServer,
-    ) -> orfail::Result<()> {
-        let Some((from, request)) = server.poll_request().or_fail()? else {
-            return Ok(());
+/// Export the frame timeline of an image to an animated GIF or PNG (APNG) file
+#[derive(Debug, clap::Args)]
+pub struct ExportAnimationCommand {
+    path: PathBuf,
+
+    /// Output file path (`.gif` for GIF, otherwise APNG)
+    #[clap(short, long)]
+    output: PathBuf,
+
+    #[clap(flatten)]
+    region: RegionArgs,
+
+    /// Frames per second of the frame timeline
+    #[clap(long, default_value_t = NonZeroU8::new(Time::DEFAULT_FPS).expect("unreachable"))]
+    fps: NonZeroU8,
+
+    /// Number of ticks to export (default: the end ticks of the last frame)
+    #[clap(long)]
+    duration: Option<u32>,
+
+    /// Number of times the animation is played (0 means infinite)
+    #[clap(long, default_value_t = 0)]
+    loop_count: u16,
+
+    /// Integer upscaling factor
+    #[clap(long, default_value = "1")]
+    scale: NonZeroU32,
+
+    /// Fill the background with the canvas background color
+    #[clap(long)]
+    background: bool,
+}
+
+impl ExportAnimationCommand {
+    fn run(&self) -> orfail::Result<()> {
+        let image = load_image(&self.path).or_fail()?;
+        let frames = load_frames(&self.path, &image).or_fail()?;
+        let duration = self
+            .duration
+            .map(Ticks::new)
+            .unwrap_or_else(|| frame::end_ticks(&frames));
+        (duration.get() > 0).or_fail_with(|()| {
+            "Empty timeline: no frames are embedded and `--duration` is not specified".to_owned()
+        })?;
+
+        let background = if self.background {
+            Some(BACKGROUND_COLOR.get(image.metadata()).or_fail()?)
+        } else {
+            None
+        };
+        let bitmaps = render_timeline(&image, &frames, duration, &self.region, background)
+            .or_fail()?
+            .into_iter()
+            .map(|(bitmap, ticks)| {
+                let duration = Time::new(Ticks::new(ticks.len() as u32), self.fps).duration();
+                Ok((bitmap.scale(self.scale).or_fail()?, duration))
+            })
+            .collect::<orfail::Result<Vec<_>>>()?;
+
+        let file = std::fs::File::create(&self.output)
+            .or_fail_with(|e| format!("Failed to create file {}: {e}", self.output.display()))?;
+        let writer = BufWriter::new(file);
+        if self.output.extension().is_some_and(|ext| ext == "gif") {
+            crate::gif::write_animation(writer, bitmaps.iter().cloned().map(Ok), self.loop_count)
+                .or_fail()?;
+        } else {
+            crate::png::write_animation(writer, &bitmaps, self.loop_count).or_fail()?;
+        }
+        println!(
+            "Exported {} ticks ({} frames) to {}",
+            duration.get(),
+            bitmaps.len(),
+            self.output.display()
+        );
+        Ok(())
+    }
+}
+
+/// Export regions or animation frames of an image as a sprite sheet with a JSON atlas
+///
+/// The atlas uses the Aseprite / TexturePacker JSON hash format.
+#[derive(Debug, clap::Args)]
+pub struct ExportSheetCommand {
+    path: PathBuf,
+
+    /// Output PNG file path
+    #[clap(short, long)]
+    output: PathBuf,
+
+    /// Output atlas JSON file path (default: the output path with `.json` extension)
+    #[clap(long)]
+    atlas: Option<PathBuf>,
+
+    /// Anchor-delimited region to add as a sprite (e.g. `--region walk.start:walk.end`)
+    #[clap(long, value_name = "START_ANCHOR:END_ANCHOR")]
+    region: Vec<String>,
+
+    /// Slice to add as a sprite (tags of the slices become atlas tags, so slices are ordered by their tags)
+    #[clap(long)]
+    slice: Vec<String>,
+
+    /// Add all slices as sprites
+    #[clap(long, conflicts_with = "slice")]
+    all_slices: bool,
+
+    /// Add each distinct tick of the frame timeline as a sprite (frame names become atlas tags)
+    #[clap(long)]
+    frames: bool,
+
+    /// Frames per second of the frame timeline
+    #[clap(long, default_value_t = NonZeroU8::new(Time::DEFAULT_FPS).expect("unreachable"))]
+    fps: NonZeroU8,
+
+    /// Duration (in milliseconds) of sprites that have no duration
+    ///
+    /// The duration of a slice sprite can be specified by the `duration` property of the slice.
+    #[clap(long, default_value_t = 100)]
+    default_duration: u32,
+
+    #[clap(long, value_enum, default_value_t = Layout::Grid)]
+    layout: Layout,
+
+    /// Number of columns of the grid layout (default: square-ish)
+    #[clap(long)]
+    columns: Option<u32>,
+
+    /// Space between sprites
+    #[clap(long, default_value_t = 0)]
+    padding: u32,
+
+    /// Integer upscaling factor
+    #[clap(long, default_value = "1")]
+    scale: NonZeroU32,
+}
+
+impl ExportSheetCommand {
+    fn run(&self) -> orfail::Result<()> {
+        let image = load_image(&self.path).or_fail()?;
+        let mut sprites = Vec::new();
+        let mut tags = Vec::new();
+
+        for region in &self.region {
+            let (start_anchor, end_anchor) = region
+                .split_once(':')
+                .or_fail_with(|()| format!("Invalid region: {region:?}"))?;
+            let region_args = RegionArgs {
+                start_anchor: Some(start_anchor.to_owned()),
+                end_anchor: Some(end_anchor.to_owned()),
+                slice: None,
+            };
+            let (start, end) = region_args.resolve(&image).or_fail()?;
+            sprites.push(Sprite {
+                name: region.clone(),
+                bitmap: Bitmap::from_pixels(start, end, image.range_pixels(start..=end), None)
+                    .or_fail()?,
+                duration_ms: self.default_duration,
+            });
+        }
+
+        // Slices are ordered by their tags so that the slices of each tag are contiguous in the sheet.
+        let mut slices = image
+            .slices()
+            .iter()
+            .filter(|(name, _)| self.all_slices || self.slice.contains(name))
+            .map(|(name, slice)| {
+                let tags = slice.tags.iter().collect::<BTreeSet<_>>();
+                (tags, name, slice)
+            })
+            .collect::<Vec<_>>();
+        slices.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+        let mut slice_tags = BTreeMap::<&str, Vec<usize>>::new();
+        for (_, name, slice) in slices {
+            for tag in &slice.tags {
+                slice_tags.entry(tag).or_default().push(sprites.len());
+            }
+            let duration_ms = slice
+                .properties
+                .get("duration")
+                .and_then(|v| v.as_u64())
+                .map_or(self.default_duration, |v| v as u32);
+            sprites.push(Sprite {
+                name: name.clone(),
+                bitmap: Bitmap::from_pixels(
+                    slice.start,
+                    slice.end,
+                    image.range_pixels(slice.start..=slice.end),
+                    None,
+                )
+                .or_fail()?,
+                duration_ms,
+            });
+        }
+        for name in &self.slice {
+            image
+                .slices()
+                .contains_key(name)
+                .or_fail_with(|()| format!("No such slice: {name}"))?;
+        }
+        for (name, indices) in slice_tags {
+            tags.push(Tag::from_indices(name, &indices).or_fail()?);
+        }
+
+        if self.frames {
+            let frames = load_frames(&self.path, &image).or_fail()?;
+            let end_ticks = frame::end_ticks(&frames);
+            let offset = sprites.len();
+            let timeline =
+                render_timeline(&image, &frames, end_ticks, &RegionArgs::default(), None)
+                    .or_fail()?;
+            for frame in &frames {
+                let visible = |ticks: &Range<u32>| {
+                    ticks.start < frame.frame.end_ticks.get()
+                        && frame.frame.start_ticks.get() < ticks.end
+                };
+                let from = timeline.iter().position(|(_, t)| visible(t));
+                let to = timeline.iter().rposition(|(_, t)| visible(t));
+                if let (Some(from), Some(to)) = (from, to) {
+                    tags.push(Tag::new(&frame.frame.name, offset + from, offset + to));
+                }
+            }
+            for (i, (bitmap, ticks)) in timeline.into_iter().enumerate() {
+                sprites.push(Sprite {
+                    name: format!("frame.{i}"),
+                    bitmap,
+                    duration_ms: Time::new(Ticks::new(ticks.len() as u32), self.fps)
+                        .duration()
+                        .as_millis() as u32,
+                });
+            }
+        }
+        (!sprites.is_empty()).or_fail_with(|()| {
+            "No sprites: specify `--region`, `--slice`, `--all-slices` or `--frames`".to_owned()
+        })?;
+
+        for sprite in &mut sprites {
+            sprite.bitmap = sprite.bitmap.scale(self.scale).or_fail()?;
+        }
+        let sheet = Sheet::pack(&sprites, self.layout, self.columns, self.padding).or_fail()?;
+
+        let file = std::fs::File::create(&self.output)
+            .or_fail_with(|e| format!("Failed to create file {}: {e}", self.output.display()))?;
+        crate::png::write_image(BufWriter::new(file), &sheet.bitmap).or_fail()?;
+
+        let atlas_path = self
+            .atlas
+            .clone()
+            .unwrap_or_else(|| self.output.with_extension("json"));
+        let image_name = self
+            .output
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let atlas = sheet.atlas(&sprites, &image_name, tags).or_fail()?;
+        let file = std::fs::File::create(&atlas_path)
+            .or_fail_with(|e| format!("Failed to create file {}: {e}", atlas_path.display()))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &atlas).or_fail()?;
+
+        println!(
+            "Exported {} sprites to {} and {}",
+            sprites.len(),
+            self.output.display(),
+            atlas_path.display()
+        );
+        Ok(())
+    }
+}
+
+/// Export an image as a Tiled tileset (`.tsx` + PNG) and map (`.tmj`)
+///
+/// The image is sliced into a fixed tile grid and identical tiles are deduplicated.
+///
+/// Tile properties are taken from the `patica.tile.{x},{y}` metadata items (JSON objects),
+/// which apply to the tile covering the point `(x, y)` of the image.
+#[derive(Debug, clap::Args)]
+pub struct ExportTiledCommand {
+    path: PathBuf,
+
+    /// Output map (`.tmj`) file path
+    #[clap(short, long)]
+    output: PathBuf,
+
+    /// Output tileset (`.tsx`) file path (default: the output path with `.tsx` extension)
+    ///
+    /// The tileset image is written next to it with `.png` extension.
+    #[clap(long)]
+    tileset: Option<PathBuf>,
+
+    #[clap(flatten)]
+    region: RegionArgs,
+
+    /// Tile width
+    #[clap(long, default_value = "16")]
+    tile_width: NonZeroU32,
+
+    /// Tile height (default: the tile width)
+    #[clap(long)]
+    tile_height: Option<NonZeroU32>,
+
+    /// Number of columns of the tileset image (default: square-ish)
+    #[clap(long)]
+    columns: Option<u32>,
+
+    /// Don't deduplicate flipped or rotated variants of tiles
+    #[clap(long)]
+    no_transform: bool,
+}
+
+impl ExportTiledCommand {
+    fn run(&self) -> orfail::Result<()> {
+        let image = load_image(&self.path).or_fail()?;
+        let (start, end) = self.region.resolve(&image).or_fail()?;
+        let bitmap =
+            Bitmap::from_pixels(start, end, image.range_pixels(start..=end), None).or_fail()?;
+        let tile_width = self.tile_width.get();
+        let tile_height = self.tile_height.unwrap_or(self.tile_width).get();
+        let tileset =
+            Tileset::slice(&bitmap, tile_width, tile_height, !self.no_transform).or_fail()?;
+        let columns = tileset.columns(self.columns);
+
+        let properties = tileset.properties(image.metadata(), start).or_fail()?;
+
+        let tileset_path = self
+            .tileset
+            .clone()
+            .unwrap_or_else(|| self.output.with_extension("tsx"));
+        let image_path = tileset_path.with_extension("png");
+        let file_name = |path: &Path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+
+        let file = std::fs::File::create(&image_path)
+            .or_fail_with(|e| format!("Failed to create file {}: {e}", image_path.display()))?;
+        crate::png::write_image(BufWriter::new(file), &tileset.image(columns).or_fail()?)
+            .or_fail()?;
+
+        let name = tileset_path
+            .file_stem()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let file = std::fs::File::create(&tileset_path)
+            .or_fail_with(|e| format!("Failed to create file {}: {e}", tileset_path.display()))?;
+        tileset
+            .write_tsx(
+                BufWriter::new(file),
+                &name,
+                &file_name(&image_path),
+                columns,
+                &properties,
+            )
+            .or_fail()?;
+
+        let file = std::fs::File::create(&self.output)
+            .or_fail_with(|e| format!("Failed to create file {}: {e}", self.output.display()))?;
+        serde_json::to_writer_pretty(
+            BufWriter::new(file),
+            &tileset.map_json(&file_name(&tileset_path)),
+        )
+        .or_fail()?;
+
+        println!(
+            "Exported {} tiles ({}x{} map) to {}, {} and {}",
+            tileset.tiles.len(),
+            tileset.map_width,
+            tileset.map_height,
+            self.output.display(),
+            tileset_path.display(),
+            image_path.display()
+        );
+        Ok(())
+    }
+}
+
+/// Import a PNG, BMP or Aseprite image into a pati file
+///
+/// Aseprite frames are drawn side by side and embedded as patica frames,
+/// and Aseprite slices are mapped to anchors (see `patica::aseprite` for the details).
+#[derive(Debug, clap::Args)]
+pub struct ImportCommand {
+    /// PNG, BMP or Aseprite (`.ase` / `.aseprite`) file path
+    image_path: PathBuf,
+
+    /// Pati file path to which the pixels are appended
+    #[clap(short, long)]
+    output: PathBuf,
+
+    /// X coordinate of the top-left point of the imported pixels
+    #[clap(short, long, default_value_t = 0, allow_hyphen_values = true)]
+    x: i16,
+
+    /// Y coordinate of the top-left point of the imported pixels
+    #[clap(short, long, default_value_t = 0, allow_hyphen_values = true)]
+    y: i16,
+
+    /// Downsample the image by the detected pixel scale (e.g. for upscaled screenshots; PNG / BMP only)
+    #[clap(long)]
+    downsample: bool,
+
+    /// Resize the image to this width (the aspect ratio is kept if `--height` is omitted; PNG / BMP only)
+    #[clap(long)]
+    width: Option<NonZeroU32>,
+
+    /// Resize the image to this height (the aspect ratio is kept if `--width` is omitted; PNG / BMP only)
+    #[clap(long)]
+    height: Option<NonZeroU32>,
+
+    /// Pati palette file (e.g. `palettes/copic.pati`) onto which the colors are mapped (PNG / BMP only)
+    #[clap(long)]
+    palette: Option<PathBuf>,
+
+    /// Dithering method used when mapping colors onto `--palette`
+    #[clap(long, value_enum, default_value_t = Dither::None, requires = "palette")]
+    dither: Dither,
+}
+
+impl ImportCommand {
+    fn run(&self) -> orfail::Result<()> {
+        let is_aseprite = self
+            .image_path
+            .extension()
+            .is_some_and(|ext| ext == "ase" || ext == "aseprite");
+        if is_aseprite {
+            let unsupported = [
+                ("--downsample", self.downsample),
+                ("--width", self.width.is_some()),
+                ("--height", self.height.is_some()),
+                ("--palette", self.palette.is_some()),
+            ];
+            for (option, specified) in unsupported {
+                (!specified)
+                    .or_fail_with(|()| format!("`{option}` is not supported for Aseprite input"))?;
+            }
+            return self.run_aseprite().or_fail();
+        }
+
+        let mut image = ImportedImage::load(&self.image_path).or_fail()?;
+        if self.downsample {
+            let scale = image.pixel_scale();
+            image = image.downsample(scale);
+            eprintln!("Downsampled by the detected pixel scale {scale}");
+        }
+        if self.width.is_some() || self.height.is_some() {
+            let (w, h) = (image.width(), image.height());
+            let width = self.width.map_or_else(
+                || self.height.map(|h1| scale_length(w, h1.get(), h)),
+                |v| Some(v.get()),
+            );
+            let height = self.height.map_or_else(
+                || self.width.map(|w1| scale_length(h, w1.get(), w)),
+                |v| Some(v.get()),
+            );
+            let width = width.and_then(NonZeroU32::new).or_fail()?;
+            let height = height.and_then(NonZeroU32::new).or_fail()?;
+            image = image.resize(width, height).or_fail()?;
+        }
+        if let Some(path) = &self.palette {
+            let palette_image = load_image(path).or_fail()?;
+            let (start, end) = match palette_image.slices().get(PALETTE_SLICE) {
+                Some(slice) => (slice.start, slice.end),
+                None => Point::bounding_box(palette_image.pixels().keys().copied())
+                    .or_fail_with(|()| format!("Empty palette: {}", path.display()))?,
+            };
+            let palette = Palette::from_image(&palette_image, start, end);
+            let colors = palette.colors.iter().map(|c| c.color).collect::<Vec<_>>();
+            image.quantize(&colors, self.dither);
+            eprintln!("Mapped colors onto {} palette colors", colors.len());
+        }
+        let command = image.to_command(Point::new(self.x, self.y)).or_fail()?;
+        let mut file = CanvasFile::open(&self.output, true).or_fail()?;
+        file.command(&CanvasCommand::Image(command)).or_fail()?;
+        println!(
+            "Imported {}x{} pixels into {}",
+            image.width(),
+            image.height(),
+            self.output.display()
+        );
+        Ok(())
+    }
+
+    fn run_aseprite(&self) -> orfail::Result<()> {
+        let bytes = std::fs::read(&self.image_path)
+            .or_fail_with(|e| format!("Failed to read file {}: {e}", self.image_path.display()))?;
+        let aseprite = AsepriteFile::decode(&bytes).or_fail()?;
+        // Frame source paths are relative to the directory of the pati file.
+        let frame_path = self.output.file_name().map(PathBuf::from).or_fail()?;
+        let fps = NonZeroU8::new(Time::DEFAULT_FPS).expect("unreachable");
+        let commands = aseprite
+            .to_commands(Point::new(self.x, self.y), frame_path, fps)
+            .or_fail()?;
+        let commands = commands
+            .into_iter()
+            .map(CanvasCommand::Image)
+            .collect::<Vec<_>>();
+        let mut file = CanvasFile::open(&self.output, true).or_fail()?;
+        file.commands(&commands).or_fail()?;
+        println!(
+            "Imported {}x{} pixels x {} frames ({} tags, {} slices) into {}",
+            aseprite.width,
+            aseprite.height,
+            aseprite.frames.len(),
+            aseprite.tags.len(),
+            aseprite.slices.len(),
+            self.output.display()
+        );
+        Ok(())
+    }
+}
+
+/// Convert palette files to / from pati palette images
+#[derive(Debug, clap::Subcommand)]
+pub enum PaletteCommand {
+    /// Import a palette file (GPL, HEX, JASC-PAL or Paint.NET) as a pati palette image
+    Import {
+        /// Palette file path
+        palette_path: PathBuf,
+
+        /// Output pati file path (default: the input path with `.pati` extension)
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// Palette file format (default: detected from the file extension)
+        #[clap(long, value_enum)]
+        format: Option<PaletteFormat>,
+
+        /// Number of swatches per row
+        #[clap(long, default_value_t = 16)]
+        columns: u16,
+
+        /// Width and height of each swatch
+        #[clap(long, default_value_t = 1)]
+        swatch_size: u16,
+    },
+
+    /// Export the colors of a pati palette image to a palette file
+    Export {
+        path: PathBuf,
+
+        /// Output palette file path
+        #[clap(short, long)]
+        output: PathBuf,
+
+        /// Palette file format (default: detected from the file extension)
+        #[clap(long, value_enum)]
+        format: Option<PaletteFormat>,
+
+        /// Region of the colors (default: the `palette` slice if exists)
+        #[clap(flatten)]
+        region: RegionArgs,
+    },
+}
+
+impl PaletteCommand {
+    fn run(&self) -> orfail::Result<()> {
+        match self {
+            Self::Import {
+                palette_path,
+                output,
+                format,
+                columns,
+                swatch_size,
+            } => {
+                let format = format
+                    .map_or_else(|| PaletteFormat::from_path(palette_path), Ok)
+                    .or_fail()?;
+                let text = std::fs::read_to_string(palette_path).or_fail_with(|e| {
+                    format!("Failed to read file {}: {e}", palette_path.display())
+                })?;
+                let palette = Palette::parse(&text, format).or_fail()?;
+                let output = output
+                    .clone()
+                    .unwrap_or_else(|| palette_path.with_extension("pati"));
+                let commands = palette
+                    .to_commands(*columns, *swatch_size)
+                    .or_fail()?
+                    .into_iter()
+                    .map(CanvasCommand::Image)
+                    .collect::<Vec<_>>();
+                let mut file = CanvasFile::open(&output, true).or_fail()?;
+                file.commands(&commands).or_fail()?;
+                println!(
+                    "Imported {} colors into {}",
+                    palette.colors.len(),
+                    output.display()
+                );
+            }
+            Self::Export {
+                path,
+                output,
+                format,
+                region,
+            } => {
+                let format = format
+                    .map_or_else(|| PaletteFormat::from_path(output), Ok)
+                    .or_fail()?;
+                let image = load_image(path).or_fail()?;
+                let (start, end) = match image.slices().get(PALETTE_SLICE) {
+                    Some(slice) if region.is_unspecified() => (slice.start, slice.end),
+                    _ => region.resolve(&image).or_fail()?,
+                };
+                let palette = Palette::from_image(&image, start, end);
+                std::fs::write(output, palette.format(format).or_fail()?)
+                    .or_fail_with(|e| format!("Failed to write file {}: {e}", output.display()))?;
+                println!(
+                    "Exported {} colors to {}",
+                    palette.colors.len(),
+                    output.display()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Send commands to the running editor (`patica open`)
+///
+/// Each command is a `CanvasCommand` JSON value.
+/// A single command, an array of commands and JSON Lines are accepted.
+#[derive(Debug, clap::Args)]
+pub struct CommandCommand {
+    /// Command JSON (default: read from stdin)
+    commands: Vec<String>,
+
+    #[clap(flatten)]
+    agent: AgentArgs,
+
+    /// Apply the commands atomically (if any of them fails, none of them are applied)
+    #[clap(long)]
+    batch: bool,
+}
+
+impl CommandCommand {
+    fn run(&self) -> orfail::Result<()> {
+        let text = if self.commands.is_empty() {
+            std::io::read_to_string(std::io::stdin().lock())
+                .or_fail_with(|e| format!("Failed to read stdin: {e}"))?
+        } else {
+            self.commands.join("\n")
         };
-        match request {
-            CanvasAgentRequest::Command(command) => {
-                game.model_mut().command(&command).or_fail()?;
-                server.send_response(from, ()).or_fail()?;
+        let mut commands = Vec::new();
+        for value in serde_json::Deserializer::from_str(&text).into_iter::<serde_json::Value>() {
+            let value = value.or_fail_with(|e| format!("Invalid JSON: {e}"))?;
+            let values = match value {
+                serde_json::Value::Array(values) => values,
+                value => vec![value],
+            };
+            for value in values {
+                let command = serde_json::from_value::<CanvasCommand>(value.clone())
+                    .or_fail_with(|e| format!("Invalid command {value}: {e}"))?;
+                commands.push(command);
             }
-            CanvasAgentRequest::Query(query) => {
-                let value = game.model().query(&query);
-                server.send_response(from, value).or_fail()?;
+        }
+
+        let mut agent = self.agent.connect().or_fail()?;
+        if self.batch {
+            return agent.batch(commands).or_fail();
+        }
+        let total = commands.len();
+        for (i, command) in commands.into_iter().enumerate() {
+            agent.command(command).or_fail_with(|e| {
+                format!("Command {} of {total} failed ({i} applied): {e}", i + 1)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Query the state of the running editor or a pati file
+///
+/// The query is a `CanvasQuery` JSON value (a bare name like `cursor` is also accepted).
+/// If `--file` is specified and no editor has opened the file, the query is evaluated against the file.
+#[derive(Debug, clap::Args)]
+pub struct QueryCommand {
+    query: String,
+
+    #[clap(flatten)]
+    agent: AgentArgs,
+
+    /// Print strings without quotes and arrays as space-separated values (for shell scripts)
+    #[clap(long)]
+    raw: bool,
+}
+
+impl QueryCommand {
+    fn run(&self) -> orfail::Result<()> {
+        let query = serde_json::from_str::<CanvasQuery>(&self.query)
+            .or_else(|_| serde_json::from_value(serde_json::Value::String(self.query.clone())))
+            .or_fail_with(|e| format!("Invalid query {:?}: {e}", self.query))?;
+        let value = if let Some(mut agent) = self.agent.connect_if_running().or_fail()? {
+            agent.query(query).or_fail()?
+        } else {
+            let path = self.agent.file.as_ref().or_fail()?;
+            load_canvas(path).or_fail()?.query(&query)
+        };
+
+        // Strip the enum tag (e.g. `{"cursor":[0,0]}` => `[0,0]`).
+        let value = match serde_json::to_value(&value).or_fail()? {
+            serde_json::Value::Object(map) if map.len() == 1 => {
+                map.into_iter().next().map(|(_, v)| v).or_fail()?
             }
+            value => value,
+        };
+        if self.raw {
+            println!("{}", raw_string(&value));
+        } else {
+            print_json(&value).or_fail()?;
         }
         Ok(())
     }
 }
 
+/// Print the change events of the running editor as JSON Lines
+///
+/// Events are applied image commands (with versions), cursor / camera moves and mode changes.
+#[derive(Debug, clap::Args)]
+pub struct SubscribeCommand {
+    #[clap(flatten)]
+    agent: AgentArgs,
+}
+
+impl SubscribeCommand {
+    fn run(&self) -> orfail::Result<()> {
+        let mut subscription = self.agent.connect().or_fail()?.subscribe().or_fail()?;
+        loop {
+            let event = subscription.recv().or_fail()?;
+            print_json(&event).or_fail()?;
+        }
+    }
+}
+
+fn raw_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(v) => v.clone(),
+        serde_json::Value::Array(vs) => vs.iter().map(raw_string).collect::<Vec<_>>().join(" "),
+        value => value.to_string(),
+    }
+}
+
+/// Connection to the running editor
+///
+/// The editor is resolved in the order of `--port`, `--socket`, the `PATICA_PORT` / `PATICA_SOCKET`
+/// environment variables (set for processes spawned by the editor) and the discovery by `--file`.
+/// If `--file` is specified, the connected editor must have opened the file.
+#[derive(Debug, Default, Clone, clap::Args)]
+pub struct AgentArgs {
+    /// Port of the editor
+    #[clap(long)]
+    port: Option<u16>,
+
+    /// Unix domain socket of the editor
+    #[clap(long)]
+    socket: Option<PathBuf>,
+
+    /// Pati file opened by the editor (used to discover the editor)
+    #[clap(long)]
+    file: Option<PathBuf>,
+}
+
+impl AgentArgs {
+    /// Like [`AgentArgs::connect()`] but returns `None` if the editor of `--file` isn't running.
+    fn connect_if_running(&self) -> orfail::Result<Option<CanvasAgent>> {
+        if self.explicit_addr().or_fail()?.is_some() {
+            return self.connect().map(Some).or_fail();
+        }
+        let file = self.file.as_ref().or_fail_with(|()| not_found_message())?;
+        Ok(CanvasAgent::discover(file)
+            .ok()
+            .and_then(|addr| CanvasAgent::connect_in_session(&addr, Some(session_id())).ok()))
+    }
+
+    fn connect(&self) -> orfail::Result<CanvasAgent> {
+        let addr = if let Some(addr) = self.explicit_addr().or_fail()? {
+            addr
+        } else if let Some(file) = &self.file {
+            CanvasAgent::discover(file).or_fail()?
+        } else {
+            return Err(orfail::Failure::new(not_found_message()));
+        };
+        let agent = CanvasAgent::connect_in_session(&addr, Some(session_id())).or_fail()?;
+        if let (Some(file), Some(opened)) = (&self.file, &agent.server_info().file) {
+            let file = file
+                .canonicalize()
+                .or_fail_with(|e| format!("Failed to resolve path {}: {e}", file.display()))?;
+            (&file == opened).or_fail_with(|()| {
+                format!(
+                    "The editor on {addr} has opened {}, not {}",
+                    opened.display(),
+                    file.display()
+                )
+            })?;
+        }
+        Ok(agent)
+    }
+
+    /// Gets the address specified by the options or the environment variables.
+    fn explicit_addr(&self) -> orfail::Result<Option<CanvasAgentAddr>> {
+        if let Some(port) = self.port {
+            Ok(Some(CanvasAgentAddr::Port(port)))
+        } else if let Some(path) = &self.socket {
+            socket_addr(path.clone()).map(Some).or_fail()
+        } else if let Ok(port) = std::env::var(ENV_PATICA_PORT) {
+            let port = port
+                .parse::<u16>()
+                .or_fail_with(|e| format!("Invalid {ENV_PATICA_PORT} {port:?}: {e}"))?;
+            Ok(Some(CanvasAgentAddr::Port(port)))
+        } else if let Some(path) = std::env::var_os(ENV_PATICA_SOCKET) {
+            socket_addr(PathBuf::from(path)).map(Some).or_fail()
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn not_found_message() -> String {
+    format!("Cannot find the editor: specify `--port`, `--socket`, `--file`, {ENV_PATICA_PORT} or {ENV_PATICA_SOCKET}")
+}
+
+#[cfg(unix)]
+fn socket_addr(path: PathBuf) -> orfail::Result<CanvasAgentAddr> {
+    Ok(CanvasAgentAddr::Socket(path))
+}
+
+#[cfg(not(unix))]
+fn socket_addr(path: PathBuf) -> orfail::Result<CanvasAgentAddr> {
+    Err(orfail::Failure::new(format!(
+        "Unix domain sockets are not supported on this platform: {}",
+        path.display()
+    )))
+}
+
+/// Region of an image specified by a pair of anchors or a slice
+///
+/// If neither is specified, the bounding box of the image pixels is used.
+#[derive(Debug, Default, Clone, clap::Args)]
+pub struct RegionArgs {
+    /// Anchor name of the top-left point of the region
+    #[clap(long, requires = "end_anchor", conflicts_with = "slice")]
+    start_anchor: Option<String>,
+
+    /// Anchor name of the bottom-right point of the region
+    #[clap(long, requires = "start_anchor", conflicts_with = "slice")]
+    end_anchor: Option<String>,
+
+    /// Slice name of the region
+    #[clap(long)]
+    slice: Option<String>,
+}
+
+impl RegionArgs {
+    fn is_unspecified(&self) -> bool {
+        self.slice.is_none() && self.start_anchor.is_none()
+    }
+
+    fn resolve(&self, image: &VersionedImage) -> orfail::Result<(Point, Point)> {
+        self.resolve_or(image, image.pixels().keys().copied())
+    }
+
+    /// Like [`RegionArgs::resolve()`] but uses the bounding box of `points` if no region is specified.
+    fn resolve_or(
+        &self,
+        image: &VersionedImage,
+        points: impl Iterator<Item = Point>,
+    ) -> orfail::Result<(Point, Point)> {
+        if let Some(name) = &self.slice {
+            let slice = image
+                .slices()
+                .get(name)
+                .or_fail_with(|()| format!("No such slice: {name}"))?;
+            return Ok((slice.start, slice.end));
+        }
+        if let (Some(start_anchor), Some(end_anchor)) = (&self.start_anchor, &self.end_anchor) {
+            let anchor = |name: &String| {
+                image
+                    .anchors()
+                    .get(name)
+                    .copied()
+                    .or_fail_with(|()| format!("No such anchor: {name}"))
+            };
+            let start = anchor(start_anchor).or_fail()?;
+            let end = anchor(end_anchor).or_fail()?;
+            (start.x <= end.x && start.y <= end.y).or_fail_with(|()| {
+                format!(
+                    "Empty range: start=[{},{}]({start_anchor}), end=[{},{}]({end_anchor})",
+                    start.x, start.y, end.x, end.y,
+                )
+            })?;
+            return Ok((start, end));
+        }
+        Point::bounding_box(points).or_fail_with(|()| "The image has no pixels".to_owned())
+    }
+}
+
+fn session_id() -> String {
+    let user = std::env::var("USER").unwrap_or_else(|_| "unknown".to_owned());
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    format!("{user}@{now}.{}", std::process::id())
+}
+
+fn print_json(value: impl Serialize) -> orfail::Result<()> {
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    serde_json::to_writer(&mut stdout, &value).or_fail()?;
+    writeln!(&mut stdout).or_fail()?;
+    Ok(())
+}
+
+/// Renders each tick of the frame timeline.
+///
+/// Consecutive identical ticks are merged into a single bitmap with the range of the ticks.
+fn render_timeline(
+    image: &VersionedImage,
+    frames: &[EmbeddedFrame],
+    duration: Ticks,
+    region: &RegionArgs,
+    background: Option<Color>,
+) -> orfail::Result<Vec<(Bitmap, Range<u32>)>> {
+    let timeline = (0..duration.get())
+        .map(|t| frame::render_pixels(image, frames, Ticks::new(t)))
+        .collect::<Vec<_>>();
+    let all_points = timeline.iter().flat_map(|pixels| pixels.keys().copied());
+    let (start, end) = region.resolve_or(image, all_points).or_fail()?;
+
+    let mut bitmaps: Vec<(Bitmap, Range<u32>)> = Vec::new();
+    for (t, pixels) in (0..).zip(timeline) {
+        let bitmap = Bitmap::from_pixels(start, end, pixels.into_iter(), background).or_fail()?;
+        match bitmaps.last_mut() {
+            Some((last, ticks)) if *last == bitmap => ticks.end = t + 1,
+            _ => bitmaps.push((bitmap, t..t + 1)),
+        }
+    }
+    Ok(bitmaps)
+}
+
+/// Loads the frames embedded in the given image.
+///
+/// Frame source paths are relative to the directory of the image file.
+fn load_frames(path: &Path, image: &VersionedImage) -> orfail::Result<Vec<EmbeddedFrame>> {
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut frames = Vec::new();
+    for (name, frame) in FRAMES.iter(image.metadata()) {
+        let mut frame = frame.or_fail_with(|e| format!("Invalid frame {name:?}: {e}"))?;
+        let source = load_image(base_dir.join(&frame.frame.path)).or_fail()?;
+        frame.sync(&source).or_fail()?;
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+/// Scales `length` by `numer / denom` (rounded, at least 1).
+fn scale_length(length: u32, numer: u32, denom: u32) -> u32 {
+    ((u64::from(length) * u64::from(numer) + u64::from(denom) / 2) / u64::from(denom).max(1)).max(1)
+        as u32
+}
+
+fn load_canvas<P: AsRef<Path>>(path: P) -> orfail::Result<Canvas> {
+    let file = std::fs::File::open(&path)
+        .or_fail_with(|e| format!("Failed to open file {}: {e}", path.as_ref().display()))?;
+    let mut reader = ImageCommandReader::new(BufReader::new(file));
+    reader.set_recover_checksum_mismatches(true);
+    let mut canvas = Canvas::new();
+    while let Some(command) = reader.read_command().or_fail()? {
+        canvas.command(&CanvasCommand::Image(command)).or_fail()?;
+    }
+    warn_checksum_mismatches(path.as_ref(), reader.checksum_mismatches());
+    Ok(canvas)
+}
+
+fn load_image<P: AsRef<Path>>(path: P) -> orfail::Result<VersionedImage> {
+    let file = std::fs::File::open(&path)
+        .or_fail_with(|e| format!("Failed to open file {}: {e}", path.as_ref().display()))?;
+    let mut reader = ImageCommandReader::new(BufReader::new(file));
+    reader.set_recover_checksum_mismatches(true);
+    let mut image = VersionedImage::new();
+    while let Some(command) = reader.read_command().or_fail()? {
+        image.apply(&command);
+    }
+    warn_checksum_mismatches(path.as_ref(), reader.checksum_mismatches());
+    Ok(image)
+}
+
+/// Warns about the checksum mismatches found while reading the given file.
+///
+/// They are not fatal because the commands of concurrent writers can be interleaved.
+fn warn_checksum_mismatches(path: &Path, mismatches: u32) {
+    if mismatches > 0 {
+        eprintln!(
+            "Warning: {mismatches} checksum mismatch(es) in {} (commands of concurrent writers may be interleaved)",
+            path.display()
+        );
+    }
+}
+
 // #[derive(Debug)]
 // struct EmbeddedCanvas {
 //     path: PathBuf,
@@ -143,22 +1491,6 @@ impl OpenCommand {
 //     }
 // }
 
-// #[derive(Debug, clap::Args)]
-// pub struct ApplyCommand {
-//     #[clap(short, long, default_value_t = 7539)]
-//     port: u16,
-//     // TODO: path
-// }
-
-// impl ApplyCommand {
-//     fn run(&self) -> orfail::Result<()> {
-//         let commands: Vec<Command> =
-//             serde_json::from_reader(&mut std::io::stdin().lock()).or_fail()?;
-//         apply_commands(self.port, &commands).or_fail()?;
-//         Ok(())
-//     }
-// }
-
 // #[derive(Debug, clap::Args)]
 // pub struct IncludeCommand {
 //     #[clap(short, long, default_value_t = 7539)]
@@ -263,107 +1595,3 @@ impl OpenCommand {
 //     client.send_commands(commands).or_fail()?;
 //     Ok(())
 // }
-
-// #[derive(Debug, clap::Args)]
-// pub struct ExportCommand {
-//     path: PathBuf,
-
-//     #[clap(short, long)]
-//     output: Option<PathBuf>,
-// }
-
-// impl ExportCommand {
-//     fn run(&self) -> orfail::Result<()> {
-//         let output = self
-//             .output
-//             .clone()
-//             .unwrap_or_else(|| self.path.with_extension("bmp"));
-//         let canvas = load_canvas(&self.path).or_fail()?;
-
-//         let mut start = Point::new(0, 0);
-//         let mut end = Point::new(0, 0);
-//         for point in canvas.pixels().keys().copied() {
-//             start.y = start.y.min(point.y);
-//             start.x = start.x.min(point.x);
-//             end.y = end.y.max(point.y);
-//             end.x = end.x.max(point.x);
-//         }
-//         crate::bmp::write_image(
-//             BufWriter::new(std::fs::File::create(&output).or_fail()?),
-//             (end.x - start.x + 1) as u16,
-//             (end.y - start.y + 1) as u16,
-//             canvas
-//                 .pixels()
-//                 .iter()
-//                 .map(|(&point, &color)| (point - start, color)),
-//         )
-//         .or_fail()?;
-//         println!("Exported to {}", output.display());
-//         Ok(())
-//     }
-// }
-
-// fn load_canvas<P: AsRef<Path>>(path: &P) -> orfail::Result<pati::Image> {
-//     let file = std::fs::File::open(path).or_fail()?;
-//     let mut reader = ImageCommandReader::new(BufReader::new(file));
-//     let mut canvas = pati::Image::new();
-//     while let Some(command) = reader.read_command().or_fail()? {
-//         canvas.apply(&command);
-//     }
-//     Ok(canvas)
-// }
-
-// #[derive(Debug, clap::Subcommand)]
-// pub enum GetCommand {
-//     // TODO: port arg
-//     BackgroundColor { path: PathBuf },
-//     BrushColor { path: PathBuf },
-//     Anchors { path: PathBuf },
-// }
-
-// impl GetCommand {
-//     fn run(&self) -> orfail::Result<()> {
-//         match self {
-//             GetCommand::BackgroundColor { .. } => {
-//                 let model = self.load_model().or_fail()?;
-//                 self.output(model.background_color()).or_fail()?;
-//             }
-//             GetCommand::BrushColor { .. } => {
-//                 let model = self.load_model().or_fail()?;
-//                 self.output(model.brush_color()).or_fail()?;
-//             }
-//             GetCommand::Anchors { .. } => {
-//                 let model = self.load_model().or_fail()?;
-//                 self.output(model.canvas().anchors()).or_fail()?;
-//             }
-//         }
-//         Ok(())
-//     }
-
-//     fn load_model(&self) -> orfail::Result<Model> {
-//         let file = std::fs::File::open(self.path()).or_fail()?;
-//         let mut reader = ImageCommandReader::new(BufReader::new(file));
-//         let mut model = Model::default();
-//         while let Some(command) = reader.read_command().or_fail()? {
-//             model.canvas_mut().apply(&command);
-//         }
-//         model.initialize().or_fail()?;
-//         Ok(model)
-//     }
-
-//     fn output(&self, value: impl serde::Serialize) -> orfail::Result<()> {
-//         let stdout = std::io::stdout();
-//         let mut stdout = stdout.lock();
-//         serde_json::to_writer(&mut stdout, &value).or_fail()?;
-//         writeln!(&mut stdout).or_fail()?;
-//         Ok(())
-//     }
-
-//     fn path(&self) -> &PathBuf {
-//         match self {
-//             GetCommand::BackgroundColor { path } => path,
-//             GetCommand::BrushColor { path } => path,
-//             GetCommand::Anchors { path } => path,
-//         }
-//     }
-// }
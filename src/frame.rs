@@ -1,9 +1,12 @@
 use crate::clock::Ticks;
 use orfail::OrFail;
-use pati::{Color, Point, Version, VersionedImage};
+use pati::{Color, MetadataNamespace, Point, Version, VersionedImage};
 use serde::{Deserialize, Serialize};
 use std::{collections::BTreeMap, path::PathBuf};
 
+/// Metadata namespace of the frames embedded in an image.
+pub const FRAMES: MetadataNamespace<EmbeddedFrame> = MetadataNamespace::new("patica.frame");
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Frame {
     pub name: String,
@@ -25,6 +28,9 @@ pub struct EmbeddedFrame {
     pub frame: Frame,
     pub start: Point,
     pub version: Version,
+
+    // Pixels are loaded from the frame source by `sync()`.
+    #[serde(skip)]
     pub pixels: BTreeMap<Point, Color>,
 }
 
@@ -57,3 +63,28 @@ impl EmbeddedFrame {
         Ok(())
     }
 }
+
+/// Gets the end ticks of the last frame.
+pub fn end_ticks(frames: &[EmbeddedFrame]) -> Ticks {
+    frames
+        .iter()
+        .map(|f| f.frame.end_ticks)
+        .max()
+        .unwrap_or_default()
+}
+
+/// Gets the pixels of the given image at the given ticks.
+///
+/// The pixels of the visible frames are drawn behind the image pixels.
+pub fn render_pixels(
+    image: &VersionedImage,
+    frames: &[EmbeddedFrame],
+    ticks: Ticks,
+) -> BTreeMap<Point, Color> {
+    let mut pixels = BTreeMap::new();
+    for frame in frames.iter().filter(|f| f.frame.is_visible(ticks)) {
+        pixels.extend(frame.pixels.iter().map(|(p, c)| (*p, *c)));
+    }
+    pixels.extend(image.pixels().iter().map(|(p, c)| (*p, *c)));
+    pixels
+}
@@ -0,0 +1,114 @@
+use crate::bitmap::Bitmap;
+use orfail::OrFail;
+use std::{io::Write, time::Duration};
+
+/// Writes an animated GIF.
+///
+/// All frames must have the same size.
+/// `plays` is the number of times the animation is played (`0` means infinite).
+pub fn write_animation<W: Write>(
+    writer: W,
+    frames: impl Iterator<Item = orfail::Result<(Bitmap, Duration)>>,
+    plays: u16,
+) -> orfail::Result<()> {
+    let mut frames = frames.peekable();
+    let first = match frames.peek() {
+        None => return Err(orfail::Failure::new("No frames to write")),
+        Some(Ok((first, _))) => first,
+        Some(Err(_)) => return frames.next().or_fail()?.map(|_| ()),
+    };
+    let width = u16::try_from(first.width()).or_fail()?;
+    let height = u16::try_from(first.height()).or_fail()?;
+
+    let mut encoder = gif::Encoder::new(writer, width, height, &[]).or_fail()?;
+    // The NETSCAPE extension counts the repeats after the first play.
+    let repeat = match plays {
+        0 => gif::Repeat::Infinite,
+        n => gif::Repeat::Finite(n - 1),
+    };
+    encoder.set_repeat(repeat).or_fail()?;
+    for frame in frames {
+        let (bitmap, duration) = frame.or_fail()?;
+        (bitmap.width() == u32::from(width) && bitmap.height() == u32::from(height))
+            .or_fail_with(|()| "All frames must have the same size".to_owned())?;
+        let mut rgba = bitmap.to_rgba_bytes();
+        let mut frame = gif::Frame::from_rgba_speed(width, height, &mut rgba, 10);
+        frame.delay = delay_centis(duration);
+        frame.dispose = gif::DisposalMethod::Background;
+        encoder.write_frame(&frame).or_fail()?;
+    }
+    Ok(())
+}
+
+/// Rounds `duration` to the nearest centisecond.
+///
+/// Delays below 2 centiseconds are clamped because browsers replace them with about 100 ms.
+fn delay_centis(duration: Duration) -> u16 {
+    let centis = (duration.as_millis() + 5) / 10;
+    centis.clamp(2, u128::from(u16::MAX)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pati::Color;
+
+    fn encode(plays: u16) -> Vec<u8> {
+        let frames = [
+            (Color::rgb(255, 0, 0), Duration::from_millis(100)),
+            (Color::rgb(0, 0, 255), Duration::from_millis(250)),
+        ]
+        .map(|(color, duration)| Bitmap::new(2, 1, Some(color)).map(|b| (b, duration)));
+        let mut data = Vec::new();
+        write_animation(&mut data, frames.into_iter(), plays).expect("write");
+        data
+    }
+
+    fn decode(data: &[u8]) -> (Vec<(Vec<u8>, u16)>, gif::Repeat) {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = options.read_info(data).expect("read info");
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().expect("decode") {
+            frames.push((frame.buffer.to_vec(), frame.delay));
+        }
+        (frames, decoder.repeat())
+    }
+
+    #[test]
+    fn write_animation_works() {
+        let (frames, repeat) = decode(&encode(0));
+        assert_eq!(
+            frames,
+            [
+                ([255, 0, 0, 255].repeat(2), 10),
+                ([0, 0, 255, 255].repeat(2), 25)
+            ]
+        );
+        assert_eq!(repeat, gif::Repeat::Infinite);
+    }
+
+    #[test]
+    fn loop_count_is_the_number_of_plays() {
+        // The NETSCAPE extension has the number of repeats after the first play.
+        let data = encode(3);
+        let netscape = data
+            .windows(11)
+            .position(|w| w == b"NETSCAPE2.0")
+            .expect("NETSCAPE extension");
+        assert_eq!(&data[netscape + 11..netscape + 15], [3, 1, 2, 0]);
+        assert_eq!(decode(&data).1, gif::Repeat::Finite(2));
+        assert_eq!(decode(&encode(1)).1, gif::Repeat::Finite(0));
+    }
+
+    #[test]
+    fn delay_centis_works() {
+        let centis = |millis| delay_centis(Duration::from_millis(millis));
+        assert_eq!(centis(33), 3);
+        assert_eq!(centis(35), 4);
+        assert_eq!(centis(100), 10);
+        assert_eq!(centis(0), 2);
+        assert_eq!(centis(5), 2);
+        assert_eq!(centis(u64::MAX), u16::MAX);
+    }
+}
@@ -0,0 +1,246 @@
+//! Terminal graphics protocols (Sixel and kitty) for rendering images at real pixel resolution.
+use crate::{ansi::ColorMode, bitmap::Bitmap};
+use orfail::OrFail;
+use pati::Color;
+use std::{
+    collections::BTreeMap,
+    io::{IsTerminal, Write},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum GraphicsProtocol {
+    /// DEC Sixel graphics.
+    Sixel,
+
+    /// Kitty terminal graphics protocol.
+    Kitty,
+
+    /// Character cells (ANSI half-block characters).
+    Cells,
+}
+
+impl GraphicsProtocol {
+    /// Detects the graphics protocol supported by the terminal from environment variables.
+    ///
+    /// Falls back to [`GraphicsProtocol::Cells`] if stdout is not a terminal or the terminal is unknown.
+    pub fn detect() -> Self {
+        if !std::io::stdout().is_terminal() {
+            return Self::Cells;
+        }
+        let env = |name| std::env::var(name).unwrap_or_default();
+        let term = env("TERM");
+        let term_program = env("TERM_PROGRAM");
+        if !env("KITTY_WINDOW_ID").is_empty()
+            || term.contains("kitty")
+            || term.contains("ghostty")
+            || matches!(term_program.as_str(), "WezTerm" | "ghostty")
+        {
+            Self::Kitty
+        } else if term.contains("sixel")
+            || term.starts_with("foot")
+            || term.starts_with("mlterm")
+            || matches!(term_program.as_str(), "iTerm.app" | "mintty")
+        {
+            Self::Sixel
+        } else {
+            Self::Cells
+        }
+    }
+
+    pub fn write_image<W: Write>(self, writer: W, bitmap: &Bitmap) -> orfail::Result<()> {
+        match self {
+            Self::Sixel => write_sixel(writer, bitmap).or_fail(),
+            Self::Kitty => write_kitty(writer, bitmap).or_fail(),
+            Self::Cells => crate::ansi::write_image(writer, bitmap, ColorMode::detect()).or_fail(),
+        }
+    }
+}
+
+/// Writes the given bitmap as a Sixel image.
+///
+/// Bitmaps having more than 256 colors are quantized. Fully transparent pixels are not drawn.
+pub fn write_sixel<W: Write>(mut writer: W, bitmap: &Bitmap) -> orfail::Result<()> {
+    let quantize = bitmap
+        .pixels()
+        .iter()
+        .filter(|c| c.a != 0)
+        .map(|&c| Color::rgb(c.r, c.g, c.b))
+        .collect::<std::collections::BTreeSet<_>>()
+        .len()
+        > 256;
+    let to_key = |c: Color| {
+        if quantize {
+            // 6x7x6 levels (252 colors).
+            let q = |v: u8, n: u16| ((u16::from(v) * (n - 1) + 127) / 255 * 255 / (n - 1)) as u8;
+            Color::rgb(q(c.r, 6), q(c.g, 7), q(c.b, 6))
+        } else {
+            Color::rgb(c.r, c.g, c.b)
+        }
+    };
+    let mut registers = BTreeMap::new();
+    for &c in bitmap.pixels().iter().filter(|c| c.a != 0) {
+        let n = registers.len();
+        registers.entry(to_key(c)).or_insert(n);
+    }
+
+    // P2=1: pixels with no sixel bits set keep the terminal background.
+    write!(
+        writer,
+        "\x1bP0;1;0q\"1;1;{};{}",
+        bitmap.width(),
+        bitmap.height()
+    )
+    .or_fail()?;
+    let percent = |v: u8| u32::from(v) * 100 / 255;
+    for (c, i) in &registers {
+        write!(
+            writer,
+            "#{i};2;{};{};{}",
+            percent(c.r),
+            percent(c.g),
+            percent(c.b)
+        )
+        .or_fail()?;
+    }
+
+    for band in (0..bitmap.height()).step_by(6) {
+        // Sixel bits of each color register in this band.
+        let mut rows = BTreeMap::<usize, Vec<u8>>::new();
+        for dy in 0..6 {
+            for x in 0..bitmap.width() {
+                let Some(c) = bitmap.get_pixel(x, band + dy).filter(|c| c.a != 0) else {
+                    continue;
+                };
+                let row = rows
+                    .entry(registers[&to_key(c)])
+                    .or_insert_with(|| vec![0; bitmap.width() as usize]);
+                row[x as usize] |= 1 << dy;
+            }
+        }
+        for (i, row) in rows {
+            write!(writer, "#{i}").or_fail()?;
+            let row = &row[..row.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1)];
+            let mut x = 0;
+            while x < row.len() {
+                let bits = row[x];
+                let n = row[x..].iter().take_while(|&&b| b == bits).count();
+                let ch = char::from(0x3f + bits);
+                if n > 3 {
+                    write!(writer, "!{n}{ch}").or_fail()?;
+                } else {
+                    for _ in 0..n {
+                        write!(writer, "{ch}").or_fail()?;
+                    }
+                }
+                x += n;
+            }
+            write!(writer, "$").or_fail()?;
+        }
+        write!(writer, "-").or_fail()?;
+    }
+    writeln!(writer, "\x1b\\").or_fail()?;
+    Ok(())
+}
+
+/// Writes the given bitmap using the kitty graphics protocol (32-bit RGBA, direct transmission).
+pub fn write_kitty<W: Write>(mut writer: W, bitmap: &Bitmap) -> orfail::Result<()> {
+    const CHUNK_SIZE: usize = 4096;
+    let payload = base64(&bitmap.to_rgba_bytes());
+    let chunks = payload.as_bytes().chunks(CHUNK_SIZE).collect::<Vec<_>>();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(i + 1 < chunks.len());
+        let chunk = std::str::from_utf8(chunk).or_fail()?;
+        if i == 0 {
+            write!(
+                writer,
+                "\x1b_Ga=T,f=32,s={},v={},m={more};{chunk}\x1b\\",
+                bitmap.width(),
+                bitmap.height()
+            )
+            .or_fail()?;
+        } else {
+            write!(writer, "\x1b_Gm={more};{chunk}\x1b\\").or_fail()?;
+        }
+    }
+    writeln!(writer).or_fail()?;
+    Ok(())
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut s = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(char::from(TABLE[(n >> (18 - 6 * i) & 0x3f) as usize]));
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_sixel_works() {
+        let red = Color::rgb(255, 0, 0);
+        let mut bitmap = Bitmap::new(5, 2, None).expect("bitmap");
+        for x in 0..5 {
+            bitmap.set_pixel(x, 0, red);
+        }
+        bitmap.set_pixel(0, 1, red);
+        bitmap.set_pixel(1, 1, Color::rgb(0, 0, 255));
+
+        let mut output = Vec::new();
+        write_sixel(&mut output, &bitmap).expect("write");
+        assert_eq!(
+            String::from_utf8(output).expect("UTF-8"),
+            concat!(
+                "\x1bP0;1;0q\"1;1;5;2",
+                "#1;2;0;0;100#0;2;100;0;0",
+                "#0B!4@$#1?A$-",
+                "\x1b\\\n",
+            )
+        );
+    }
+
+    #[test]
+    fn write_kitty_works() {
+        let mut bitmap = Bitmap::new(1, 1, None).expect("bitmap");
+        bitmap.set_pixel(0, 0, Color::rgb(255, 0, 0));
+        let mut output = Vec::new();
+        write_kitty(&mut output, &bitmap).expect("write");
+        assert_eq!(
+            String::from_utf8(output).expect("UTF-8"),
+            "\x1b_Ga=T,f=32,s=1,v=1,m=0;/wAA/w==\x1b\\\n"
+        );
+
+        // 32x32 RGBA pixels are 5464 base64 characters, so they are sent in two chunks.
+        let bitmap = Bitmap::new(32, 32, Some(Color::rgb(255, 0, 0))).expect("bitmap");
+        let mut output = Vec::new();
+        write_kitty(&mut output, &bitmap).expect("write");
+        let output = String::from_utf8(output).expect("UTF-8");
+        assert!(output.starts_with("\x1b_Ga=T,f=32,s=32,v=32,m=1;/wAA"));
+        assert_eq!(output.matches("\x1b_Gm=0;").count(), 1);
+        assert!(output.ends_with("\x1b\\\n"));
+    }
+
+    #[test]
+    fn base64_works() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+}
@@ -1,14 +1,26 @@
+pub mod ansi;
+pub mod aseprite;
+pub mod bitmap;
 // pub mod bmp;
 pub mod cli;
-// pub mod clock; // TODO: rename module
+// TODO: rename module
+pub mod clock;
 // pub mod command;
 pub mod config;
 // pub mod editor;
-// pub mod frame;
+pub mod frame;
 pub mod game;
+pub mod gif;
+pub mod graphics;
 // pub mod marker;
 pub mod model;
+pub mod palette;
+pub mod png;
 // pub mod query;
 // pub mod remote;
 pub mod screen;
+pub mod sheet;
+pub mod svg;
+pub mod tiled;
+pub mod upscale;
 pub mod view;
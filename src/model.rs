@@ -1,5 +1,5 @@
 use orfail::OrFail;
-use paticanvas::{CanvasCommand, CanvasFile, CanvasQuery, CanvasQueryValue};
+use paticanvas::{Canvas, CanvasCommand, CanvasFile, CanvasQuery, CanvasQueryValue};
 use std::num::NonZeroU8;
 
 #[derive(Debug)]
@@ -14,6 +14,14 @@ impl Model {
         self.0.command(command).or_fail()
     }
 
+    pub fn canvas(&self) -> &Canvas {
+        self.0.canvas()
+    }
+
+    pub fn file_mut(&mut self) -> &mut CanvasFile {
+        &mut self.0
+    }
+
     pub fn query(&self, query: &CanvasQuery) -> CanvasQueryValue {
         self.0.canvas().query(query)
     }
@@ -0,0 +1,475 @@
+use orfail::OrFail;
+use pati::{Color, ImageCommand, Point, Slice, VersionedImage};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    path::Path,
+};
+
+/// Prefix of the anchors of named colors in a palette image.
+pub const COLOR_ANCHOR_PREFIX: &str = "color.";
+
+/// Name of the slice that covers a palette image.
+pub const PALETTE_SLICE: &str = "palette";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PaletteFormat {
+    /// GIMP palette (`.gpl`).
+    Gpl,
+
+    /// Lospec hex palette (`.hex`).
+    Hex,
+
+    /// JASC-PAL palette (`.pal`).
+    Pal,
+
+    /// Paint.NET palette (`.txt`).
+    Txt,
+}
+
+impl PaletteFormat {
+    pub fn from_path(path: &Path) -> orfail::Result<Self> {
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("gpl") => Ok(Self::Gpl),
+            Some("hex") => Ok(Self::Hex),
+            Some("pal") => Ok(Self::Pal),
+            Some("txt") => Ok(Self::Txt),
+            _ => Err(orfail::Failure::new(format!(
+                "Unknown palette format (please specify `--format`): {}",
+                path.display()
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaletteColor {
+    pub color: Color,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Palette {
+    pub name: Option<String>,
+    pub colors: Vec<PaletteColor>,
+}
+
+impl Palette {
+    pub fn parse(text: &str, format: PaletteFormat) -> orfail::Result<Self> {
+        match format {
+            PaletteFormat::Gpl => Self::parse_gpl(text).or_fail(),
+            PaletteFormat::Hex => Self::parse_hex(text).or_fail(),
+            PaletteFormat::Pal => Self::parse_pal(text).or_fail(),
+            PaletteFormat::Txt => Self::parse_txt(text).or_fail(),
+        }
+    }
+
+    fn parse_gpl(text: &str) -> orfail::Result<Self> {
+        let mut lines = text.lines();
+        (lines.next().map(str::trim) == Some("GIMP Palette"))
+            .or_fail_with(|()| "Missing `GIMP Palette` header".to_owned())?;
+        let mut palette = Self::default();
+        for line in lines {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("Columns:") {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix("Name:") {
+                palette.name = Some(name.trim().to_owned());
+                continue;
+            }
+            let mut tokens = line.split_whitespace();
+            let mut component = || -> orfail::Result<u8> {
+                let token = tokens
+                    .next()
+                    .or_fail_with(|()| format!("Invalid GPL color line: {line:?}"))?;
+                token
+                    .parse::<u8>()
+                    .or_fail_with(|e| format!("Invalid GPL color component {token:?}: {e}"))
+            };
+            let color = Color::rgb(component()?, component()?, component()?);
+            let name = tokens.collect::<Vec<_>>().join(" ");
+            palette.colors.push(PaletteColor {
+                color,
+                name: (!name.is_empty()).then_some(name),
+            });
+        }
+        Ok(palette)
+    }
+
+    fn parse_hex(text: &str) -> orfail::Result<Self> {
+        let mut palette = Self::default();
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let hex = line.trim_start_matches('#');
+            (hex.len() == 6).or_fail_with(|()| {
+                format!("Invalid HEX color (expected `RRGGBB`, alpha is not supported): {line:?}")
+            })?;
+            let [_, r, g, b] = parse_hex_u32(hex).or_fail()?.to_be_bytes();
+            palette.colors.push(PaletteColor {
+                color: Color::rgb(r, g, b),
+                name: None,
+            });
+        }
+        Ok(palette)
+    }
+
+    fn parse_pal(text: &str) -> orfail::Result<Self> {
+        let mut lines = text.lines().map(str::trim);
+        (lines.next() == Some("JASC-PAL"))
+            .or_fail_with(|()| "Missing `JASC-PAL` header".to_owned())?;
+        let _version = lines.next().or_fail()?;
+        let count = lines
+            .next()
+            .or_fail()?
+            .parse::<usize>()
+            .or_fail_with(|e| format!("Invalid JASC-PAL color count: {e}"))?;
+        let mut palette = Self::default();
+        for line in lines.filter(|l| !l.is_empty()).take(count) {
+            let components = line
+                .split_whitespace()
+                .map(|t| t.parse::<u8>())
+                .collect::<Result<Vec<_>, _>>()
+                .or_fail_with(|e| format!("Invalid JASC-PAL color line {line:?}: {e}"))?;
+            let [r, g, b] = components[..] else {
+                return Err(orfail::Failure::new(format!(
+                    "Invalid JASC-PAL color line: {line:?}"
+                )));
+            };
+            palette.colors.push(PaletteColor {
+                color: Color::rgb(r, g, b),
+                name: None,
+            });
+        }
+        (palette.colors.len() == count).or_fail_with(|()| {
+            format!(
+                "JASC-PAL color count mismatch: expected {count}, but got {}",
+                palette.colors.len()
+            )
+        })?;
+        Ok(palette)
+    }
+
+    fn parse_txt(text: &str) -> orfail::Result<Self> {
+        let mut palette = Self::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            (line.len() == 8).or_fail_with(|()| format!("Invalid Paint.NET color: {line:?}"))?;
+            let [a, r, g, b] = parse_hex_u32(line).or_fail()?.to_be_bytes();
+            palette.colors.push(PaletteColor {
+                color: Color::rgba(r, g, b, a),
+                name: None,
+            });
+        }
+        Ok(palette)
+    }
+
+    /// Formats this palette.
+    ///
+    /// Only the Paint.NET format can hold alpha, so the other formats fail on non-opaque colors.
+    pub fn format(&self, format: PaletteFormat) -> orfail::Result<String> {
+        if format != PaletteFormat::Txt {
+            if let Some(c) = self.colors.iter().find(|c| c.color.a != u8::MAX) {
+                return Err(orfail::Failure::new(format!(
+                    "{format:?} palettes cannot hold non-opaque colors (use `txt` instead): {:?}",
+                    c.color
+                )));
+            }
+        }
+        let mut s = String::new();
+        match format {
+            PaletteFormat::Gpl => {
+                s.push_str("GIMP Palette\n");
+                if let Some(name) = &self.name {
+                    let _ = writeln!(s, "Name: {name}");
+                }
+                s.push_str("#\n");
+                for c in &self.colors {
+                    let Color { r, g, b, .. } = c.color;
+                    let _ = write!(s, "{r:3} {g:3} {b:3}");
+                    if let Some(name) = &c.name {
+                        let _ = write!(s, "\t{name}");
+                    }
+                    s.push('\n');
+                }
+            }
+            PaletteFormat::Hex => {
+                for c in &self.colors {
+                    let Color { r, g, b, .. } = c.color;
+                    let _ = writeln!(s, "{r:02x}{g:02x}{b:02x}");
+                }
+            }
+            PaletteFormat::Pal => {
+                let _ = writeln!(s, "JASC-PAL\n0100\n{}", self.colors.len());
+                for c in &self.colors {
+                    let Color { r, g, b, .. } = c.color;
+                    let _ = writeln!(s, "{r} {g} {b}");
+                }
+            }
+            PaletteFormat::Txt => {
+                s.push_str(";paint.net Palette File\n");
+                if let Some(name) = &self.name {
+                    let _ = writeln!(s, ";Palette Name: {name}");
+                }
+                let _ = writeln!(s, ";Colors: {}", self.colors.len());
+                for c in &self.colors {
+                    let Color { r, g, b, a } = c.color;
+                    let _ = writeln!(s, "{a:02X}{r:02X}{g:02X}{b:02X}");
+                }
+            }
+        }
+        Ok(s)
+    }
+
+    /// Makes commands to draw this palette as a swatch grid starting at `(0, 0)`.
+    ///
+    /// Named colors have the anchors `color.{name}` at the top-left of their swatches,
+    /// and the whole grid is covered by the slice `palette` (with the `name` property if the palette is named).
+    /// The slice also has the `columns`, `swatch_size` and `count` properties of the grid,
+    /// so that [`Palette::from_image()`] can restore the entries in order (including repeated colors).
+    pub fn to_commands(&self, columns: u16, swatch_size: u16) -> orfail::Result<Vec<ImageCommand>> {
+        let columns = usize::from(columns.max(1)).min(self.colors.len().max(1));
+        let rows = self.colors.len().div_ceil(columns);
+        let too_large = || {
+            format!("Palette grid is too large: {columns}x{rows} swatches of {swatch_size} pixels")
+        };
+        let size = i16::try_from(swatch_size.max(1)).or_fail_with(|_| too_large())?;
+        let extent = |n: usize| {
+            i16::try_from(n)
+                .ok()
+                .and_then(|n| n.checked_mul(size))
+                .or_fail_with(|()| too_large())
+        };
+        let end = Point::new(extent(columns)? - 1, extent(rows)? - 1);
+
+        let mut commands = Vec::new();
+        let mut pixels = Vec::new();
+        for (i, c) in self.colors.iter().enumerate() {
+            let start = Point::new(extent(i % columns)?, extent(i / columns)?);
+            for y in 0..size {
+                for x in 0..size {
+                    pixels.push((start + Point::new(x, y), c.color));
+                }
+            }
+            if let Some(name) = &c.name {
+                commands.push(ImageCommand::anchor(
+                    format!("{COLOR_ANCHOR_PREFIX}{name}"),
+                    Some(start),
+                ));
+            }
+        }
+        commands.insert(0, ImageCommand::draw_pixels(pixels.into_iter()));
+
+        if !self.colors.is_empty() {
+            let mut slice = Slice::new(Point::new(0, 0), end);
+            if let Some(name) = &self.name {
+                slice.properties.insert("name".to_owned(), json!(name));
+            }
+            slice
+                .properties
+                .insert("columns".to_owned(), json!(columns));
+            slice
+                .properties
+                .insert("swatch_size".to_owned(), json!(size));
+            slice
+                .properties
+                .insert("count".to_owned(), json!(self.colors.len()));
+            commands.push(ImageCommand::slice(PALETTE_SLICE, Some(slice)));
+        }
+        Ok(commands)
+    }
+
+    /// Extracts the colors in the given region of a palette image.
+    ///
+    /// If the region is the `palette` slice made by [`Palette::to_commands()`],
+    /// the color of each swatch is extracted in order.
+    /// Otherwise, the distinct colors are extracted in row-major order,
+    /// except that every pixel pointed by a `color.{name}` anchor becomes a named entry.
+    pub fn from_image(image: &VersionedImage, start: Point, end: Point) -> Self {
+        let names = image
+            .anchors()
+            .iter()
+            .filter_map(|(name, point)| {
+                let name = name.strip_prefix(COLOR_ANCHOR_PREFIX)?;
+                Some((*point, name.to_owned()))
+            })
+            .collect::<BTreeMap<_, _>>();
+        let slice = image
+            .slices()
+            .get(PALETTE_SLICE)
+            .filter(|s| s.start == start && s.end == end);
+        let mut palette = Self {
+            name: slice
+                .or_else(|| image.slices().get(PALETTE_SLICE))
+                .and_then(|s| s.properties.get("name"))
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_owned()),
+            colors: Vec::new(),
+        };
+
+        if let Some(swatches) = slice.and_then(|s| swatch_points(s).ok()) {
+            for point in swatches {
+                if let Some(color) = image.get_pixel(point) {
+                    palette.colors.push(PaletteColor {
+                        color,
+                        name: names.get(&point).cloned(),
+                    });
+                }
+            }
+            return palette;
+        }
+
+        let mut pixels = image.range_pixels(start..=end).collect::<Vec<_>>();
+        pixels.sort_by_key(|(p, _)| (p.y, p.x));
+        let mut seen = BTreeSet::new();
+        for (point, color) in pixels {
+            let name = names.get(&point).cloned();
+            if seen.insert(color) || name.is_some() {
+                palette.colors.push(PaletteColor { color, name });
+            }
+        }
+        palette
+    }
+}
+
+/// Returns the top-left points of the swatches of a palette grid in order.
+fn swatch_points(slice: &Slice) -> orfail::Result<Vec<Point>> {
+    let property = |key: &str| {
+        slice
+            .properties
+            .get(key)
+            .and_then(|v| v.as_u64())
+            .or_fail_with(|()| format!("Missing palette slice property: {key:?}"))
+    };
+    let columns = property("columns").or_fail()?.max(1);
+    let size = i16::try_from(property("swatch_size").or_fail()?).or_fail()?;
+    let count = property("count").or_fail()?;
+    (0..count)
+        .map(|i| {
+            let x = i16::try_from(i % columns)
+                .ok()
+                .and_then(|x| x.checked_mul(size));
+            let y = i16::try_from(i / columns)
+                .ok()
+                .and_then(|y| y.checked_mul(size));
+            let offset = Point::new(x.or_fail()?, y.or_fail()?);
+            Ok(slice.start + offset)
+        })
+        .collect()
+}
+
+fn parse_hex_u32(hex: &str) -> orfail::Result<u32> {
+    u32::from_str_radix(hex, 16).or_fail_with(|e| format!("Invalid hex color {hex:?}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_round_trip(text: &str, format: PaletteFormat) -> Palette {
+        let palette = Palette::parse(text, format).expect("parse");
+        assert_eq!(palette.format(format).expect("format"), text);
+        palette
+    }
+
+    #[test]
+    fn gpl_round_trip() {
+        let text = "GIMP Palette\nName: Test\n#\n  0   0   0\tBlack\n255 128  64\tLight orange\n";
+        let palette = assert_round_trip(text, PaletteFormat::Gpl);
+        assert_eq!(palette.name.as_deref(), Some("Test"));
+        assert_eq!(palette.colors[1].color, Color::rgb(255, 128, 64));
+        assert_eq!(palette.colors[1].name.as_deref(), Some("Light orange"));
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let palette = assert_round_trip("000000\nff8040\n", PaletteFormat::Hex);
+        assert_eq!(palette.colors[1].color, Color::rgb(255, 128, 64));
+        assert!(Palette::parse("ff804080\n", PaletteFormat::Hex).is_err());
+    }
+
+    #[test]
+    fn pal_round_trip() {
+        let palette =
+            assert_round_trip("JASC-PAL\n0100\n2\n0 0 0\n255 128 64\n", PaletteFormat::Pal);
+        assert_eq!(palette.colors[1].color, Color::rgb(255, 128, 64));
+    }
+
+    #[test]
+    fn txt_round_trip() {
+        let text = ";paint.net Palette File\n;Colors: 2\nFF000000\n80FF8040\n";
+        let palette = assert_round_trip(text, PaletteFormat::Txt);
+        assert_eq!(palette.colors[1].color, Color::rgba(255, 128, 64, 128));
+    }
+
+    #[test]
+    fn format_rejects_non_opaque_colors() {
+        let palette = Palette {
+            name: None,
+            colors: vec![PaletteColor {
+                color: Color::rgba(255, 128, 64, 128),
+                name: None,
+            }],
+        };
+        assert!(palette.format(PaletteFormat::Gpl).is_err());
+        assert!(palette.format(PaletteFormat::Hex).is_err());
+        assert!(palette.format(PaletteFormat::Pal).is_err());
+        assert!(palette.format(PaletteFormat::Txt).is_ok());
+    }
+
+    #[test]
+    fn image_round_trip_keeps_repeated_colors_and_names() {
+        let text =
+            "GIMP Palette\n#\n  0   0   0\tBlack\n255 128  64\n  0   0   0\tInk\n255 128  64\n";
+        let palette = Palette::parse(text, PaletteFormat::Gpl).expect("parse");
+        let mut image = VersionedImage::new();
+        for command in palette.to_commands(3, 2).expect("commands") {
+            image.apply(&command);
+        }
+        let slice = &image.slices()[PALETTE_SLICE];
+        let restored = Palette::from_image(&image, slice.start, slice.end);
+        assert_eq!(restored, palette);
+        assert_eq!(restored.format(PaletteFormat::Gpl).expect("format"), text);
+    }
+
+    #[test]
+    fn from_image_keeps_named_pixels_outside_palette_grids() {
+        let black = Color::rgb(0, 0, 0);
+        let mut image = VersionedImage::new();
+        image.apply(&ImageCommand::draw_pixels(
+            (0..3).map(|x| (Point::new(x, 0), black)),
+        ));
+        image.apply(&ImageCommand::anchor("color.a", Some(Point::new(1, 0))));
+        image.apply(&ImageCommand::anchor("color.b", Some(Point::new(2, 0))));
+        let palette = Palette::from_image(&image, Point::new(0, 0), Point::new(2, 0));
+        let names = palette
+            .colors
+            .iter()
+            .map(|c| c.name.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(names, [None, Some("a"), Some("b")]);
+    }
+
+    #[test]
+    fn to_commands_fails_on_coordinate_overflow() {
+        let palette = Palette {
+            name: None,
+            colors: vec![
+                PaletteColor {
+                    color: Color::rgb(0, 0, 0),
+                    name: None,
+                };
+                4
+            ],
+        };
+        assert!(palette.to_commands(2, 100).is_ok());
+        assert!(palette.to_commands(2, 20000).is_err());
+        assert!(palette.to_commands(1, u16::MAX).is_err());
+    }
+}
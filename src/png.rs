@@ -0,0 +1,191 @@
+use crate::bitmap::Bitmap;
+use orfail::OrFail;
+use pati::Color;
+use std::{collections::BTreeMap, io::Write, time::Duration};
+
+/// Writes a PNG image.
+///
+/// If the bitmap has 256 colors or less, an indexed-palette PNG is written.
+pub fn write_image<W: Write>(writer: W, bitmap: &Bitmap) -> orfail::Result<()> {
+    let mut encoder = png::Encoder::new(writer, bitmap.width(), bitmap.height());
+    encoder.set_depth(png::BitDepth::Eight);
+    let data = if let Some((palette, indices)) = index_colors(bitmap) {
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_palette(
+            palette
+                .iter()
+                .flat_map(|c| [c.r, c.g, c.b])
+                .collect::<Vec<_>>(),
+        );
+        if palette.iter().any(|c| c.a != 255) {
+            encoder.set_trns(palette.iter().map(|c| c.a).collect::<Vec<_>>());
+        }
+        indices
+    } else {
+        encoder.set_color(png::ColorType::Rgba);
+        bitmap.to_rgba_bytes()
+    };
+    let mut writer = encoder.write_header().or_fail()?;
+    writer.write_image_data(&data).or_fail()?;
+    writer.finish().or_fail()?;
+    Ok(())
+}
+
+/// Writes an animated PNG (APNG).
+///
+/// All frames must have the same size.
+/// `plays` is the number of times the animation is played (`0` means infinite).
+pub fn write_animation<W: Write>(
+    writer: W,
+    frames: &[(Bitmap, Duration)],
+    plays: u16,
+) -> orfail::Result<()> {
+    let (first, _) = frames
+        .first()
+        .or_fail_with(|()| "No frames to write".to_owned())?;
+    let (width, height) = (first.width(), first.height());
+
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .set_animated(frames.len() as u32, u32::from(plays))
+        .or_fail()?;
+    encoder
+        .set_dispose_op(png::DisposeOp::Background)
+        .or_fail()?;
+    let mut writer = encoder.write_header().or_fail()?;
+    for (bitmap, duration) in frames {
+        (bitmap.width() == width && bitmap.height() == height)
+            .or_fail_with(|()| "All frames must have the same size".to_owned())?;
+        let delay = duration.as_millis().min(u16::MAX as u128) as u16;
+        writer.set_frame_delay(delay, 1000).or_fail()?;
+        writer.write_image_data(&bitmap.to_rgba_bytes()).or_fail()?;
+    }
+    writer.finish().or_fail()?;
+    Ok(())
+}
+
+fn index_colors(bitmap: &Bitmap) -> Option<(Vec<Color>, Vec<u8>)> {
+    let mut palette = BTreeMap::new();
+    for &color in bitmap.pixels() {
+        let n = palette.len();
+        palette.entry(color).or_insert(n);
+        if palette.len() > 256 {
+            return None;
+        }
+    }
+    let indices = bitmap
+        .pixels()
+        .iter()
+        .map(|color| palette[color] as u8)
+        .collect();
+    let mut palette = palette.into_iter().collect::<Vec<_>>();
+    palette.sort_by_key(|(_, i)| *i);
+    Some((palette.into_iter().map(|(c, _)| c).collect(), indices))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Color = Color::rgb(255, 0, 0);
+    const CLEAR: Color = Color::rgba(0, 0, 255, 0);
+
+    fn decoder(data: &[u8], transformations: png::Transformations) -> png::Reader<&[u8]> {
+        let mut decoder = png::Decoder::new(data);
+        decoder.set_transformations(transformations);
+        decoder.read_info().expect("read info")
+    }
+
+    fn encode(bitmap: &Bitmap) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_image(&mut data, bitmap).expect("write");
+        data
+    }
+
+    #[test]
+    fn write_indexed_image_works() {
+        let mut bitmap = Bitmap::new(3, 1, Some(RED)).expect("bitmap");
+        bitmap.set_pixel(1, 0, CLEAR);
+        let data = encode(&bitmap);
+
+        let mut reader = decoder(&data, png::Transformations::IDENTITY);
+        let info = reader.info();
+        assert_eq!(info.color_type, png::ColorType::Indexed);
+        assert_eq!(info.palette.as_deref(), Some(&[255, 0, 0, 0, 0, 255][..]));
+        assert_eq!(info.trns.as_deref(), Some(&[255, 0][..]));
+        let mut indices = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut indices).expect("decode");
+        assert_eq!(indices, [0, 1, 0]);
+
+        let mut reader = decoder(&data, png::Transformations::EXPAND);
+        let mut rgba = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut rgba).expect("decode");
+        assert_eq!(rgba, bitmap.to_rgba_bytes());
+
+        // Opaque images have no tRNS chunk.
+        let data = encode(&Bitmap::new(2, 2, Some(RED)).expect("bitmap"));
+        let reader = decoder(&data, png::Transformations::IDENTITY);
+        assert_eq!(reader.info().color_type, png::ColorType::Indexed);
+        assert!(reader.info().trns.is_none());
+    }
+
+    #[test]
+    fn write_rgba_image_works() {
+        // More than 256 colors can't be indexed.
+        let mut bitmap = Bitmap::new(17, 17, None).expect("bitmap");
+        for i in 0..17 * 17 {
+            bitmap.set_pixel(
+                i % 17,
+                i / 17,
+                Color::rgba(i as u8, (i / 256) as u8, 0, 200),
+            );
+        }
+        let data = encode(&bitmap);
+        let mut reader = decoder(&data, png::Transformations::IDENTITY);
+        assert_eq!(reader.info().color_type, png::ColorType::Rgba);
+        let mut rgba = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut rgba).expect("decode");
+        assert_eq!(rgba, bitmap.to_rgba_bytes());
+    }
+
+    #[test]
+    fn write_animation_works() {
+        let frames = [
+            (
+                Bitmap::new(2, 1, Some(RED)).expect("bitmap"),
+                Duration::from_millis(100),
+            ),
+            (
+                Bitmap::new(2, 1, Some(CLEAR)).expect("bitmap"),
+                Duration::from_millis(250),
+            ),
+        ];
+        let mut data = Vec::new();
+        write_animation(&mut data, &frames, 3).expect("write");
+
+        let mut reader = decoder(&data, png::Transformations::IDENTITY);
+        let control = reader.info().animation_control.expect("acTL");
+        assert_eq!((control.num_frames, control.num_plays), (2, 3));
+        for (bitmap, duration) in &frames {
+            let mut rgba = vec![0; reader.output_buffer_size()];
+            reader.next_frame(&mut rgba).expect("decode");
+            assert_eq!(rgba, bitmap.to_rgba_bytes());
+            let control = reader.info().frame_control.expect("fcTL");
+            assert_eq!(
+                (control.delay_num, control.delay_den),
+                (duration.as_millis() as u16, 1000)
+            );
+        }
+
+        let mismatched = [
+            frames[0].clone(),
+            (
+                Bitmap::new(1, 1, None).expect("bitmap"),
+                Duration::from_millis(100),
+            ),
+        ];
+        assert!(write_animation(Vec::new(), &mismatched, 0).is_err());
+    }
+}
@@ -0,0 +1,386 @@
+use crate::bitmap::Bitmap;
+use orfail::OrFail;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeSet;
+
+/// Sprite to be packed into a sheet.
+#[derive(Debug, Clone)]
+pub struct Sprite {
+    pub name: String,
+    pub bitmap: Bitmap,
+    pub duration_ms: u32,
+}
+
+/// Named range of sprites (inclusive indices), e.g. an animation.
+#[derive(Debug, Clone, Serialize)]
+pub struct Tag {
+    pub name: String,
+    pub from: usize,
+    pub to: usize,
+    pub direction: &'static str,
+}
+
+impl Tag {
+    pub fn new(name: impl Into<String>, from: usize, to: usize) -> Self {
+        Self {
+            name: name.into(),
+            from,
+            to,
+            direction: "forward",
+        }
+    }
+
+    /// Makes a tag covering the sprites of the given indices.
+    ///
+    /// Fails if the indices are not contiguous, as a tag can only represent a range of sprites.
+    pub fn from_indices(name: impl Into<String>, indices: &[usize]) -> orfail::Result<Self> {
+        let name = name.into();
+        let from = indices
+            .iter()
+            .copied()
+            .min()
+            .or_fail_with(|()| format!("Tag {name:?} has no sprites"))?;
+        let to = indices.iter().copied().max().or_fail()?;
+        let distinct = indices.iter().collect::<BTreeSet<_>>().len();
+        (to - from + 1 == distinct).or_fail_with(|()| {
+            format!("The sprites of tag {name:?} can't be placed contiguously in the sheet")
+        })?;
+        Ok(Self::new(name, from, to))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Layout {
+    /// Places sprites in fixed-size cells of a grid.
+    #[default]
+    Grid,
+
+    /// Packs sprites into rows sorted by height (shelf packing).
+    Pack,
+}
+
+/// Packed sprite sheet.
+#[derive(Debug, Clone)]
+pub struct Sheet {
+    pub bitmap: Bitmap,
+
+    /// Top-left position of each sprite in the sheet.
+    pub positions: Vec<(u32, u32)>,
+}
+
+impl Sheet {
+    pub fn pack(
+        sprites: &[Sprite],
+        layout: Layout,
+        columns: Option<u32>,
+        padding: u32,
+    ) -> orfail::Result<Self> {
+        let positions = match layout {
+            Layout::Grid => grid_positions(sprites, columns, padding).or_fail()?,
+            Layout::Pack => shelf_positions(sprites, padding).or_fail()?,
+        };
+        let too_large = |()| format!("Too large sprite sheet (padding: {padding})");
+        let mut width = 0;
+        let mut height = 0;
+        for (sprite, &(x, y)) in sprites.iter().zip(&positions) {
+            width = width.max(
+                x.checked_add(sprite.bitmap.width())
+                    .or_fail_with(too_large)?,
+            );
+            height = height.max(
+                y.checked_add(sprite.bitmap.height())
+                    .or_fail_with(too_large)?,
+            );
+        }
+        let mut bitmap = Bitmap::new(width, height, None).or_fail()?;
+        for (sprite, &(x, y)) in sprites.iter().zip(&positions) {
+            bitmap.copy_from(x, y, &sprite.bitmap);
+        }
+        Ok(Self { bitmap, positions })
+    }
+
+    /// Makes an atlas in the Aseprite / TexturePacker JSON hash format.
+    ///
+    /// Fails if sprites have the same name, as frames are keyed by the sprite names.
+    pub fn atlas(&self, sprites: &[Sprite], image: &str, tags: Vec<Tag>) -> orfail::Result<Atlas> {
+        let mut names = BTreeSet::new();
+        for sprite in sprites {
+            names
+                .insert(&sprite.name)
+                .or_fail_with(|()| format!("Duplicate sprite name: {:?}", sprite.name))?;
+        }
+        let frames = sprites
+            .iter()
+            .zip(&self.positions)
+            .map(|(sprite, &(x, y))| {
+                let (w, h) = (sprite.bitmap.width(), sprite.bitmap.height());
+                let frame = AtlasFrame {
+                    frame: Rect { x, y, w, h },
+                    rotated: false,
+                    trimmed: false,
+                    sprite_source_size: Rect { x: 0, y: 0, w, h },
+                    source_size: Size { w, h },
+                    duration: sprite.duration_ms,
+                };
+                (sprite.name.clone(), frame)
+            })
+            .collect();
+        Ok(Atlas {
+            frames,
+            meta: AtlasMeta {
+                app: env!("CARGO_PKG_HOMEPAGE"),
+                version: env!("CARGO_PKG_VERSION"),
+                image: image.to_owned(),
+                format: "RGBA8888",
+                size: Size {
+                    w: self.bitmap.width(),
+                    h: self.bitmap.height(),
+                },
+                scale: "1",
+                frame_tags: tags,
+            },
+        })
+    }
+}
+
+fn grid_positions(
+    sprites: &[Sprite],
+    columns: Option<u32>,
+    padding: u32,
+) -> orfail::Result<Vec<(u32, u32)>> {
+    let too_large = |()| format!("Too large sprite sheet (padding: {padding})");
+    let max = |f: fn(&Bitmap) -> u32| sprites.iter().map(|s| f(&s.bitmap)).max().unwrap_or(0);
+    let cell_width = max(Bitmap::width)
+        .checked_add(padding)
+        .or_fail_with(too_large)?;
+    let cell_height = max(Bitmap::height)
+        .checked_add(padding)
+        .or_fail_with(too_large)?;
+    let columns = columns
+        .unwrap_or_else(|| (sprites.len() as f64).sqrt().ceil() as u32)
+        .max(1);
+    (0..sprites.len() as u32)
+        .map(|i| {
+            let x = (i % columns).checked_mul(cell_width);
+            let y = (i / columns).checked_mul(cell_height);
+            x.zip(y).or_fail_with(too_large)
+        })
+        .collect()
+}
+
+fn shelf_positions(sprites: &[Sprite], padding: u32) -> orfail::Result<Vec<(u32, u32)>> {
+    let too_large = |()| format!("Too large sprite sheet (padding: {padding})");
+    let padding64 = u64::from(padding);
+    let area: u64 = sprites
+        .iter()
+        .map(|s| {
+            (u64::from(s.bitmap.width()) + padding64)
+                .saturating_mul(u64::from(s.bitmap.height()) + padding64)
+        })
+        .fold(0, u64::saturating_add);
+    let widest = sprites.iter().map(|s| s.bitmap.width()).max().unwrap_or(0);
+    let max_width = ((area as f64).sqrt().ceil() as u32).max(widest);
+
+    let mut order = (0..sprites.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| std::cmp::Reverse(sprites[i].bitmap.height()));
+
+    let mut positions = vec![(0, 0); sprites.len()];
+    let (mut x, mut y, mut shelf_height) = (0u32, 0u32, 0);
+    for i in order {
+        let bitmap = &sprites[i].bitmap;
+        if x > 0
+            && x.checked_add(bitmap.width())
+                .is_none_or(|end| end > max_width)
+        {
+            x = 0;
+            y = y
+                .checked_add(shelf_height)
+                .and_then(|y| y.checked_add(padding))
+                .or_fail_with(too_large)?;
+            shelf_height = 0;
+        }
+        positions[i] = (x, y);
+        x = x
+            .checked_add(bitmap.width())
+            .and_then(|x| x.checked_add(padding))
+            .or_fail_with(too_large)?;
+        shelf_height = shelf_height.max(bitmap.height());
+    }
+    Ok(positions)
+}
+
+#[derive(Debug, Serialize)]
+pub struct Atlas {
+    // Serialized as a JSON object preserving the sprite order (tags refer to sprites by index).
+    #[serde(serialize_with = "serialize_frames")]
+    frames: Vec<(String, AtlasFrame)>,
+    meta: AtlasMeta,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AtlasFrame {
+    frame: Rect,
+    rotated: bool,
+    trimmed: bool,
+    sprite_source_size: Rect,
+    source_size: Size,
+    duration: u32,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AtlasMeta {
+    app: &'static str,
+    version: &'static str,
+    image: String,
+    format: &'static str,
+    size: Size,
+    scale: &'static str,
+    frame_tags: Vec<Tag>,
+}
+
+#[derive(Debug, Serialize)]
+struct Rect {
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct Size {
+    w: u32,
+    h: u32,
+}
+
+fn serialize_frames<S: Serializer>(
+    frames: &[(String, AtlasFrame)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(frames.iter().map(|(name, frame)| (name, frame)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pati::Color;
+
+    fn sprite(name: &str, width: u32, height: u32) -> Sprite {
+        Sprite {
+            name: name.to_owned(),
+            bitmap: Bitmap::new(width, height, Some(Color::rgb(255, 0, 0))).expect("bitmap"),
+            duration_ms: 100,
+        }
+    }
+
+    fn sprites() -> Vec<Sprite> {
+        vec![sprite("a", 4, 2), sprite("b", 2, 4), sprite("c", 2, 2)]
+    }
+
+    fn assert_no_overlaps(sprites: &[Sprite], sheet: &Sheet) {
+        let rects = sprites
+            .iter()
+            .zip(&sheet.positions)
+            .map(|(s, &(x, y))| (x, y, x + s.bitmap.width(), y + s.bitmap.height()))
+            .collect::<Vec<_>>();
+        for (i, a) in rects.iter().enumerate() {
+            for b in &rects[i + 1..] {
+                assert!(a.2 <= b.0 || b.2 <= a.0 || a.3 <= b.1 || b.3 <= a.1);
+            }
+        }
+    }
+
+    #[test]
+    fn grid_layout_works() {
+        let sprites = sprites();
+        let sheet = Sheet::pack(&sprites, Layout::Grid, None, 1).expect("pack");
+        // 2 columns of 5x5 cells (the largest sprite size plus the padding).
+        assert_eq!(sheet.positions, [(0, 0), (5, 0), (0, 5)]);
+        assert_eq!((sheet.bitmap.width(), sheet.bitmap.height()), (7, 7));
+        assert_no_overlaps(&sprites, &sheet);
+
+        let sheet = Sheet::pack(&sprites, Layout::Grid, Some(3), 0).expect("pack");
+        assert_eq!(sheet.positions, [(0, 0), (4, 0), (8, 0)]);
+        assert_eq!((sheet.bitmap.width(), sheet.bitmap.height()), (10, 4));
+    }
+
+    #[test]
+    fn shelf_layout_works() {
+        let sprites = sprites();
+        let sheet = Sheet::pack(&sprites, Layout::Pack, None, 0).expect("pack");
+        // The shelf width is 5 (the square root of the total area), and sprites are placed in decreasing order of height:
+        // `b` fills the first shelf, and `a` and `c` don't fit next to each other.
+        assert_eq!(sheet.positions, [(0, 4), (0, 0), (0, 6)]);
+        assert_eq!((sheet.bitmap.width(), sheet.bitmap.height()), (4, 8));
+        assert_no_overlaps(&sprites, &sheet);
+
+        let sheet = Sheet::pack(&sprites, Layout::Pack, None, 1).expect("pack");
+        assert_no_overlaps(&sprites, &sheet);
+        assert_eq!(
+            sheet.bitmap.get_pixel(0, 0),
+            Some(Color::rgb(255, 0, 0)),
+            "{:?}",
+            sheet.positions
+        );
+    }
+
+    #[test]
+    fn too_large_sheets_are_rejected() {
+        let sprites = sprites();
+        assert!(Sheet::pack(&sprites, Layout::Grid, None, u32::MAX).is_err());
+        assert!(Sheet::pack(&sprites, Layout::Grid, Some(1), u32::MAX).is_err());
+        assert!(Sheet::pack(&sprites, Layout::Pack, None, u32::MAX).is_err());
+    }
+
+    #[test]
+    fn atlas_works() {
+        let sprites = sprites();
+        let sheet = Sheet::pack(&sprites, Layout::Grid, Some(3), 0).expect("pack");
+        let tags = vec![Tag::from_indices("ab", &[1, 0]).expect("tag")];
+        let atlas = sheet.atlas(&sprites, "sheet.png", tags).expect("atlas");
+        let json = serde_json::to_string(&atlas).expect("serialize");
+
+        // Frames are in the sprite order.
+        let (a, b, c) = (
+            json.find(r#""a":"#).expect("a"),
+            json.find(r#""b":"#).expect("b"),
+            json.find(r#""c":"#).expect("c"),
+        );
+        assert!(a < b && b < c);
+
+        let json: serde_json::Value = serde_json::from_str(&json).expect("parse");
+        assert_eq!(
+            json["frames"]["b"],
+            serde_json::json!({
+                "frame": {"x": 4, "y": 0, "w": 2, "h": 4},
+                "rotated": false,
+                "trimmed": false,
+                "spriteSourceSize": {"x": 0, "y": 0, "w": 2, "h": 4},
+                "sourceSize": {"w": 2, "h": 4},
+                "duration": 100,
+            })
+        );
+        assert_eq!(json["meta"]["image"], "sheet.png");
+        assert_eq!(json["meta"]["size"], serde_json::json!({"w": 10, "h": 4}));
+        assert_eq!(
+            json["meta"]["frameTags"],
+            serde_json::json!([{"name": "ab", "from": 0, "to": 1, "direction": "forward"}])
+        );
+    }
+
+    #[test]
+    fn atlas_rejects_duplicate_names() {
+        let sprites = vec![sprite("a", 1, 1), sprite("a", 2, 2)];
+        let sheet = Sheet::pack(&sprites, Layout::Grid, None, 0).expect("pack");
+        assert!(sheet.atlas(&sprites, "sheet.png", Vec::new()).is_err());
+    }
+
+    #[test]
+    fn tags_must_be_contiguous() {
+        let tag = Tag::from_indices("t", &[3, 2, 4]).expect("tag");
+        assert_eq!((tag.from, tag.to), (2, 4));
+        assert!(Tag::from_indices("t", &[0, 2]).is_err());
+        assert!(Tag::from_indices("t", &[]).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use orfail::OrFail;
+use pati::{Color, Point};
+use std::{collections::BTreeMap, io::Write, num::NonZeroU32};
+
+/// Writes an SVG image of the pixels in the given region (inclusive).
+///
+/// Adjacent same-color pixels are merged into rectangles, and the rectangles of each color are
+/// written as a single `<path>` element.
+pub fn write_image<W: Write>(
+    mut writer: W,
+    start: Point,
+    end: Point,
+    pixels: impl Iterator<Item = (Point, Color)>,
+    scale: NonZeroU32,
+    background: Option<Color>,
+) -> orfail::Result<()> {
+    let width = (i32::from(end.x) - i32::from(start.x) + 1).max(0) as u32;
+    let height = (i32::from(end.y) - i32::from(start.y) + 1).max(0) as u32;
+    // Keyed by the (y, x) offsets from `start`, computed in `i32` as wide regions don't fit in `i16`.
+    let pixels = pixels
+        .filter(|(_, c)| c.a != 0)
+        .map(|(p, c)| {
+            let y = i32::from(p.y) - i32::from(start.y);
+            let x = i32::from(p.x) - i32::from(start.x);
+            ((y, x), c)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    writeln!(
+        writer,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {width} {height}" shape-rendering="crispEdges">"#,
+        u64::from(width) * u64::from(scale.get()),
+        u64::from(height) * u64::from(scale.get()),
+    )
+    .or_fail()?;
+    if let Some(color) = background {
+        writeln!(
+            writer,
+            r#"<rect width="{width}" height="{height}" {}/>"#,
+            fill(color)
+        )
+        .or_fail()?;
+    }
+    for (color, rects) in merge_rects(&pixels) {
+        write!(writer, r#"<path {} d=""#, fill(color)).or_fail()?;
+        for (i, rect) in rects.iter().enumerate() {
+            if i > 0 {
+                write!(writer, " ").or_fail()?;
+            }
+            write!(
+                writer,
+                "M{},{}h{}v{}h-{}z",
+                rect.x, rect.y, rect.width, rect.height, rect.width
+            )
+            .or_fail()?;
+        }
+        writeln!(writer, r#""/>"#).or_fail()?;
+    }
+    writeln!(writer, "</svg>").or_fail()?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rect {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+
+/// Merges pixels into horizontal runs, then merges runs having the same span in consecutive rows.
+fn merge_rects(pixels: &BTreeMap<(i32, i32), Color>) -> BTreeMap<Color, Vec<Rect>> {
+    // Horizontal runs grouped by row (`pixels` is keyed by (y, x), so it is already in row order).
+    let mut rows = BTreeMap::<i32, Vec<(Rect, Color)>>::new();
+    for (&(y, x), &color) in pixels {
+        let row = rows.entry(y).or_default();
+        match row.last_mut() {
+            Some((run, c)) if *c == color && run.x + run.width == x => run.width += 1,
+            _ => row.push((
+                Rect {
+                    x,
+                    y,
+                    width: 1,
+                    height: 1,
+                },
+                color,
+            )),
+        }
+    }
+
+    // Vertical merging of runs that have the same color and span as an open rectangle in the previous row.
+    let mut rects = BTreeMap::<Color, Vec<Rect>>::new();
+    let mut open = Vec::<(Rect, Color)>::new();
+    let mut prev_y = None;
+    for (y, runs) in rows {
+        let mut next_open = Vec::new();
+        let contiguous = prev_y == Some(y - 1);
+        for (run, color) in runs {
+            let i = open.iter().position(|(r, c)| {
+                contiguous && *c == color && r.x == run.x && r.width == run.width
+            });
+            if let Some(i) = i {
+                let (mut rect, color) = open.swap_remove(i);
+                rect.height += 1;
+                next_open.push((rect, color));
+            } else {
+                next_open.push((run, color));
+            }
+        }
+        for (rect, color) in std::mem::replace(&mut open, next_open) {
+            rects.entry(color).or_default().push(rect);
+        }
+        prev_y = Some(y);
+    }
+    for (rect, color) in open {
+        rects.entry(color).or_default().push(rect);
+    }
+    rects
+}
+
+fn fill(color: Color) -> String {
+    let rgb = format!(r##"fill="#{:02x}{:02x}{:02x}""##, color.r, color.g, color.b);
+    if color.a == 255 {
+        rgb
+    } else {
+        format!(r#"{rgb} fill-opacity="{:.3}""#, f64::from(color.a) / 255.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_image_works() {
+        let red = Color::rgb(255, 0, 0);
+        let blue = Color::rgba(0, 0, 255, 128);
+        let pixels = [
+            (Point::new(10, 20), red),
+            (Point::new(11, 20), red),
+            (Point::new(12, 20), Color::rgba(0, 255, 0, 0)),
+            (Point::new(10, 21), red),
+            (Point::new(11, 21), blue),
+            (Point::new(12, 21), blue),
+        ];
+        let mut svg = Vec::new();
+        write_image(
+            &mut svg,
+            Point::new(10, 20),
+            Point::new(12, 21),
+            pixels.into_iter(),
+            NonZeroU32::new(2).expect("unreachable"),
+            Some(Color::rgb(255, 255, 255)),
+        )
+        .expect("write");
+        assert_eq!(
+            String::from_utf8(svg).expect("UTF-8"),
+            concat!(
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="6" height="4" viewBox="0 0 3 2" shape-rendering="crispEdges">"#,
+                "\n",
+                r##"<rect width="3" height="2" fill="#ffffff"/>"##,
+                "\n",
+                r##"<path fill="#0000ff" fill-opacity="0.502" d="M1,1h2v1h-2z"/>"##,
+                "\n",
+                r##"<path fill="#ff0000" d="M0,0h2v1h-2z M0,1h1v1h-1z"/>"##,
+                "\n",
+                "</svg>\n",
+            )
+        );
+    }
+
+    #[test]
+    fn write_image_handles_wide_regions() {
+        let red = Color::rgb(255, 0, 0);
+        let pixels = [(Point::new(-20000, 0), red), (Point::new(20000, 0), red)];
+        let mut svg = Vec::new();
+        write_image(
+            &mut svg,
+            Point::new(-20000, 0),
+            Point::new(20000, 0),
+            pixels.into_iter(),
+            NonZeroU32::new(1).expect("unreachable"),
+            None,
+        )
+        .expect("write");
+        let svg = String::from_utf8(svg).expect("UTF-8");
+        assert!(svg.contains(r#"viewBox="0 0 40001 1""#), "{svg}");
+        assert!(svg.contains("M0,0h1v1h-1z M40000,0h1v1h-1z"), "{svg}");
+    }
+
+    #[test]
+    fn merge_rects_merges_same_spans_in_consecutive_rows() {
+        let red = Color::rgb(255, 0, 0);
+        let pixels = [(0, 0), (1, 0), (0, 1), (1, 1), (0, 3), (1, 3)]
+            .into_iter()
+            .map(|(x, y)| ((y, x), red))
+            .collect::<BTreeMap<_, _>>();
+        let rect = |x, y, width, height| Rect {
+            x,
+            y,
+            width,
+            height,
+        };
+        assert_eq!(
+            merge_rects(&pixels)[&red],
+            [rect(0, 0, 2, 2), rect(0, 3, 2, 1)]
+        );
+    }
+}
@@ -0,0 +1,476 @@
+//! Export to the [Tiled](https://www.mapeditor.org/) map editor formats (`.tsx` tileset and `.tmj` map).
+use crate::bitmap::Bitmap;
+use orfail::OrFail;
+use pati::{Metadata, Point};
+use paticanvas::TILE_PROPERTIES;
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, io::Write};
+
+/// Global tile ID flag of a horizontally flipped tile.
+pub const FLIPPED_HORIZONTALLY: u32 = 0x8000_0000;
+
+/// Global tile ID flag of a vertically flipped tile.
+pub const FLIPPED_VERTICALLY: u32 = 0x4000_0000;
+
+/// Global tile ID flag of a diagonally flipped (i.e., transposed) tile.
+pub const FLIPPED_DIAGONALLY: u32 = 0x2000_0000;
+
+const FLAGS: u32 = FLIPPED_HORIZONTALLY | FLIPPED_VERTICALLY | FLIPPED_DIAGONALLY;
+
+const TILED_VERSION: &str = "1.10";
+
+/// Image sliced into a grid of deduplicated tiles.
+#[derive(Debug, Clone)]
+pub struct Tileset {
+    pub tile_width: u32,
+    pub tile_height: u32,
+
+    /// Distinct tiles.
+    pub tiles: Vec<Bitmap>,
+
+    /// Number of the columns of the map.
+    pub map_width: u32,
+
+    /// Number of the rows of the map.
+    pub map_height: u32,
+
+    /// Global tile IDs (with flip flags) of the map cells in row-major order (`0` means empty).
+    pub cells: Vec<u32>,
+}
+
+impl Tileset {
+    /// Slices the given bitmap into tiles.
+    ///
+    /// Fully transparent cells become empty.
+    /// If `transform` is `true`, flipped (and rotated, for square tiles) variants of a tile
+    /// are deduplicated and referred to by the flip flags of the cells.
+    pub fn slice(
+        bitmap: &Bitmap,
+        tile_width: u32,
+        tile_height: u32,
+        transform: bool,
+    ) -> orfail::Result<Self> {
+        let map_width = bitmap.width().div_ceil(tile_width);
+        let map_height = bitmap.height().div_ceil(tile_height);
+        let variants = match (transform, tile_width == tile_height) {
+            (false, _) => vec![0],
+            (true, false) => vec![
+                0,
+                FLIPPED_HORIZONTALLY,
+                FLIPPED_VERTICALLY,
+                FLIPPED_HORIZONTALLY | FLIPPED_VERTICALLY,
+            ],
+            (true, true) => (0..8).map(|i| i << 29).collect(),
+        };
+
+        let mut tiles = Vec::new();
+        let mut known = BTreeMap::new();
+        let mut cells = Vec::with_capacity((map_width * map_height) as usize);
+        for row in 0..map_height {
+            for column in 0..map_width {
+                let mut tile = Bitmap::new(tile_width, tile_height, None).or_fail()?;
+                for y in 0..tile_height {
+                    for x in 0..tile_width {
+                        let (sx, sy) = (column * tile_width + x, row * tile_height + y);
+                        if let Some(color) = bitmap.get_pixel(sx, sy) {
+                            tile.set_pixel(x, y, color);
+                        }
+                    }
+                }
+                if tile.pixels().iter().all(|c| c.a == 0) {
+                    cells.push(0);
+                    continue;
+                }
+                if let Some(&gid) = known.get(tile.pixels()) {
+                    cells.push(gid);
+                    continue;
+                }
+                let gid = tiles.len() as u32 + 1;
+                for &flags in &variants {
+                    known
+                        .entry(transformed(&tile, flags).or_fail()?.pixels().to_vec())
+                        .or_insert(gid | flags);
+                }
+                tiles.push(tile);
+                cells.push(gid);
+            }
+        }
+        Ok(Self {
+            tile_width,
+            tile_height,
+            tiles,
+            map_width,
+            map_height,
+            cells,
+        })
+    }
+
+    /// Gets the index of the tile referred to by the given global tile ID.
+    pub fn tile_index(gid: u32) -> Option<usize> {
+        (gid & !FLAGS).checked_sub(1).map(|i| i as usize)
+    }
+
+    /// Collects the properties of the tiles from the [`TILE_PROPERTIES`] metadata namespace.
+    ///
+    /// `start` is the image coordinates of the top-left pixel of the sliced bitmap, and the keys of the result are tile indices.
+    /// If several items refer to the same tile and have the same property, the first one (in the order of the item names) is used.
+    /// Items that refer to empty cells or points outside of the map are ignored.
+    pub fn properties(
+        &self,
+        metadata: &Metadata,
+        start: Point,
+    ) -> orfail::Result<BTreeMap<usize, BTreeMap<String, Value>>> {
+        let mut properties = BTreeMap::<usize, BTreeMap<String, Value>>::new();
+        for (name, values) in TILE_PROPERTIES.iter(metadata) {
+            let point = parse_point(name).or_fail_with(|()| {
+                format!(
+                    "Invalid tile point {:?} (expected `{{x}},{{y}}`)",
+                    TILE_PROPERTIES.item_name(name)
+                )
+            })?;
+            let values = values.or_fail_with(|e| {
+                format!(
+                    "Invalid tile properties {:?}: {e}",
+                    TILE_PROPERTIES.item_name(name)
+                )
+            })?;
+            let (Ok(x), Ok(y)) = (
+                u32::try_from(i32::from(point.x) - i32::from(start.x)),
+                u32::try_from(i32::from(point.y) - i32::from(start.y)),
+            ) else {
+                continue;
+            };
+            let (column, row) = (x / self.tile_width, y / self.tile_height);
+            if column >= self.map_width || row >= self.map_height {
+                continue;
+            }
+            let gid = self.cells[(row * self.map_width + column) as usize];
+            let Some(tile) = Self::tile_index(gid) else {
+                continue;
+            };
+            let tile_properties = properties.entry(tile).or_default();
+            for (name, value) in values {
+                tile_properties.entry(name).or_insert(value);
+            }
+        }
+        Ok(properties)
+    }
+
+    /// Number of the columns of the tileset image (square-ish if not specified).
+    pub fn columns(&self, columns: Option<u32>) -> u32 {
+        columns
+            .unwrap_or_else(|| (self.tiles.len() as f64).sqrt().ceil() as u32)
+            .clamp(1, self.tiles.len().max(1) as u32)
+    }
+
+    /// Makes the tileset image.
+    pub fn image(&self, columns: u32) -> orfail::Result<Bitmap> {
+        let rows = (self.tiles.len() as u32).div_ceil(columns);
+        let width = columns.checked_mul(self.tile_width).or_fail()?;
+        let height = rows.checked_mul(self.tile_height).or_fail()?;
+        let mut bitmap = Bitmap::new(width, height, None).or_fail()?;
+        for (i, tile) in self.tiles.iter().enumerate() {
+            let (column, row) = (i as u32 % columns, i as u32 / columns);
+            bitmap.copy_from(column * self.tile_width, row * self.tile_height, tile);
+        }
+        Ok(bitmap)
+    }
+
+    /// Writes the tileset in the TSX (XML) format.
+    pub fn write_tsx<W: Write>(
+        &self,
+        mut writer: W,
+        name: &str,
+        image_source: &str,
+        columns: u32,
+        properties: &BTreeMap<usize, BTreeMap<String, Value>>,
+    ) -> orfail::Result<()> {
+        let image = self.image(columns).or_fail()?;
+        writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#).or_fail()?;
+        writeln!(
+            writer,
+            r#"<tileset version="{TILED_VERSION}" name="{}" tilewidth="{}" tileheight="{}" tilecount="{}" columns="{columns}">"#,
+            escape(name),
+            self.tile_width,
+            self.tile_height,
+            self.tiles.len()
+        )
+        .or_fail()?;
+        writeln!(
+            writer,
+            r#" <image source="{}" width="{}" height="{}"/>"#,
+            escape(image_source),
+            image.width(),
+            image.height()
+        )
+        .or_fail()?;
+        for (id, properties) in properties.iter().filter(|(_, p)| !p.is_empty()) {
+            writeln!(writer, r#" <tile id="{id}">"#).or_fail()?;
+            writeln!(writer, "  <properties>").or_fail()?;
+            for (name, value) in properties {
+                let (ty, value) = match value {
+                    Value::Bool(v) => (Some("bool"), v.to_string()),
+                    Value::Number(v) if v.is_f64() => (Some("float"), v.to_string()),
+                    Value::Number(v) => (Some("int"), v.to_string()),
+                    Value::String(v) => (None, v.clone()),
+                    _ => (None, value.to_string()),
+                };
+                let ty = ty.map(|ty| format!(r#" type="{ty}""#)).unwrap_or_default();
+                writeln!(
+                    writer,
+                    r#"   <property name="{}"{ty} value="{}"/>"#,
+                    escape(name),
+                    escape(&value)
+                )
+                .or_fail()?;
+            }
+            writeln!(writer, "  </properties>").or_fail()?;
+            writeln!(writer, " </tile>").or_fail()?;
+        }
+        writeln!(writer, "</tileset>").or_fail()?;
+        Ok(())
+    }
+
+    /// Makes a map in the TMJ (JSON) format that has a single tile layer referring to the given tileset file.
+    pub fn map_json(&self, tileset_source: &str) -> Value {
+        json!({
+            "type": "map",
+            "version": TILED_VERSION,
+            "orientation": "orthogonal",
+            "renderorder": "right-down",
+            "infinite": false,
+            "width": self.map_width,
+            "height": self.map_height,
+            "tilewidth": self.tile_width,
+            "tileheight": self.tile_height,
+            "nextlayerid": 2,
+            "nextobjectid": 1,
+            "layers": [{
+                "id": 1,
+                "name": "Tile Layer 1",
+                "type": "tilelayer",
+                "x": 0,
+                "y": 0,
+                "width": self.map_width,
+                "height": self.map_height,
+                "opacity": 1,
+                "visible": true,
+                "data": self.cells,
+            }],
+            "tilesets": [{
+                "firstgid": 1,
+                "source": tileset_source,
+            }],
+        })
+    }
+}
+
+/// Makes the bitmap of a tile as rendered by Tiled with the given flip flags
+/// (diagonal flip first, then horizontal and vertical flips).
+fn transformed(tile: &Bitmap, flags: u32) -> orfail::Result<Bitmap> {
+    let diagonal = flags & FLIPPED_DIAGONALLY != 0;
+    let (w, h) = if diagonal {
+        (tile.height(), tile.width())
+    } else {
+        (tile.width(), tile.height())
+    };
+    let mut bitmap = Bitmap::new(w, h, None).or_fail()?;
+    for y in 0..h {
+        for x in 0..w {
+            let x0 = if flags & FLIPPED_HORIZONTALLY != 0 {
+                w - 1 - x
+            } else {
+                x
+            };
+            let y0 = if flags & FLIPPED_VERTICALLY != 0 {
+                h - 1 - y
+            } else {
+                y
+            };
+            let (sx, sy) = if diagonal { (y0, x0) } else { (x0, y0) };
+            if let Some(color) = tile.get_pixel(sx, sy) {
+                bitmap.set_pixel(x, y, color);
+            }
+        }
+    }
+    Ok(bitmap)
+}
+
+fn parse_point(s: &str) -> Option<Point> {
+    let (x, y) = s.split_once(',')?;
+    let parse = |v: &str| v.trim().parse::<i16>().ok();
+    Some(Point::new(parse(x)?, parse(y)?))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pati::Color;
+
+    const R: Color = Color::rgb(255, 0, 0);
+    const G: Color = Color::rgb(0, 255, 0);
+    const B: Color = Color::rgb(0, 0, 255);
+    const K: Color = Color::rgb(0, 0, 0);
+
+    /// Lays out 2x2 tiles (given in row-major order) horizontally.
+    fn tile_row(tiles: &[[Color; 4]]) -> Bitmap {
+        let mut bitmap = Bitmap::new(tiles.len() as u32 * 2, 2, None).expect("bitmap");
+        for (i, tile) in tiles.iter().enumerate() {
+            for (j, color) in tile.iter().enumerate() {
+                bitmap.set_pixel(i as u32 * 2 + j as u32 % 2, j as u32 / 2, *color);
+            }
+        }
+        bitmap
+    }
+
+    fn tiles() -> Bitmap {
+        let transparent = Color::rgba(0, 0, 0, 0);
+        tile_row(&[
+            [R, G, B, K],
+            // Flipped horizontally.
+            [G, R, K, B],
+            // Flipped diagonally (transposed).
+            [R, B, G, K],
+            // Rotated 90 degrees clockwise (flipped diagonally, then horizontally).
+            [B, R, K, G],
+            [transparent; 4],
+        ])
+    }
+
+    #[test]
+    fn slice_works() {
+        let tileset = Tileset::slice(&tiles(), 2, 2, true).expect("slice");
+        assert_eq!(tileset.tiles, [tile_row(&[[R, G, B, K]])]);
+        assert_eq!((tileset.map_width, tileset.map_height), (5, 1));
+        assert_eq!(
+            tileset.cells,
+            [
+                1,
+                1 | FLIPPED_HORIZONTALLY,
+                1 | FLIPPED_DIAGONALLY,
+                1 | FLIPPED_DIAGONALLY | FLIPPED_HORIZONTALLY,
+                0
+            ]
+        );
+        assert_eq!(Tileset::tile_index(tileset.cells[3]), Some(0));
+        assert_eq!(Tileset::tile_index(tileset.cells[4]), None);
+
+        let tileset = Tileset::slice(&tiles(), 2, 2, false).expect("slice");
+        assert_eq!(tileset.tiles.len(), 4);
+        assert_eq!(tileset.cells, [1, 2, 3, 4, 0]);
+    }
+
+    #[test]
+    fn slice_does_not_rotate_non_square_tiles() {
+        // The transposed variant of a 2x1 tile is 1x2, so only the flips are deduplicated.
+        let mut bitmap = Bitmap::new(4, 1, None).expect("bitmap");
+        for (x, color) in [R, G, G, R].into_iter().enumerate() {
+            bitmap.set_pixel(x as u32, 0, color);
+        }
+        let tileset = Tileset::slice(&bitmap, 2, 1, true).expect("slice");
+        assert_eq!(tileset.tiles.len(), 1);
+        assert_eq!(tileset.cells, [1, 1 | FLIPPED_HORIZONTALLY]);
+    }
+
+    #[test]
+    fn write_tsx_works() {
+        let tileset = Tileset::slice(&tiles(), 2, 2, true).expect("slice");
+        let properties = [(
+            0,
+            [
+                ("n".to_owned(), json!(3)),
+                ("name".to_owned(), json!("<wall>")),
+                ("solid".to_owned(), json!(true)),
+                ("weight".to_owned(), json!(1.5)),
+            ]
+            .into_iter()
+            .collect(),
+        )]
+        .into_iter()
+        .collect();
+        let mut tsx = Vec::new();
+        tileset
+            .write_tsx(&mut tsx, "a&b", "a.png", tileset.columns(None), &properties)
+            .expect("write");
+        assert_eq!(
+            String::from_utf8(tsx).expect("UTF-8"),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<tileset version="1.10" name="a&amp;b" tilewidth="2" tileheight="2" tilecount="1" columns="1">
+ <image source="a.png" width="2" height="2"/>
+ <tile id="0">
+  <properties>
+   <property name="n" type="int" value="3"/>
+   <property name="name" value="&lt;wall&gt;"/>
+   <property name="solid" type="bool" value="true"/>
+   <property name="weight" type="float" value="1.5"/>
+  </properties>
+ </tile>
+</tileset>
+"#
+        );
+    }
+
+    #[test]
+    fn tile_properties_come_from_metadata() {
+        let tileset = Tileset::slice(&tiles(), 2, 2, false).expect("slice");
+        let mut image = pati::Image::new();
+        let start = Point::new(10, 20);
+        let put = |name: &str, value| TILE_PROPERTIES.put(name, &value).expect("put");
+        for command in [
+            put("11,21", [("solid".to_owned(), json!(true))].into()),
+            // The same tile as above (this item comes first in the name order, so its value is used).
+            put("10,20", [("solid".to_owned(), json!(false))].into()),
+            put("12,20", [("name".to_owned(), json!("b"))].into()),
+            // Empty cell and out of the map.
+            put("18,20", [("x".to_owned(), json!(1))].into()),
+            put("9,20", [("y".to_owned(), json!(1))].into()),
+        ] {
+            assert!(image.apply(&command));
+        }
+        let properties = tileset
+            .properties(image.metadata(), start)
+            .expect("properties");
+
+        let mut tsx = Vec::new();
+        tileset
+            .write_tsx(&mut tsx, "t", "t.png", 4, &properties)
+            .expect("write");
+        let tsx = String::from_utf8(tsx).expect("UTF-8");
+        assert!(tsx.contains(
+            r#" <tile id="0">
+  <properties>
+   <property name="solid" type="bool" value="false"/>
+  </properties>
+ </tile>
+ <tile id="1">
+  <properties>
+   <property name="name" value="b"/>
+  </properties>
+ </tile>
+</tileset>"#
+        ));
+
+        assert!(image.apply(&pati::ImageCommand::put("patica.tile.a", json!({}))));
+        assert!(tileset.properties(image.metadata(), start).is_err());
+    }
+
+    #[test]
+    fn map_json_works() {
+        let tileset = Tileset::slice(&tiles(), 2, 2, true).expect("slice");
+        let map = tileset.map_json("a.tsx");
+        assert_eq!(map["width"], json!(5));
+        assert_eq!(map["height"], json!(1));
+        assert_eq!(
+            map["layers"][0]["data"],
+            json!([1, 0x8000_0001u32, 0x2000_0001u32, 0xA000_0001u32, 0])
+        );
+        assert_eq!(map["tilesets"], json!([{"firstgid": 1, "source": "a.tsx"}]));
+    }
+}
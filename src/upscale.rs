@@ -0,0 +1,496 @@
+//! Pixel-art upscaling filters.
+use crate::bitmap::Bitmap;
+use orfail::OrFail;
+use pati::Color;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Upscaler {
+    /// Scale2x (EPX), 2x.
+    Scale2x,
+
+    /// Scale3x, 3x.
+    Scale3x,
+
+    /// hq2x-style, 2x (an approximation of hq2x, not the reference lookup tables).
+    Hq2x,
+
+    /// hq4x-style, 4x (an approximation of hq4x, not the reference lookup tables).
+    Hq4x,
+
+    /// 2xBR (xBR level 1), 2x.
+    Xbr,
+}
+
+impl Upscaler {
+    pub const fn factor(self) -> u32 {
+        match self {
+            Self::Scale2x | Self::Hq2x | Self::Xbr => 2,
+            Self::Scale3x => 3,
+            Self::Hq4x => 4,
+        }
+    }
+
+    pub fn apply(self, src: &Bitmap) -> orfail::Result<Bitmap> {
+        let n = self.factor();
+        let (width, height) = src.scaled_size(n).or_fail()?;
+        let mut dst = Bitmap::new(width, height, None).or_fail()?;
+        for y in 0..src.height() {
+            for x in 0..src.width() {
+                let block = Neighbors::new(src, x, y);
+                let outputs = match self {
+                    Self::Scale2x => scale2x(&block).to_vec(),
+                    Self::Scale3x => scale3x(&block).to_vec(),
+                    Self::Hq2x => hqx(&block, &HQ2X, 2),
+                    Self::Hq4x => hqx(&block, &HQ4X, 4),
+                    Self::Xbr => xbr(&block).to_vec(),
+                };
+                for (i, color) in outputs.into_iter().enumerate() {
+                    let i = i as u32;
+                    dst.set_pixel(x * n + i % n, y * n + i / n, color);
+                }
+            }
+        }
+        Ok(dst)
+    }
+}
+
+/// 5x5 neighborhood of a pixel (clamped at the edges).
+///
+/// ```text
+///     A1 B1 C1
+///  A0 A  B  C  C4
+///  D0 D  E  F  F4
+///  G0 G  H  I  I4
+///     G5 H5 I5
+/// ```
+#[derive(Debug)]
+struct Neighbors([[Color; 5]; 5]);
+
+impl Neighbors {
+    fn new(bitmap: &Bitmap, x: u32, y: u32) -> Self {
+        let mut pixels = [[Color::rgba(0, 0, 0, 0); 5]; 5];
+        for (dy, row) in pixels.iter_mut().enumerate() {
+            for (dx, pixel) in row.iter_mut().enumerate() {
+                let px = (x as i64 + dx as i64 - 2).clamp(0, bitmap.width() as i64 - 1);
+                let py = (y as i64 + dy as i64 - 2).clamp(0, bitmap.height() as i64 - 1);
+                *pixel = bitmap.get_pixel(px as u32, py as u32).expect("unreachable");
+            }
+        }
+        Self(pixels)
+    }
+
+    /// Gets the pixel at the given offset from the center.
+    fn at(&self, dx: i32, dy: i32) -> Color {
+        self.0[(dy + 2) as usize][(dx + 2) as usize]
+    }
+
+    /// Gets the pixel at the given offset from the center, rotated by `r` quarter turns clockwise.
+    ///
+    /// This is used to write the rules for one corner (bottom-right) and apply them to all corners.
+    fn rotated(&self, r: u8, dx: i32, dy: i32) -> Color {
+        let (dx, dy) = match r % 4 {
+            0 => (dx, dy),
+            1 => (-dy, dx),
+            2 => (-dx, -dy),
+            _ => (dy, -dx),
+        };
+        self.at(dx, dy)
+    }
+}
+
+fn scale2x(n: &Neighbors) -> [Color; 4] {
+    let (b, d, e, f, h) = (n.at(0, -1), n.at(-1, 0), n.at(0, 0), n.at(1, 0), n.at(0, 1));
+    if b != h && d != f {
+        [
+            if d == b { d } else { e },
+            if b == f { f } else { e },
+            if d == h { d } else { e },
+            if h == f { f } else { e },
+        ]
+    } else {
+        [e; 4]
+    }
+}
+
+fn scale3x(n: &Neighbors) -> [Color; 9] {
+    let (a, b, c) = (n.at(-1, -1), n.at(0, -1), n.at(1, -1));
+    let (d, e, f) = (n.at(-1, 0), n.at(0, 0), n.at(1, 0));
+    let (g, h, i) = (n.at(-1, 1), n.at(0, 1), n.at(1, 1));
+    if b == h || d == f {
+        return [e; 9];
+    }
+    let pick = |cond: bool, c: Color| if cond { c } else { e };
+    [
+        pick(d == b, d),
+        pick((d == b && e != c) || (b == f && e != a), b),
+        pick(b == f, f),
+        pick((d == b && e != g) || (d == h && e != a), d),
+        e,
+        pick((b == f && e != i) || (h == f && e != c), f),
+        pick(d == h, d),
+        pick((d == h && e != i) || (h == f && e != g), h),
+        pick(h == f, f),
+    ]
+}
+
+/// Interpolation of an output pixel of hqx: the weights of the center, `A`, `B` and `C` pixels of a corner
+/// (see [`HQX_CORNERS`]).
+type Weights = [u8; 4];
+
+const CENTER: Weights = [1, 0, 0, 0];
+
+/// Interpolations of the output pixels in a corner quadrant of hqx,
+/// indexed by `y * size / 2 + x` where `(0, 0)` is the outermost pixel, `x` grows away from `A` and `y` away from `B`.
+///
+/// Like the `Diff(w[4], w[2])` checks in the case tables of hqx, which one is used depends on
+/// whether `A` and `B` differ from each other.
+#[derive(Debug, Clone, Copy)]
+struct HqxCase {
+    diff: [Weights; 4],
+    same: [Weights; 4],
+}
+
+/// Offsets of the `C` (diagonal), `A` and `B` neighbors of each corner (top-left, top-right, bottom-right, bottom-left),
+/// and the bits of the neighbors in the hqx pattern.
+///
+/// The corners are in clockwise order, so the rules for the top-left corner apply to all of them.
+const HQX_CORNERS: [[(i32, i32, u8); 3]; 4] = [
+    [(-1, -1, 1), (-1, 0, 8), (0, -1, 2)],
+    [(1, -1, 4), (0, -1, 2), (1, 0, 16)],
+    [(1, 1, 128), (1, 0, 16), (0, 1, 64)],
+    [(-1, 1, 32), (0, 1, 64), (-1, 0, 8)],
+];
+
+/// Lookup tables from the hqx patterns (bit `i` is set if the `i`-th neighbor in row-major order differs from the center)
+/// to the cases of the corners.
+///
+/// These are not the reference tables of hqx: instead of spelling out the 256 cases of each scale, the tables are generated
+/// at compile time from simplified rules of a corner (see [`hqx_case()`]) that use the interpolations of hqx
+/// (e.g., `Interp10` for isolated pixels). So the output approximates hqx, and differs from it for some patterns.
+static HQ2X: [[HqxCase; 4]; 256] = hqx_table(2);
+static HQ4X: [[HqxCase; 4]; 256] = hqx_table(4);
+
+const fn hqx_table(size: u32) -> [[HqxCase; 4]; 256] {
+    let mut table = [[HqxCase {
+        diff: [CENTER; 4],
+        same: [CENTER; 4],
+    }; 4]; 256];
+    let mut pattern = 0;
+    while pattern < 256 {
+        let mut corner = 0;
+        while corner < 4 {
+            let [(_, _, c), (_, _, a), (_, _, b)] = HQX_CORNERS[corner];
+            let p = pattern as u8;
+            table[pattern][corner] = hqx_case(p, p & a != 0, p & b != 0, p & c != 0, size);
+            corner += 1;
+        }
+        pattern += 1;
+    }
+    table
+}
+
+/// Rules of a corner of hqx, where `da`, `db` and `dc` tell whether `A`, `B` and `C` differ from the center.
+const fn hqx_case(pattern: u8, da: bool, db: bool, dc: bool, size: u32) -> HqxCase {
+    // Edges crossing the corner (hq2x).
+    let outer = match (da, db, dc) {
+        (false, false, _) => [2, 1, 1, 0],
+        (false, true, false) => [2, 1, 0, 1],
+        (false, true, true) => [3, 1, 0, 0],
+        (true, false, false) => [2, 0, 1, 1],
+        (true, false, true) => [3, 0, 1, 0],
+        (true, true, _) => [0; 4],
+    };
+    let diff_outer = if dc { CENTER } else { [3, 0, 0, 1] };
+    let same_outer = if pattern == 255 {
+        // Isolated pixel.
+        [14, 1, 1, 0]
+    } else if !dc && pattern & 90 == 90 {
+        // Thin diagonal line.
+        [6, 1, 1, 0]
+    } else {
+        [2, 1, 1, 0]
+    };
+    if size == 2 {
+        return match (da, db) {
+            (true, true) => HqxCase {
+                diff: [diff_outer, CENTER, CENTER, CENTER],
+                same: [same_outer, CENTER, CENTER, CENTER],
+            },
+            _ => HqxCase {
+                diff: [outer, CENTER, CENTER, CENTER],
+                same: [outer, CENTER, CENTER, CENTER],
+            },
+        };
+    }
+
+    // The outermost pixel follows hq2x and the others get closer to the center (hq4x).
+    let pixels = match (da, db) {
+        (false, false) => [outer, [5, 1, 2, 0], [5, 2, 1, 0], [6, 1, 1, 0]],
+        (false, true) => {
+            let b = if dc { CENTER } else { [7, 0, 0, 1] };
+            [outer, b, [7, 1, 0, 0], CENTER]
+        }
+        (true, false) => {
+            let a = if dc { CENTER } else { [7, 0, 0, 1] };
+            [outer, [7, 0, 1, 0], a, CENTER]
+        }
+        (true, true) => {
+            let same = if !dc {
+                [same_outer, [14, 1, 1, 0], [14, 1, 1, 0], CENTER]
+            } else if pattern == 255 {
+                [[2, 1, 1, 0], [6, 1, 1, 0], [6, 1, 1, 0], CENTER]
+            } else {
+                // Diagonal edge cutting the corner.
+                [[0, 1, 1, 0], [2, 1, 1, 0], [2, 1, 1, 0], CENTER]
+            };
+            return HqxCase {
+                diff: [diff_outer, CENTER, CENTER, CENTER],
+                same,
+            };
+        }
+    };
+    HqxCase {
+        diff: pixels,
+        same: pixels,
+    }
+}
+
+/// hqx-style upscaling (approximations of hq2x and hq4x).
+///
+/// The neighbors that differ from the center in YUV (with the hqx thresholds) make an 8-bit pattern,
+/// and the lookup table gives the interpolations of the output pixels in each corner for the pattern.
+fn hqx(n: &Neighbors, table: &[[HqxCase; 4]; 256], size: u32) -> Vec<Color> {
+    let e = n.at(0, 0);
+    let mut pattern = 0usize;
+    let offsets = [
+        (-1, -1),
+        (0, -1),
+        (1, -1),
+        (-1, 0),
+        (1, 0),
+        (-1, 1),
+        (0, 1),
+        (1, 1),
+    ];
+    for (i, (dx, dy)) in offsets.into_iter().enumerate() {
+        if yuv_differs(e, n.at(dx, dy)) {
+            pattern |= 1 << i;
+        }
+    }
+
+    let half = size / 2;
+    let mut outputs = vec![e; (size * size) as usize];
+    for (corner, case) in table[pattern].iter().enumerate() {
+        let [(cx, cy, _), (ax, ay, _), (bx, by, _)] = HQX_CORNERS[corner];
+        let (c, a, b) = (n.at(cx, cy), n.at(ax, ay), n.at(bx, by));
+        let pixels = if yuv_differs(a, b) {
+            &case.diff
+        } else {
+            &case.same
+        };
+        for y in 0..half {
+            for x in 0..half {
+                let [we, wa, wb, wc] = pixels[(y * half + x) as usize];
+                let color = mix(&[
+                    (e, u32::from(we)),
+                    (a, u32::from(wa)),
+                    (b, u32::from(wb)),
+                    (c, u32::from(wc)),
+                ]);
+                let (col, row) = match corner {
+                    0 => (x, y),
+                    1 => (size - 1 - y, x),
+                    2 => (size - 1 - x, size - 1 - y),
+                    _ => (y, size - 1 - x),
+                };
+                outputs[(row * size + col) as usize] = color;
+            }
+        }
+    }
+    outputs
+}
+
+/// 2xBR (xBR level 1).
+fn xbr(n: &Neighbors) -> [Color; 4] {
+    let e = n.at(0, 0);
+    // Output order of the corners (rotation 0: bottom-right, 1: bottom-left, 2: top-left, 3: top-right).
+    let mut corners = [e; 4];
+    for (r, corner) in corners.iter_mut().enumerate() {
+        let p = |dx, dy| n.rotated(r as u8, dx, dy);
+        let (c, f, g, h, i) = (p(1, -1), p(1, 0), p(-1, 1), p(0, 1), p(1, 1));
+        let (b, d, f4, h5, i4, i5) = (p(0, -1), p(-1, 0), p(2, 0), p(0, 2), p(2, 1), p(1, 2));
+        let wd1 = yuv_distance(e, c)
+            + yuv_distance(e, g)
+            + yuv_distance(i, f4)
+            + yuv_distance(i, h5)
+            + 4 * yuv_distance(h, f);
+        let wd2 = yuv_distance(h, d)
+            + yuv_distance(h, i5)
+            + yuv_distance(f, i4)
+            + yuv_distance(f, b)
+            + 4 * yuv_distance(e, i);
+        if wd1 < wd2 && e != f && e != h {
+            let px = if yuv_distance(e, f) <= yuv_distance(e, h) {
+                f
+            } else {
+                h
+            };
+            *corner = mix(&[(e, 1), (px, 1)]);
+        }
+    }
+    [corners[2], corners[3], corners[1], corners[0]]
+}
+
+fn yuv(c: Color) -> (i32, i32, i32) {
+    let (r, g, b) = (i32::from(c.r), i32::from(c.g), i32::from(c.b));
+    let y = (299 * r + 587 * g + 114 * b) / 1000;
+    let u = (-169 * r - 331 * g + 500 * b) / 1000 + 128;
+    let v = (500 * r - 419 * g - 81 * b) / 1000 + 128;
+    (y, u, v)
+}
+
+/// Returns `true` if the given colors differ beyond the hqx thresholds (Y: 48, U: 7, V: 6).
+fn yuv_differs(a: Color, b: Color) -> bool {
+    let (ay, au, av) = yuv(a);
+    let (by, bu, bv) = yuv(b);
+    (ay - by).abs() > 48 || (au - bu).abs() > 7 || (av - bv).abs() > 6 || a.a != b.a
+}
+
+fn yuv_distance(a: Color, b: Color) -> u32 {
+    let (ay, au, av) = yuv(a);
+    let (by, bu, bv) = yuv(b);
+    (48 * (ay - by).unsigned_abs() + 7 * (au - bu).unsigned_abs() + 6 * (av - bv).unsigned_abs())
+        + 48 * u32::from(a.a.abs_diff(b.a))
+}
+
+/// Weighted average of the given colors.
+fn mix(colors: &[(Color, u32)]) -> Color {
+    let total: u32 = colors.iter().map(|(_, w)| w).sum();
+    let channel = |f: fn(&Color) -> u8| {
+        (colors.iter().map(|(c, w)| u32::from(f(c)) * w).sum::<u32>() / total) as u8
+    };
+    Color::rgba(
+        channel(|c| c.r),
+        channel(|c| c.g),
+        channel(|c| c.b),
+        channel(|c| c.a),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const W: Color = Color::rgb(255, 255, 255);
+    const B: Color = Color::rgb(0, 0, 0);
+
+    fn gray(v: u8) -> Color {
+        Color::rgb(v, v, v)
+    }
+
+    fn bitmap(rows: &[&[Color]]) -> Bitmap {
+        let mut bitmap =
+            Bitmap::new(rows[0].len() as u32, rows.len() as u32, None).expect("bitmap");
+        for (y, row) in rows.iter().enumerate() {
+            for (x, color) in row.iter().enumerate() {
+                bitmap.set_pixel(x as u32, y as u32, *color);
+            }
+        }
+        bitmap
+    }
+
+    fn block(bitmap: &Bitmap, x: u32, y: u32, size: u32) -> Vec<Vec<Color>> {
+        (y..y + size)
+            .map(|y| {
+                (x..x + size)
+                    .map(|x| bitmap.get_pixel(x, y).expect("out of range"))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn checker() -> Bitmap {
+        bitmap(&[&[W, B], &[B, W]])
+    }
+
+    #[test]
+    fn scale2x_works() {
+        let output = Upscaler::Scale2x.apply(&checker()).expect("apply");
+        assert_eq!(
+            block(&output, 0, 0, 4),
+            [[W, W, B, B], [W, B, W, B], [B, W, B, W], [B, B, W, W],]
+        );
+    }
+
+    #[test]
+    fn scale3x_works() {
+        let output = Upscaler::Scale3x.apply(&checker()).expect("apply");
+        assert_eq!(
+            block(&output, 0, 0, 6),
+            [
+                [W, W, W, B, B, B],
+                [W, W, B, W, B, B],
+                [W, B, B, W, W, B],
+                [B, W, W, B, B, W],
+                [B, B, W, B, W, W],
+                [B, B, B, W, W, W],
+            ]
+        );
+    }
+
+    #[test]
+    fn hq2x_works() {
+        // Isolated pixels are blended toward their neighbors but stay dark.
+        let dot = bitmap(&[&[W, W, W], &[W, B, W], &[W, W, W]]);
+        let output = Upscaler::Hq2x.apply(&dot).expect("apply");
+        let d = gray(31);
+        assert_eq!(
+            block(&output, 1, 1, 4),
+            [[W, W, W, W], [W, d, d, W], [W, d, d, W], [W, W, W, W]]
+        );
+
+        // The corners of a diagonal line are smoothed.
+        let diagonal = bitmap(&[&[B, W, W], &[W, B, W], &[W, W, B]]);
+        let output = Upscaler::Hq2x.apply(&diagonal).expect("apply");
+        let (g, d) = (gray(127), gray(63));
+        assert_eq!(
+            block(&output, 0, 0, 6),
+            [
+                [B, B, W, W, W, W],
+                [B, g, g, W, W, W],
+                [W, g, d, g, W, W],
+                [W, W, g, d, g, W],
+                [W, W, W, g, g, B],
+                [W, W, W, W, B, B],
+            ]
+        );
+    }
+
+    #[test]
+    fn hq4x_works() {
+        // The corners of a square are rounded.
+        let square = bitmap(&[&[W, W, W, W], &[W, B, B, W], &[W, B, B, W], &[W, W, W, W]]);
+        let output = Upscaler::Hq4x.apply(&square).expect("apply");
+        let g = gray(127);
+        assert_eq!(
+            block(&output, 3, 3, 4),
+            [[W, W, W, W], [W, W, g, B], [W, g, B, B], [W, B, B, B]]
+        );
+
+        let dot = bitmap(&[&[W, W, W], &[W, B, W], &[W, W, W]]);
+        let output = Upscaler::Hq4x.apply(&dot).expect("apply");
+        let (g, d) = (gray(127), gray(63));
+        assert_eq!(
+            block(&output, 4, 4, 4),
+            [[g, d, d, g], [d, B, B, d], [d, B, B, d], [g, d, d, g]]
+        );
+    }
+
+    #[test]
+    fn xbr_works() {
+        let diagonal = bitmap(&[&[B, W, W], &[W, B, W], &[W, W, B]]);
+        let output = Upscaler::Xbr.apply(&diagonal).expect("apply");
+        let g = gray(127);
+        assert_eq!(block(&output, 2, 2, 2), [[B, g], [g, B]]);
+    }
+}